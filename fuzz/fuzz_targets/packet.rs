@@ -0,0 +1,17 @@
+//! Feeds arbitrary frames to the packet parser. Run with `cargo fuzz run packet` in this
+//! directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use radiolink::packet::{Packet, MAX_PLAINTEXT_SIZE, VERSION};
+use radiolink_sim as _;
+
+fuzz_target!(|frame: &[u8]| {
+    // Whatever parses must survive a round trip
+    if let Ok(packet) = Packet::read(frame) {
+        let mut buffer = [0; MAX_PLAINTEXT_SIZE];
+        packet.write(VERSION, &mut buffer);
+        assert!(Packet::read(&buffer) == Ok(packet));
+    }
+});
@@ -0,0 +1,194 @@
+#!/usr/bin/env python3
+"""Check that the firmware's statics and its worst-case stack fit in the RAM of the nRF51.
+
+Builds the firmware in the given profiles (default: dev and release), adds up the statics, and
+computes the deepest stack from the disassembly: the frame of each function from its prologue,
+plus the deepest function it calls. Indirect calls may go to any function whose address is
+stored somewhere, except that interrupt handlers never poll futures: only the executor does,
+in thread mode. On top of the stack of the thread comes the deepest interrupt handler, with the
+registers the CPU pushes on entry; all interrupts run at the same priority, so they don't nest.
+Cycles in the call graph are not followed.
+
+Needs llvm-objdump, on the PATH or from `rustup component add llvm-tools`.
+"""
+
+import glob
+import os
+import re
+import shutil
+import struct
+import subprocess
+import sys
+
+TARGET = 'thumbv6m-none-eabi'
+BINARY = 'radiolink'
+
+# Registers pushed when an exception is taken, plus alignment
+EXCEPTION_FRAME = 36
+
+
+def run(*args):
+    return subprocess.run(args, capture_output=True, text=True, check=True).stdout
+
+
+def objdump():
+    if shutil.which('llvm-objdump'):
+        return 'llvm-objdump'
+    sysroot = run('rustc', '--print', 'sysroot').strip()
+    found = glob.glob(os.path.join(sysroot, 'lib', 'rustlib', '*', 'bin', 'llvm-objdump'))
+    if not found:
+        sys.exit('llvm-objdump not found, install it with `rustup component add llvm-tools`')
+    return found[0]
+
+
+class Elf:
+    """The sections and function symbols of a 32-bit little-endian ELF file"""
+
+    def __init__(self, path):
+        self.path = path
+        self.data = open(path, 'rb').read()
+        shoff, = struct.unpack_from('<I', self.data, 0x20)
+        shentsize, shnum, shstrndx = struct.unpack_from('<HHH', self.data, 0x2e)
+        headers = [struct.unpack_from('<IIIIIIIIII', self.data, shoff + i * shentsize)
+                   for i in range(shnum)]
+        names = headers[shstrndx][4]
+        self.sections = {}
+        for name, kind, flags, addr, offset, size, link, _, _, entsize in headers:
+            self.sections[self.string(names + name)] = (kind, flags, addr, offset, size)
+            if kind == 2:  # SHT_SYMTAB
+                self.functions = self.read_functions(offset, size, entsize, headers[link][4])
+
+    def string(self, offset):
+        return self.data[offset:self.data.index(b'\0', offset)].decode()
+
+    def read_functions(self, offset, size, entsize, strings):
+        functions = {}
+        for i in range(offset, offset + size, entsize):
+            name, value, _, info, _, _ = struct.unpack_from('<IIIBBH', self.data, i)
+            if info & 0xf == 2:  # STT_FUNC
+                functions[value & ~1] = self.string(strings + name)
+        return functions
+
+    def words(self, section):
+        _, _, addr, offset, size = self.sections[section]
+        for i in range(0, size - 3, 4):
+            yield addr + i, struct.unpack_from('<I', self.data, offset + i)[0]
+
+    def word_at(self, addr):
+        for kind, _, start, offset, size in self.sections.values():
+            if kind == 1 and start <= addr < start + size:  # SHT_PROGBITS
+                return struct.unpack_from('<i', self.data, offset + addr - start)[0]
+        return 0
+
+
+def ram():
+    """The start and size of the RAM from the memory.x of the HAL"""
+    for path in glob.glob(f'target/{TARGET}/*/build/nrf51-hal-*/out/memory.x'):
+        match = re.search(r'RAM\s*:\s*ORIGIN\s*=\s*(\w+)\s*,\s*LENGTH\s*=\s*(\d+)K', open(path).read())
+        if match:
+            return int(match.group(1), 16), int(match.group(2)) * 1024
+    sys.exit('memory.x not found')
+
+
+def statics(elf, ram_start, ram_size):
+    total = 0
+    for name, (kind, flags, addr, _, size) in elf.sections.items():
+        if flags & 2 and ram_start <= addr < ram_start + ram_size:  # SHF_ALLOC
+            print(f'  {name:<20} {size:6} B')
+            total += size
+    return total
+
+
+def stack(elf, tool):
+    frames, calls, indirect = {}, {}, set()
+    current, literals = None, {}
+    for line in run(tool, '-d', '--no-show-raw-insn', '-j', '.text', elf.path).splitlines():
+        match = re.match(r'^([0-9a-f]+) <.*>:$', line)
+        if match:
+            addr = int(match.group(1), 16)
+            if addr in elf.functions:
+                current = addr
+                frames[current], calls[current] = 0, set()
+            continue
+        if current is None or ':' not in line:
+            continue
+        insn = line.split(':', 1)[1].strip()
+        match = re.match(r'push\s+\{([^}]*)\}', insn)
+        if match:
+            frames[current] += 4 * len(match.group(1).split(','))
+        match = re.match(r'sub\s+sp, #(\d+)', insn)
+        if match:
+            frames[current] += int(match.group(1))
+        # Large frames: ldr rN, =-size; add sp, rN
+        match = re.match(r'ldr\s+(r\d+), \[pc, #\d+\]\s+@ 0x([0-9a-f]+)', insn)
+        if match:
+            literals[match.group(1)] = elf.word_at(int(match.group(2), 16))
+        match = re.match(r'add\s+sp, (r\d+)$', insn)
+        if match and literals.get(match.group(1), 0) < 0:
+            frames[current] -= literals[match.group(1)]
+        match = re.match(r'(bl|b(?:\.\w+)?|b\w\w(?:\.\w)?)\s+0x([0-9a-f]+)', insn)
+        if match:
+            target = int(match.group(2), 16)
+            if match.group(1) == 'bl' or (target in elf.functions and target != current):
+                calls[current].add(target)
+        if re.match(r'blx\s+r\d+', insn) or re.match(r'bx\s+r\d+', insn):
+            indirect.add(current)
+
+    vectors = [word & ~1 for _, word in elf.words('.vector_table')][1:]
+    reset, handlers = vectors[0], set(vectors[1:]) & set(frames)
+    taken = set()
+    for section in ('.text', '.rodata', '.data'):
+        if section in elf.sections:
+            taken |= {word & ~1 for _, word in elf.words(section) if word & 1} & set(frames)
+    # Only the CPU calls the handlers
+    taken -= set(vectors)
+
+    # Polls of futures, and the bodies of async functions and blocks
+    futures = {function for function in taken
+               if re.search(r'Future\$GT\$4poll|\$u7b\$\$u7b\$closure', elf.functions[function])}
+    recursive = set()
+
+    def depth(function, targets, depths, path=()):
+        if function in path:
+            recursive.add(function)
+            return 0
+        if function not in depths:
+            callees = calls.get(function, set()) | (targets if function in indirect else set())
+            depths[function] = frames.get(function, 0) + max(
+                (depth(callee, targets, depths, path + (function,))
+                 for callee in callees if callee in frames), default=0)
+        return depths[function]
+
+    thread = depth(reset, taken, {})
+    depths = {}
+    interrupt = max((depth(handler, taken - futures, depths) for handler in handlers),
+                    default=0) + EXCEPTION_FRAME
+    if recursive:
+        print(f'  {len(recursive)} functions in call cycles, not followed')
+    print(f'  {"thread stack":<20} {thread:6} B')
+    print(f'  {"interrupt stack":<20} {interrupt:6} B')
+    return thread + interrupt
+
+
+def check(profile, tool):
+    args = ['cargo', 'build'] + (['--release'] if profile == 'release' else [])
+    subprocess.run(args, check=True)
+    directory = 'debug' if profile == 'dev' else profile
+    elf = Elf(f'target/{TARGET}/{directory}/{BINARY}')
+    ram_start, ram_size = ram()
+    print(f'{profile}:')
+    used = statics(elf, ram_start, ram_size) + stack(elf, tool)
+    print(f'  {used} of {ram_size} B used, {ram_size - used} B free')
+    return used <= ram_size
+
+
+def main():
+    os.chdir(os.path.join(os.path.dirname(os.path.abspath(__file__)), '..'))
+    tool = objdump()
+    profiles = sys.argv[1:] or ['dev', 'release']
+    if not all([check(profile, tool) for profile in profiles]):
+        sys.exit('The firmware does not fit in RAM')
+
+
+if __name__ == '__main__':
+    main()
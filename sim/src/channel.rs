@@ -0,0 +1,260 @@
+use radiolink::hal::DataRate;
+
+use crate::rng::Rng;
+
+/// Frames get lost more and more as the signal gets within this many dB of the sensitivity of the
+/// data rate
+const FADE_MARGIN: f64 = 6.0;
+
+/// Frames are forgotten this long after they have ended
+const FRAME_LIFETIME: u64 = 10_000;
+
+/// Behaviour of the virtual radio channel. All times are in microseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelConfig {
+    /// Probability that a receiver misses a frame entirely
+    pub loss: f64,
+    /// Probability that a frame is received with bit errors, i.e. a failing CRC
+    pub corruption: f64,
+    /// Probability that a frame is received a second time, e.g. via a reflection
+    pub duplication: f64,
+    /// Minimum and maximum propagation delay
+    pub delay: (u64, u64),
+    /// Signal strength at the receivers in dBm. Frames near or below the sensitivity of their data
+    /// rate get lost on top of `loss`.
+    pub rssi: i8,
+}
+
+impl ChannelConfig {
+    /// Every frame arrives intact and without delay
+    pub fn ideal() -> Self {
+        Self {
+            loss: 0.0,
+            corruption: 0.0,
+            duplication: 0.0,
+            delay: (0, 0),
+            rssi: -50,
+        }
+    }
+
+    /// A noisy channel that exercises all of the failure modes
+    pub fn lossy() -> Self {
+        Self {
+            loss: 0.1,
+            corruption: 0.05,
+            duplication: 0.02,
+            delay: (0, 50),
+            rssi: -70,
+        }
+    }
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self::ideal()
+    }
+}
+
+/// Counters for what happened on the channel
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelStats {
+    pub transmitted: usize,
+    /// Bytes in all transmitted frames, counting the length byte
+    pub transmitted_bytes: usize,
+    pub lost: usize,
+    pub corrupted: usize,
+    pub duplicated: usize,
+    pub collided: usize,
+}
+
+/// A frame put on air, as an eavesdropper records it
+#[derive(Clone)]
+pub struct Recording {
+    /// Index of the node that sent it
+    pub from: usize,
+    pub address: u32,
+    /// The frame, starting with the length byte
+    pub bytes: Vec<u8>,
+}
+
+/// A frame as seen by one receiver
+pub struct Arrival {
+    /// Unique for each arrival
+    pub key: u64,
+    /// Same for all arrivals of one transmission
+    pub id: u64,
+    pub to: usize,
+    /// Base address the frame was sent with. Receivers only hear their own address.
+    pub address: u32,
+    /// Receivers only hear frames sent at the data rate they are set to
+    pub rate: DataRate,
+    pub start: u64,
+    pub end: u64,
+    pub bytes: Vec<u8>,
+    pub corrupted: bool,
+    pub rssi: i8,
+}
+
+/// Time from the start of a frame until the ADDRESS event: 1 byte preamble + 4 byte base address +
+/// 1 byte prefix
+pub fn address_time(rate: DataRate) -> u64 {
+    6 * byte_time(rate)
+}
+
+/// Airtime of a frame with the given number of bytes after the address (length field and
+/// payload), including the preamble, address and 16-bit CRC
+pub fn airtime(len: usize, rate: DataRate) -> u64 {
+    address_time(rate) + (len as u64 + 2) * byte_time(rate)
+}
+
+/// Time to send a byte, in microseconds
+fn byte_time(rate: DataRate) -> u64 {
+    match rate {
+        DataRate::Kbit250 => 32,
+        DataRate::Mbit1 => 8,
+        DataRate::Mbit2 => 4,
+    }
+}
+
+/// Frames on air between the simulated radios
+pub struct Channel {
+    config: ChannelConfig,
+    nodes: usize,
+    arrivals: Vec<Arrival>,
+    next_id: u64,
+    next_key: u64,
+    pub stats: ChannelStats,
+    /// Frames put on air while recording
+    pub recordings: Option<Vec<Recording>>,
+}
+
+impl Channel {
+    pub fn new(config: ChannelConfig, nodes: usize) -> Self {
+        Self {
+            config,
+            nodes,
+            arrivals: Vec::new(),
+            next_id: 0,
+            next_key: 0,
+            stats: ChannelStats::default(),
+            recordings: None,
+        }
+    }
+
+    /// Change the behaviour, e.g. to move the nodes apart. Affects frames sent from now on.
+    pub fn configure(&mut self, config: ChannelConfig) {
+        self.config = config;
+    }
+
+    /// Put a frame on air, starting with the length byte. Returns the id of the transmission,
+    /// which is used to refer to the arrivals at all receivers.
+    pub fn transmit(
+        &mut self,
+        from: usize,
+        address: u32,
+        rate: DataRate,
+        start: u64,
+        bytes: &[u8],
+        rng: &mut Rng,
+    ) -> u64 {
+        let end = start + airtime(bytes.len(), rate);
+        self.arrivals.retain(|a| a.end + FRAME_LIFETIME > start);
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stats.transmitted += 1;
+        self.stats.transmitted_bytes += bytes.len();
+        if let Some(recordings) = &mut self.recordings {
+            recordings.push(Recording {
+                from,
+                address,
+                bytes: bytes.to_vec(),
+            });
+        }
+        let margin = self.config.rssi as f64 - rate.sensitivity() as f64;
+        let fade = (1.0 - margin / FADE_MARGIN).clamp(0.0, 1.0);
+
+        for to in (0..self.nodes).filter(|&to| to != from) {
+            let copies = if rng.chance(self.config.duplication) {
+                self.stats.duplicated += 1;
+                2
+            } else {
+                1
+            };
+            let mut offset = rng.range(self.config.delay.0, self.config.delay.1);
+            for _ in 0..copies {
+                if rng.chance(self.config.loss) || (fade > 0.0 && rng.chance(fade)) {
+                    self.stats.lost += 1;
+                } else {
+                    let corrupted = rng.chance(self.config.corruption);
+                    if corrupted {
+                        self.stats.corrupted += 1;
+                    }
+                    self.arrivals.push(Arrival {
+                        key: self.next_key,
+                        id,
+                        to,
+                        address,
+                        rate,
+                        start: start + offset,
+                        end: end + offset,
+                        bytes: bytes.to_vec(),
+                        corrupted,
+                        rssi: self.config.rssi,
+                    });
+                    self.next_key += 1;
+                }
+                // A duplicate arrives after the original
+                offset += end - start + rng.range(10, 200);
+            }
+        }
+        id
+    }
+
+    /// The transmitter was disabled in the middle of a frame
+    pub fn abort(&mut self, id: u64, now: u64) {
+        for arrival in self.arrivals.iter_mut().filter(|a| a.id == id) {
+            if arrival.end > now {
+                arrival.corrupted = true;
+            }
+        }
+    }
+
+    /// Find the first frame with the given address and data rate that a receiver that has been
+    /// listening since `since` has heard by `now`. A frame whose preamble started before `since`
+    /// can't be received.
+    pub fn find(
+        &self,
+        to: usize,
+        address: u32,
+        rate: DataRate,
+        since: u64,
+        now: u64,
+    ) -> Option<&Arrival> {
+        self.arrivals
+            .iter()
+            .filter(|a| {
+                a.to == to
+                    && a.address == address
+                    && a.rate == rate
+                    && a.start >= since
+                    && a.start + address_time(rate) <= now
+            })
+            .min_by_key(|a| a.start)
+    }
+
+    /// Remove a received frame from the channel. Returns the frame and whether it overlapped with
+    /// another frame at the receiver.
+    pub fn take(&mut self, key: u64) -> (Arrival, bool) {
+        let index = self.arrivals.iter().position(|a| a.key == key).unwrap();
+        let arrival = self.arrivals.remove(index);
+        let collided = self
+            .arrivals
+            .iter()
+            .any(|a| a.to == arrival.to && a.start < arrival.end && arrival.start < a.end);
+        if collided {
+            self.stats.collided += 1;
+        }
+        (arrival, collided)
+    }
+}
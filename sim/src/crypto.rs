@@ -0,0 +1,36 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use radiolink::hal::{AesHal, RngHal};
+
+use crate::World;
+
+/// The ECB peripheral, implemented in software
+pub struct SimAes;
+
+impl AesHal for SimAes {
+    fn encrypt(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        Aes128::new(key.into()).encrypt_block(block.into());
+    }
+}
+
+/// The RNG peripheral, drawing from the seeded simulation RNG
+pub struct SimRng {
+    world: Rc<RefCell<World>>,
+}
+
+impl SimRng {
+    pub fn new(world: Rc<RefCell<World>>) -> Self {
+        Self { world }
+    }
+}
+
+impl RngHal for SimRng {
+    fn init(&mut self) {}
+
+    fn random(&mut self) -> u32 {
+        self.world.borrow_mut().rng.next_u64() as u32
+    }
+}
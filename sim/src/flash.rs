@@ -0,0 +1,43 @@
+use radiolink::hal::FlashHal;
+
+const PAGE_SIZE: usize = 1024;
+
+/// A page of nRF51 flash
+pub struct SimFlash {
+    page: Vec<u8>,
+}
+
+impl SimFlash {
+    pub fn new() -> Self {
+        Self {
+            page: vec![0xff; PAGE_SIZE],
+        }
+    }
+}
+
+impl Default for SimFlash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlashHal for SimFlash {
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        data.copy_from_slice(&self.page[offset..offset + data.len()]);
+    }
+
+    fn erase(&mut self) {
+        self.page.fill(0xff);
+    }
+
+    fn write_word(&mut self, offset: usize, word: u32) {
+        assert!(offset.is_multiple_of(4));
+        // Programming can only clear bits
+        for (byte, new) in self.page[offset..offset + 4]
+            .iter_mut()
+            .zip(word.to_le_bytes())
+        {
+            *byte &= new;
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Host-side simulator for the radio link.
+//!
+//! Runs the link logic of two nodes against simulated peripherals: a virtual radio channel that
+//! can lose, corrupt, delay and duplicate frames, serial ports with a host at the other end, and
+//! the RTC. Everything is driven by a virtual clock and a seeded RNG, so runs are reproducible.
+
+use std::cell::{RefCell, RefMut};
+use std::rc::Rc;
+
+use radiolink::hal::{DataRate, RadioSettings, UartSettings, AUTOBAUD};
+use radiolink::node::{Config, Node, Stats};
+use radiolink::radio::LinkState;
+use radiolink::storage::{Settings, Storage};
+
+use crate::channel::Channel;
+pub use crate::channel::{ChannelConfig, ChannelStats, Recording};
+pub use crate::crypto::SimAes;
+use crate::crypto::SimRng;
+pub use crate::flash::SimFlash;
+use crate::radio::{RadioCore, SimRadio};
+use crate::rng::Rng;
+use crate::rtc::{RtcCore, SimRtc};
+pub use crate::serial::SerialPort;
+use crate::serial::SimUart;
+
+mod channel;
+mod crypto;
+mod flash;
+mod radio;
+pub mod rng;
+mod rtc;
+mod serial;
+
+/// Virtual time advanced per pass of the executor, in microseconds
+const STEP_TIME: u64 = 5;
+
+/// Nodes boot at a random time up to this many microseconds after the simulation starts
+const MAX_BOOT_TIME: u64 = 20_000;
+
+/// Frames injected with [`Simulator::inject`] come from this node index, so all nodes hear them
+const INJECTOR: usize = usize::MAX;
+
+/// State shared by all simulated peripherals
+pub struct World {
+    /// Current time in microseconds
+    now: u64,
+    channel: Channel,
+    rng: Rng,
+}
+
+type LinkNode = Node<SimRadio, SimUart, SimRtc, SimAes, SimRng>;
+
+struct SimNode {
+    node: LinkNode,
+    config: Config,
+    serial: Rc<RefCell<SerialPort>>,
+    /// The radio peripheral, whose events wake up the radio task
+    radio: Rc<RefCell<RadioCore>>,
+    /// Flash where the settings are stored, like the firmware does
+    storage: Storage<SimFlash>,
+    /// The nodes boot at different times, so that their clocks aren't in sync
+    boot_at: u64,
+    booted: bool,
+    /// The tasks don't run until then
+    busy_until: u64,
+    /// The RTC, whose event wakes up the timer task
+    rtc: Rc<RefCell<RtcCore>>,
+    /// Times the timer task has run
+    timer_wakeups: u64,
+    /// UART interrupts when the serial task last ran
+    uart_interrupts: u64,
+    /// The serial and radio tasks run when woken up by another task, or by their own interrupts,
+    /// like on the executor of the firmware
+    serial_woken: bool,
+    radio_woken: bool,
+    /// Time spent sleeping with no task to run, in microseconds
+    slept: u64,
+}
+
+impl SimNode {
+    /// Create the node and its peripherals. The RTC counts from `boot_at`.
+    fn build(
+        index: usize,
+        config: Config,
+        world: &Rc<RefCell<World>>,
+        serial: &Rc<RefCell<SerialPort>>,
+        boot_at: u64,
+    ) -> (LinkNode, Rc<RefCell<RadioCore>>, Rc<RefCell<RtcCore>>) {
+        let radio = SimRadio::new(index, world.clone());
+        let radio_core = radio.core();
+        let rtc = SimRtc::new(world.clone(), boot_at);
+        let rtc_core = rtc.core();
+        let node = Node::new(
+            radio,
+            SimUart::new(serial.clone(), world.clone()),
+            rtc,
+            SimAes,
+            SimRng::new(world.clone()),
+            config,
+        );
+        (node, radio_core, rtc_core)
+    }
+
+    /// Run the tasks that have been woken up, in the order of the firmware. Returns false if
+    /// none did, i.e. the CPU slept.
+    fn run_tasks(&mut self, now: u64) -> bool {
+        let timer = self.rtc.borrow_mut().event_pending();
+        if timer {
+            self.timer_wakeups += 1;
+            self.node.tick_clock();
+            self.serial_woken = true;
+            self.radio_woken = true;
+        }
+
+        let serial = {
+            let serial = self.serial.borrow();
+            self.serial_woken
+                || serial.interrupts() != self.uart_interrupts
+                || serial.edge_pending(now)
+        };
+        if serial {
+            self.uart_interrupts = self.serial.borrow().interrupts();
+            self.node.tick_serial();
+            self.save_settings();
+            self.serial_woken = !self.node.serial_is_idle();
+            self.radio_woken = true;
+        }
+
+        let radio = self.radio_woken || self.radio.borrow_mut().event_pending();
+        if radio {
+            self.node.tick_radio();
+            self.save_settings();
+            if !self.node.serial_is_idle() {
+                self.serial_woken = true;
+            }
+            self.radio_woken = !self.node.radio_is_idle();
+        }
+        timer || serial || radio
+    }
+
+    fn save_settings(&mut self) {
+        if let Some(settings) = self.node.take_settings() {
+            self.storage.save(&settings);
+        }
+    }
+
+    /// Start the tasks after booting, all woken up
+    fn start(&mut self) {
+        self.node.init();
+        self.booted = true;
+        self.uart_interrupts = self.serial.borrow().interrupts();
+        self.serial_woken = true;
+        self.radio_woken = true;
+    }
+}
+
+pub struct Simulator {
+    world: Rc<RefCell<World>>,
+    nodes: Vec<SimNode>,
+}
+
+impl Simulator {
+    /// Two nodes linked over a channel with the given behaviour. Returns after both nodes have
+    /// booted.
+    pub fn new(seed: u64, channel: ChannelConfig, config: Config) -> Self {
+        Self::with_configs(seed, channel, &[config, config])
+    }
+
+    /// Any number of nodes on the same channel, each with its own config
+    pub fn with_configs(seed: u64, channel: ChannelConfig, configs: &[Config]) -> Self {
+        let world = Rc::new(RefCell::new(World {
+            now: 0,
+            channel: Channel::new(channel, configs.len()),
+            rng: Rng::new(seed),
+        }));
+        let nodes = configs
+            .iter()
+            .copied()
+            .enumerate()
+            .map(|(index, config)| {
+                // A host talking to a node that detects the baud rate starts at the default one
+                let baud_rate = match config.settings.uart.baud_rate {
+                    AUTOBAUD => UartSettings::default().baud_rate,
+                    baud_rate => baud_rate,
+                };
+                let serial = Rc::new(RefCell::new(SerialPort::new(
+                    baud_rate as u64,
+                    config.settings.uart.parity,
+                    config.flow_control,
+                )));
+                let boot_at = world.borrow_mut().rng.range(0, MAX_BOOT_TIME);
+                let (node, radio_core, rtc_core) =
+                    SimNode::build(index, config, &world, &serial, boot_at);
+                SimNode {
+                    node,
+                    config,
+                    serial,
+                    radio: radio_core,
+                    rtc: rtc_core,
+                    storage: Storage::new(SimFlash::new()),
+                    boot_at,
+                    booted: false,
+                    busy_until: 0,
+                    timer_wakeups: 0,
+                    uart_interrupts: 0,
+                    serial_woken: false,
+                    radio_woken: false,
+                    slept: 0,
+                }
+            })
+            .collect();
+        let mut sim = Self { world, nodes };
+        sim.run_for(MAX_BOOT_TIME + STEP_TIME);
+        sim
+    }
+
+    /// Current time in microseconds
+    pub fn now(&self) -> u64 {
+        self.world.borrow().now
+    }
+
+    /// The serial port of the host connected to a node
+    pub fn serial(&self, node: usize) -> RefMut<'_, SerialPort> {
+        self.nodes[node].serial.borrow_mut()
+    }
+
+    /// Start pairing on a node, like holding button A during reset, where all tasks start woken up
+    pub fn pair(&mut self, node: usize) {
+        self.nodes[node].node.pair();
+        self.nodes[node].radio_woken = true;
+    }
+
+    /// The settings a node has stored in flash
+    pub fn stored_settings(&self, node: usize) -> Option<Settings> {
+        self.nodes[node].storage.load()
+    }
+
+    /// The link id a node has stored in flash
+    pub fn stored_link_id(&self, node: usize) -> Option<u32> {
+        self.stored_settings(node)
+            .and_then(|settings| settings.link_id)
+    }
+
+    /// Reset a node, like pressing the reset button. It boots again right away with the settings
+    /// it has stored in flash, if any. The radio on time starts over.
+    pub fn reboot(&mut self, index: usize) {
+        let now = self.now();
+        let node = &mut self.nodes[index];
+        let config = Config {
+            settings: node.storage.load().unwrap_or(node.config.settings),
+            ..node.config
+        };
+        (node.node, node.radio, node.rtc) =
+            SimNode::build(index, config, &self.world, &node.serial, now);
+        node.boot_at = now;
+        node.booted = false;
+        node.busy_until = 0;
+    }
+
+    /// Keep the tasks of a node from running for the given number of microseconds, like a slow
+    /// radio operation would. Interrupts keep running.
+    pub fn stall(&mut self, node: usize, duration: u64) {
+        self.nodes[node].busy_until = self.now() + duration;
+    }
+
+    /// Time a node has spent sleeping with no task to run, in microseconds
+    pub fn sleep_time(&self, node: usize) -> u64 {
+        self.nodes[node].slept
+    }
+
+    /// Times the RTC has woken up the timer task of a node
+    pub fn timer_wakeups(&self, node: usize) -> u64 {
+        self.nodes[node].timer_wakeups
+    }
+
+    /// Time the radio of a node has been on, in microseconds
+    pub fn radio_on_time(&self, node: usize) -> u64 {
+        self.nodes[node].radio.borrow_mut().on_time()
+    }
+
+    /// Time the crystal oscillator of a node has been running, in microseconds
+    pub fn clock_on_time(&self, node: usize) -> u64 {
+        self.nodes[node].radio.borrow().clock_on_time()
+    }
+
+    /// State of the radio link of a node
+    pub fn link_state(&self, node: usize) -> LinkState {
+        self.nodes[node].node.link_state()
+    }
+
+    /// Counters of a node
+    pub fn stats(&self, node: usize) -> Stats {
+        self.nodes[node].node.stats()
+    }
+
+    /// The data rate the radio of a node is set to
+    pub fn data_rate(&self, node: usize) -> DataRate {
+        self.nodes[node].radio.borrow().data_rate()
+    }
+
+    pub fn channel_stats(&self) -> ChannelStats {
+        self.world.borrow().channel.stats
+    }
+
+    /// Change the behaviour of the channel, e.g. to move the nodes apart
+    pub fn set_channel(&mut self, config: ChannelConfig) {
+        self.world.borrow_mut().channel.configure(config);
+    }
+
+    /// Record the frames the nodes put on air from now on, like an eavesdropper
+    pub fn start_recording(&mut self) {
+        self.world.borrow_mut().channel.recordings = Some(Vec::new());
+    }
+
+    /// Stop recording, and return the frames recorded since [`Simulator::start_recording`]
+    pub fn stop_recording(&mut self) -> Vec<Recording> {
+        self.world
+            .borrow_mut()
+            .channel
+            .recordings
+            .take()
+            .unwrap_or_default()
+    }
+
+    /// Put a frame on air from a third party, e.g. an attacker, at the default data rate. The
+    /// frame starts with the length byte, like the radio packet buffer.
+    pub fn inject(&mut self, address: u32, frame: &[u8]) {
+        let mut world = self.world.borrow_mut();
+        let now = world.now;
+        let rate = RadioSettings::default().data_rate;
+        let World { channel, rng, .. } = &mut *world;
+        channel.transmit(INJECTOR, address, rate, now, frame, rng);
+    }
+
+    /// Run the woken tasks of each node once
+    pub fn step(&mut self) {
+        let now = {
+            let mut world = self.world.borrow_mut();
+            world.now += STEP_TIME;
+            world.now
+        };
+        for node in &mut self.nodes {
+            node.serial.borrow_mut().update(now);
+            if node.booted {
+                if now < node.busy_until {
+                    continue;
+                }
+                if !node.run_tasks(now) {
+                    node.slept += STEP_TIME;
+                }
+            } else if now >= node.boot_at {
+                node.start();
+            }
+        }
+    }
+
+    /// Run for the given number of microseconds
+    pub fn run_for(&mut self, duration: u64) {
+        let end = self.now() + duration;
+        while self.now() < end {
+            self.step();
+        }
+    }
+
+    /// Run until `done` returns true or `timeout` microseconds have passed. Returns whether `done`
+    /// returned true.
+    pub fn run_until(&mut self, timeout: u64, mut done: impl FnMut(&mut Self) -> bool) -> bool {
+        let end = self.now() + timeout;
+        while self.now() < end {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        false
+    }
+}
+
+/// The link logic logs with defmt, which needs a global logger. Logs are discarded.
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
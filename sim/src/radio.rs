@@ -0,0 +1,310 @@
+use std::cell::{RefCell, RefMut};
+use std::rc::Rc;
+
+use radiolink::hal::MAX_PACKET_SIZE;
+use radiolink::hal::{DataRate, RadioHal, RadioSettings};
+
+use crate::channel::airtime;
+use crate::World;
+
+/// TXEN/RXEN to READY
+const RAMP_UP_TIME: u64 = 140;
+
+/// DISABLE to DISABLED
+const DISABLE_TIME: u64 = 4;
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Disabled,
+    RampUp { tx: bool, ready_at: u64 },
+    Listening { since: u64 },
+    Receiving { key: u64, end: u64 },
+    RxIdle,
+    Transmitting { id: u64, end: u64 },
+    TxIdle,
+    Disabling { done_at: u64 },
+}
+
+/// The radio peripheral itself, shared with the simulator so that it can see the events that wake
+/// up a sleeping node
+pub(crate) struct RadioCore {
+    node: usize,
+    world: Rc<RefCell<World>>,
+    /// The packet buffer as it was when transmitting was enabled
+    tx_packet: [u8; MAX_PACKET_SIZE],
+    /// A received frame not yet seen by the node
+    rx_packet: Option<Vec<u8>>,
+    address: u32,
+    rate: DataRate,
+    state: State,
+    address_event: bool,
+    end: bool,
+    disabled: bool,
+    crc_ok: bool,
+    /// Of the last received frame
+    rssi: i8,
+    /// When the radio was last turned on, if it is on
+    on_since: Option<u64>,
+    /// Time the radio was on before `on_since`, which is what draws the current
+    on_time: u64,
+    /// When the crystal oscillator was last started, if it is running
+    clock_since: Option<u64>,
+    /// Time the crystal oscillator was running before `clock_since`
+    clock_time: u64,
+}
+
+impl RadioCore {
+    /// Bring the state up to date with the current time
+    fn update(&mut self) {
+        let mut world = self.world.borrow_mut();
+        let now = world.now;
+        loop {
+            self.state = match self.state {
+                State::RampUp { tx: true, ready_at } if now >= ready_at => {
+                    let len = (self.tx_packet[0] as usize).min(MAX_PACKET_SIZE - 1) + 1;
+                    let end = ready_at + airtime(len, self.rate);
+                    let World { channel, rng, .. } = &mut *world;
+                    let id = channel.transmit(
+                        self.node,
+                        self.address,
+                        self.rate,
+                        ready_at,
+                        &self.tx_packet[..len],
+                        rng,
+                    );
+                    State::Transmitting { id, end }
+                }
+                State::RampUp {
+                    tx: false,
+                    ready_at,
+                } if now >= ready_at => State::Listening { since: ready_at },
+                State::Listening { since } => {
+                    match world
+                        .channel
+                        .find(self.node, self.address, self.rate, since, now)
+                    {
+                        Some(arrival) => {
+                            self.address_event = true;
+                            self.rssi = arrival.rssi;
+                            State::Receiving {
+                                key: arrival.key,
+                                end: arrival.end,
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                State::Receiving { key, end } if now >= end => {
+                    let (arrival, collided) = world.channel.take(key);
+                    let len = arrival.bytes.len().min(MAX_PACKET_SIZE);
+                    let mut packet = arrival.bytes[..len].to_vec();
+                    self.crc_ok = !arrival.corrupted && !collided && len == arrival.bytes.len();
+                    if !self.crc_ok {
+                        let bit = world.rng.range(0, len as u64 * 8 - 1);
+                        packet[bit as usize / 8] ^= 1 << (bit % 8);
+                    }
+                    self.rx_packet = Some(packet);
+                    self.end = true;
+                    State::RxIdle
+                }
+                State::Transmitting { end, .. } if now >= end => {
+                    self.address_event = true;
+                    self.end = true;
+                    State::TxIdle
+                }
+                State::Disabling { done_at } if now >= done_at => {
+                    self.disabled = true;
+                    if let Some(since) = self.on_since.take() {
+                        self.on_time += done_at - since;
+                    }
+                    State::Disabled
+                }
+                _ => break,
+            };
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.world.borrow().now
+    }
+
+    /// Time the radio has been on, in microseconds
+    pub(crate) fn on_time(&mut self) -> u64 {
+        self.update();
+        let now = self.now();
+        self.on_time + self.on_since.map_or(0, |since| now - since)
+    }
+
+    /// Time the crystal oscillator has been running, in microseconds
+    pub(crate) fn clock_on_time(&self) -> u64 {
+        let now = self.now();
+        self.clock_time + self.clock_since.map_or(0, |since| now - since)
+    }
+
+    /// The data rate the radio is set to
+    pub(crate) fn data_rate(&self) -> DataRate {
+        self.rate
+    }
+
+    /// Is an event set, which raises the interrupt that wakes up the node?
+    pub(crate) fn event_pending(&mut self) -> bool {
+        self.update();
+        self.address_event || self.end || self.disabled
+    }
+}
+
+/// nRF51 radio with the READY -> START shortcut enabled
+pub struct SimRadio {
+    core: Rc<RefCell<RadioCore>>,
+    packet: [u8; MAX_PACKET_SIZE],
+}
+
+impl SimRadio {
+    pub fn new(node: usize, world: Rc<RefCell<World>>) -> Self {
+        let core = RadioCore {
+            node,
+            world,
+            tx_packet: [0; MAX_PACKET_SIZE],
+            rx_packet: None,
+            address: 0,
+            rate: RadioSettings::default().data_rate,
+            state: State::Disabled,
+            address_event: false,
+            end: false,
+            disabled: false,
+            crc_ok: false,
+            rssi: 0,
+            on_since: None,
+            on_time: 0,
+            clock_since: None,
+            clock_time: 0,
+        };
+        Self {
+            core: Rc::new(RefCell::new(core)),
+            packet: [0; MAX_PACKET_SIZE],
+        }
+    }
+
+    pub(crate) fn core(&self) -> Rc<RefCell<RadioCore>> {
+        self.core.clone()
+    }
+
+    fn update(&self) -> RefMut<'_, RadioCore> {
+        let mut core = self.core.borrow_mut();
+        core.update();
+        core
+    }
+}
+
+impl RadioHal for SimRadio {
+    fn init(&mut self) {
+        self.start_clock();
+    }
+
+    // Channel and power aren't modelled: all nodes at the same data rate hear each other
+    fn configure(&mut self, settings: &RadioSettings) {
+        self.core.borrow_mut().rate = settings.data_rate;
+    }
+
+    fn packet(&mut self) -> &mut [u8] {
+        &mut self.packet
+    }
+
+    fn set_address(&mut self, base: u32) {
+        self.core.borrow_mut().address = base;
+    }
+
+    fn rx_enable(&mut self) {
+        let mut core = self.update();
+        assert!(
+            core.clock_since.is_some(),
+            "radio enabled without its clock"
+        );
+        if let State::Disabled = core.state {
+            core.on_since = Some(core.now());
+            core.state = State::RampUp {
+                tx: false,
+                ready_at: core.now() + RAMP_UP_TIME,
+            };
+        }
+    }
+
+    fn tx_enable(&mut self) {
+        let mut core = self.update();
+        assert!(
+            core.clock_since.is_some(),
+            "radio enabled without its clock"
+        );
+        if let State::Disabled = core.state {
+            core.on_since = Some(core.now());
+            core.tx_packet = self.packet;
+            core.state = State::RampUp {
+                tx: true,
+                ready_at: core.now() + RAMP_UP_TIME,
+            };
+        }
+    }
+
+    fn start(&mut self) {
+        let mut core = self.update();
+        if let State::RxIdle = core.state {
+            core.state = State::Listening { since: core.now() };
+        }
+    }
+
+    fn disable(&mut self) {
+        let mut core = self.update();
+        let now = core.now();
+        if let State::Transmitting { id, .. } = core.state {
+            core.world.borrow_mut().channel.abort(id, now);
+        }
+        core.state = State::Disabling {
+            done_at: now + DISABLE_TIME,
+        };
+    }
+
+    fn address_event(&mut self) -> bool {
+        core::mem::take(&mut self.update().address_event)
+    }
+
+    fn end_event(&mut self) -> bool {
+        let mut core = self.core.borrow_mut();
+        core.update();
+        // The received frame has been in the packet buffer since END
+        if let Some(packet) = core.rx_packet.take() {
+            self.packet[..packet.len()].copy_from_slice(&packet);
+        }
+        core::mem::take(&mut core.end)
+    }
+
+    fn disabled_event(&mut self) -> bool {
+        core::mem::take(&mut self.update().disabled)
+    }
+
+    fn stop_clock(&mut self) {
+        let mut core = self.update();
+        assert!(
+            matches!(core.state, State::Disabled),
+            "clock stopped while the radio is on"
+        );
+        let now = core.now();
+        if let Some(since) = core.clock_since.take() {
+            core.clock_time += now - since;
+        }
+    }
+
+    fn start_clock(&mut self) {
+        let mut core = self.core.borrow_mut();
+        if core.clock_since.is_none() {
+            core.clock_since = Some(core.now());
+        }
+    }
+
+    fn crc_ok(&self) -> bool {
+        self.core.borrow().crc_ok
+    }
+
+    fn rssi(&self) -> i8 {
+        self.core.borrow().rssi
+    }
+}
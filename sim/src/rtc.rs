@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use radiolink::hal::RtcHal;
+
+use crate::World;
+
+/// The counter starts this many ticks before it wraps around, about 3 seconds, so that tests
+/// running longer cover the wraparound like a node that has been up for 4.6 hours
+const TICKS_BEFORE_WRAP: u64 = 3000;
+
+const COUNTER_MASK: u32 = 0xff_ffff;
+
+/// The 24-bit counter of an RTC started at `boot_at`
+fn counter(now: u64, boot_at: u64) -> u32 {
+    let ticks = (now - boot_at) * 32768 / 1_000_000 / 33;
+    ((ticks + (1 << 24) - TICKS_BEFORE_WRAP) as u32) & COUNTER_MASK
+}
+
+/// The RTC peripheral itself, shared with the simulator so that it can see the event that wakes
+/// up the timer task
+pub(crate) struct RtcCore {
+    world: Rc<RefCell<World>>,
+    /// The counter starts at boot
+    boot_at: u64,
+    /// Compare value, or `None` for the TICK event
+    compare: Option<u32>,
+    /// Counter value at the last update
+    counter: u32,
+    event: bool,
+}
+
+impl RtcCore {
+    fn counter(&self) -> u32 {
+        counter(self.world.borrow().now, self.boot_at)
+    }
+
+    /// Set the event if the counter has ticked, or gone past the compare value, since the last
+    /// update
+    fn update(&mut self) {
+        let counter = self.counter();
+        let passed = counter.wrapping_sub(self.counter) & COUNTER_MASK;
+        self.event |= match self.compare {
+            None => passed > 0,
+            Some(compare) => {
+                let ahead = compare.wrapping_sub(self.counter) & COUNTER_MASK;
+                ahead > 0 && ahead <= passed
+            }
+        };
+        self.counter = counter;
+    }
+
+    /// Is the event set, which raises the interrupt that wakes up the timer task?
+    pub(crate) fn event_pending(&mut self) -> bool {
+        self.update();
+        self.event
+    }
+}
+
+/// RTC0 running from the 32.768 kHz clock with prescaler 32, like the firmware configures it
+pub struct SimRtc {
+    core: Rc<RefCell<RtcCore>>,
+}
+
+impl SimRtc {
+    pub fn new(world: Rc<RefCell<World>>, boot_at: u64) -> Self {
+        let core = RtcCore {
+            world,
+            boot_at,
+            compare: None,
+            counter: 0,
+            event: false,
+        };
+        Self {
+            core: Rc::new(RefCell::new(core)),
+        }
+    }
+
+    pub(crate) fn core(&self) -> Rc<RefCell<RtcCore>> {
+        self.core.clone()
+    }
+}
+
+impl RtcHal for SimRtc {
+    fn init(&mut self) {
+        let mut core = self.core.borrow_mut();
+        core.counter = core.counter();
+        core.event = false;
+    }
+
+    fn wake_event(&mut self) -> bool {
+        let mut core = self.core.borrow_mut();
+        core.update();
+        core::mem::take(&mut core.event)
+    }
+
+    fn wake_at(&mut self, counter: Option<u32>) {
+        let mut core = self.core.borrow_mut();
+        core.update();
+        if let Some(counter) = counter {
+            let ahead = counter.wrapping_sub(core.counter) & COUNTER_MASK;
+            assert!(
+                ahead >= 2,
+                "compare value {counter} too close to the counter"
+            );
+        }
+        core.compare = counter;
+    }
+
+    fn counter(&self) -> u32 {
+        self.core.borrow().counter()
+    }
+}
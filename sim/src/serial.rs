@@ -0,0 +1,414 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use radiolink::hal::{Parity, Received, UartError, UartHal, UartSettings, EDGE_CLOCK};
+use radiolink::uart::FlowControl;
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+/// Size of the nRF51 UART receive FIFO
+const RX_FIFO_SIZE: usize = 6;
+
+/// Bytes the UART interrupt of the firmware moves out of the FIFO into its ring, whether the main
+/// loop is busy or not. The interrupt stops when the ring is full, leaving bytes in the FIFO.
+const RX_RING_SIZE: usize = 255;
+
+/// Length of a break sent by the host, in bytes
+const BREAK_LENGTH: u64 = 2;
+
+fn byte_time(baud_rate: u64, parity: Parity) -> u64 {
+    // Start bit, 8 data bits, parity bit, stop bit
+    let bits = match parity {
+        Parity::None => 10,
+        Parity::Even => 11,
+    };
+    bits * 1_000_000 / baud_rate
+}
+
+/// The even parity bit of a byte, which makes the number of ones even
+fn parity_bit(byte: u8) -> bool {
+    byte.count_ones() % 2 == 1
+}
+
+/// What the host sends on the line
+#[derive(Clone, Copy)]
+enum Symbol {
+    Byte(u8),
+    Break,
+}
+
+/// What the receiver makes of a byte sent at another baud rate. Any wrong byte will do.
+fn garble(byte: u8) -> u8 {
+    !byte.rotate_left(3)
+}
+
+/// The serial line between a host and a node, and the host at the other end of it. All times are
+/// in microseconds.
+pub struct SerialPort {
+    /// Baud rate of the host
+    baud_rate: u64,
+    /// Baud rate of the node, or `None` while it only captures edges
+    node_baud_rate: Option<u64>,
+    /// Parity of the host
+    parity: Parity,
+    /// Parity of the node
+    node_parity: Parity,
+    /// Edges on the RX line of the node, in ticks of [`EDGE_CLOCK`], while capturing
+    edges: Option<VecDeque<u64>>,
+
+    /// The host uses the same flow control as the node
+    flow_control: FlowControl,
+    /// A misbehaving host keeps sending regardless of flow control
+    ignore_flow_control: bool,
+    /// Paused by XOFF from the node
+    host_paused: bool,
+    /// RTS from the node
+    rts: bool,
+    /// CTS to the node
+    cts: bool,
+    /// DCD from the node
+    dcd: bool,
+
+    /// Written by the host but not yet on the line
+    host_tx: VecDeque<Symbol>,
+    /// When the byte currently on the line reaches the node, and the baud rate of the node when
+    /// it started
+    rx_at: Option<(u64, Option<u64>)>,
+    /// The receive FIFO and the ring behind it
+    rx_fifo: VecDeque<Received>,
+    /// Bytes lost because the node didn't read the receive FIFO in time
+    pub overruns: usize,
+    /// An overrun not yet reported to the node
+    overrun_pending: bool,
+    /// Number of UART interrupts for received and sent bytes
+    interrupts: u64,
+
+    /// The byte currently being sent by the node, and when it reaches the host
+    tx: Option<(u8, u64)>,
+    tx_ready: bool,
+    /// When the node started sending a break
+    break_since: Option<u64>,
+    host_rx: Vec<u8>,
+    /// Number of bytes the host has received
+    host_received: usize,
+    /// Breaks the host has received, as the number of bytes received before each
+    host_breaks: Vec<usize>,
+}
+
+impl SerialPort {
+    pub fn new(baud_rate: u64, parity: Parity, flow_control: FlowControl) -> Self {
+        Self {
+            baud_rate,
+            node_baud_rate: Some(baud_rate),
+            parity,
+            node_parity: parity,
+            edges: None,
+            flow_control,
+            ignore_flow_control: false,
+            host_paused: false,
+            rts: false,
+            cts: true,
+            dcd: false,
+            host_tx: VecDeque::new(),
+            rx_at: None,
+            rx_fifo: VecDeque::new(),
+            overruns: 0,
+            overrun_pending: false,
+            interrupts: 0,
+            tx: None,
+            tx_ready: false,
+            break_since: None,
+            host_rx: Vec::new(),
+            host_received: 0,
+            host_breaks: Vec::new(),
+        }
+    }
+
+    /// Send data from the host to the node
+    pub fn write(&mut self, data: &[u8]) {
+        self.host_tx
+            .extend(data.iter().map(|&byte| Symbol::Byte(byte)));
+    }
+
+    /// Send a break from the host to the node after the data written so far
+    pub fn send_break(&mut self) {
+        self.host_tx.push_back(Symbol::Break);
+    }
+
+    /// Take the data the host has received from the node so far
+    pub fn read(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.host_rx)
+    }
+
+    /// Breaks the host has received from the node, as the number of bytes received before each
+    pub fn breaks(&self) -> &[usize] {
+        &self.host_breaks
+    }
+
+    /// Number of bytes and breaks the host has not yet been able to send
+    pub fn pending(&self) -> usize {
+        self.host_tx.len()
+    }
+
+    /// Change the baud rate of the host. The node doesn't follow.
+    pub fn set_baud_rate(&mut self, baud_rate: u64) {
+        self.baud_rate = baud_rate;
+    }
+
+    /// Change the parity of the host. The node doesn't follow.
+    pub fn set_parity(&mut self, parity: Parity) {
+        self.parity = parity;
+    }
+
+    /// Set the CTS line, i.e. whether the host is ready to receive. Only has an effect with
+    /// RTS/CTS flow control.
+    pub fn set_cts(&mut self, ready: bool) {
+        self.cts = ready;
+    }
+
+    /// The DCD line, i.e. whether the node's radio link is up
+    pub fn dcd(&self) -> bool {
+        self.dcd
+    }
+
+    /// Make the host keep sending when the node asks it to stop
+    pub fn ignore_flow_control(&mut self) {
+        self.ignore_flow_control = true;
+    }
+
+    fn host_may_send(&self) -> bool {
+        if self.ignore_flow_control {
+            return true;
+        }
+        match self.flow_control {
+            FlowControl::None => true,
+            FlowControl::XonXoff => !self.host_paused,
+            FlowControl::RtsCts => self.rts,
+        }
+    }
+
+    pub(crate) fn update(&mut self, now: u64) {
+        // Host -> node
+        if self.overrun_pending && self.rx_fifo.len() < RX_FIFO_SIZE + RX_RING_SIZE {
+            self.overrun_pending = false;
+            self.rx_fifo.push_back(Received::Error(UartError::Overrun));
+            self.interrupts += 1;
+        }
+        if let Some((rx_at, node_baud_rate)) = self.rx_at {
+            if now >= rx_at {
+                let symbol = self.host_tx.pop_front().unwrap();
+                match (symbol, node_baud_rate) {
+                    // The node changed its baud rate in the middle of the byte
+                    _ if node_baud_rate != self.node_baud_rate => {}
+                    (_, None) => {}
+                    (Symbol::Break, Some(_)) => self.receive(Received::Error(UartError::Break)),
+                    (Symbol::Byte(byte), Some(baud_rate)) if baud_rate == self.baud_rate => {
+                        let error = match (self.parity, self.node_parity) {
+                            // The node takes the parity bit for the stop bit
+                            (Parity::Even, Parity::None) if !parity_bit(byte) => {
+                                Some(UartError::Framing)
+                            }
+                            // The node takes the stop bit for the parity bit
+                            (Parity::None, Parity::Even) if !parity_bit(byte) => {
+                                Some(UartError::Parity)
+                            }
+                            _ => None,
+                        };
+                        if let Some(error) = error {
+                            self.receive(Received::Error(error));
+                        }
+                        self.receive(Received::Byte(byte));
+                    }
+                    (Symbol::Byte(byte), Some(_)) => {
+                        self.receive(Received::Error(UartError::Framing));
+                        self.receive(Received::Byte(garble(byte)));
+                    }
+                }
+                self.rx_at = None;
+            }
+        }
+        if self.rx_at.is_none() && !self.host_tx.is_empty() && self.host_may_send() {
+            let symbol = self.host_tx[0];
+            let length = match symbol {
+                Symbol::Byte(_) => 1,
+                Symbol::Break => BREAK_LENGTH,
+            };
+            self.rx_at = Some((
+                now + length * byte_time(self.baud_rate, self.parity),
+                self.node_baud_rate,
+            ));
+            self.capture_edges(now, symbol);
+        }
+
+        // Node -> host
+        if let Some((byte, tx_at)) = self.tx {
+            if now >= tx_at {
+                let xonxoff = self.flow_control == FlowControl::XonXoff;
+                match byte {
+                    XOFF if xonxoff => self.host_paused = true,
+                    XON if xonxoff => self.host_paused = false,
+                    _ if self.node_baud_rate != Some(self.baud_rate) => {
+                        self.host_rx.push(garble(byte));
+                        self.host_received += 1;
+                    }
+                    _ => {
+                        self.host_rx.push(byte);
+                        self.host_received += 1;
+                    }
+                }
+                self.tx = None;
+                self.tx_ready = true;
+                self.interrupts += 1;
+            }
+        }
+    }
+
+    /// Put a byte or error in the receive FIFO, if there's room
+    fn receive(&mut self, received: Received) {
+        if self.rx_fifo.len() < RX_FIFO_SIZE + RX_RING_SIZE {
+            self.rx_fifo.push_back(received);
+            self.interrupts += 1;
+        } else {
+            self.overruns += 1;
+            self.overrun_pending = true;
+        }
+    }
+
+    /// Number of UART interrupts so far. Each one wakes up the node.
+    pub(crate) fn interrupts(&self) -> u64 {
+        self.interrupts
+    }
+
+    /// Has an edge been captured that the node hasn't read? It raises the GPIOTE interrupt, which
+    /// wakes up the node.
+    pub(crate) fn edge_pending(&self, now: u64) -> bool {
+        let now = now * (EDGE_CLOCK as u64 / 1_000_000);
+        self.edges
+            .as_ref()
+            .and_then(|edges| edges.front())
+            .is_some_and(|&time| time <= now)
+    }
+
+    /// Record the edges of a byte or break the host starts sending at `start`
+    fn capture_edges(&mut self, start: u64, symbol: Symbol) {
+        let edges = match &mut self.edges {
+            Some(edges) => edges,
+            None => return,
+        };
+        let bit_time = EDGE_CLOCK as u64 / self.baud_rate;
+        let start = start * (EDGE_CLOCK as u64 / 1_000_000);
+        let byte = match symbol {
+            Symbol::Byte(byte) => byte,
+            Symbol::Break => {
+                edges.push_back(start);
+                edges.push_back(start + BREAK_LENGTH * 10 * bit_time);
+                return;
+            }
+        };
+        // Start bit, data bits LSB first, parity bit, stop bit. The line idles high.
+        let bits = (0..9)
+            .map(|i| match i {
+                0 => false,
+                _ => byte >> (i - 1) & 1 != 0,
+            })
+            .chain((self.parity == Parity::Even).then(|| parity_bit(byte)))
+            .chain([true]);
+        let mut level = true;
+        for (i, bit) in bits.enumerate() {
+            if bit != level {
+                edges.push_back(start + i as u64 * bit_time);
+                level = bit;
+            }
+        }
+    }
+}
+
+pub struct SimUart {
+    port: Rc<RefCell<SerialPort>>,
+    world: Rc<RefCell<crate::World>>,
+}
+
+impl SimUart {
+    pub fn new(port: Rc<RefCell<SerialPort>>, world: Rc<RefCell<crate::World>>) -> Self {
+        Self { port, world }
+    }
+
+    fn port(&self) -> std::cell::RefMut<'_, SerialPort> {
+        let now = self.world.borrow().now;
+        let mut port = self.port.borrow_mut();
+        port.update(now);
+        port
+    }
+}
+
+impl UartHal for SimUart {
+    fn init(&mut self, settings: &UartSettings, _rts_cts: bool) {
+        let mut port = self.port();
+        port.node_baud_rate = Some(settings.baud_rate as u64);
+        port.node_parity = settings.parity;
+        port.edges = None;
+    }
+
+    fn tx_ready(&self) -> bool {
+        self.port().tx_ready
+    }
+
+    fn write(&mut self, byte: u8) {
+        let now = self.world.borrow().now;
+        let mut port = self.port();
+        port.tx_ready = false;
+        let baud_rate = port.node_baud_rate.unwrap_or(port.baud_rate);
+        port.tx = Some((byte, now + byte_time(baud_rate, port.node_parity)));
+    }
+
+    fn read(&mut self) -> Option<Received> {
+        self.port().rx_fifo.pop_front()
+    }
+
+    fn set_break(&mut self, on: bool) {
+        let now = self.world.borrow().now;
+        let mut port = self.port();
+        if on {
+            port.break_since = Some(now);
+        } else if let Some(since) = port.break_since.take() {
+            // The host only notices a break that is longer than a byte
+            if now - since > byte_time(port.baud_rate, port.parity) {
+                let received = port.host_received;
+                port.host_breaks.push(received);
+            }
+        }
+    }
+
+    fn start_edge_capture(&mut self) {
+        let mut port = self.port();
+        port.node_baud_rate = None;
+        port.edges = Some(VecDeque::new());
+    }
+
+    fn edge(&mut self) -> Option<u32> {
+        let now = self.world.borrow().now * (EDGE_CLOCK as u64 / 1_000_000);
+        let mut port = self.port();
+        let edges = port.edges.as_mut()?;
+        if *edges.front()? <= now {
+            // The capture register of the timer wraps around
+            edges.pop_front().map(|time| time as u32)
+        } else {
+            None
+        }
+    }
+
+    fn set_rts(&mut self, ready: bool) {
+        self.port().rts = ready;
+    }
+
+    fn cts(&self) -> bool {
+        let port = self.port();
+        port.flow_control != FlowControl::RtsCts || port.cts
+    }
+
+    fn set_dcd(&mut self, connected: bool) {
+        self.port().dcd = connected;
+    }
+}
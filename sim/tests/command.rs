@@ -0,0 +1,230 @@
+use radiolink::hal::{Parity, AUTOBAUD};
+use radiolink::node::Config;
+use radiolink_sim::rng::Rng;
+use radiolink_sim::{ChannelConfig, Simulator};
+
+/// One second in microseconds
+const SECOND: u64 = 1_000_000;
+
+/// Longer than the guard time around `+++`
+const GUARD: u64 = 1_200_000;
+
+fn payload(seed: u64, len: usize) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    (0..len).map(|_| rng.next_u64() as u8).collect()
+}
+
+fn read(sim: &mut Simulator, node: usize) -> String {
+    String::from_utf8_lossy(&sim.serial(node).read()).into_owned()
+}
+
+/// Send a command line and return the response, including the echo
+fn command(sim: &mut Simulator, node: usize, line: &str) -> String {
+    sim.serial(node).write(format!("{line}\r").as_bytes());
+    sim.run_for(SECOND / 10);
+    read(sim, node)
+}
+
+fn enter_command_mode(sim: &mut Simulator, node: usize) {
+    sim.run_for(GUARD);
+    sim.serial(node).write(b"+++");
+    sim.run_for(GUARD);
+    assert_eq!(read(sim, node), "\r\nOK\r\n");
+}
+
+fn transfer(sim: &mut Simulator, from: usize, to: usize, data: &[u8]) {
+    sim.serial(from).write(data);
+    let mut received = Vec::new();
+    let done = sim.run_until(5 * SECOND, |sim| {
+        received.extend(sim.serial(to).read());
+        received.len() >= data.len()
+    });
+    assert!(done, "received {}/{} bytes", received.len(), data.len());
+    assert!(received == data);
+}
+
+#[test]
+fn settings_are_changed_and_stored() {
+    let mut sim = Simulator::new(200, ChannelConfig::ideal(), Config::default());
+    enter_command_mode(&mut sim, 0);
+
+    assert_eq!(command(&mut sim, 0, "AT"), "AT\r\r\nOK\r\n");
+    assert_eq!(
+        command(&mut sim, 0, "AT+CHAN?"),
+        "AT+CHAN?\r\r\n+CHAN: 7\r\n\r\nOK\r\n"
+    );
+    assert!(command(&mut sim, 0, "at+chan=42").ends_with("\r\nOK\r\n"));
+    assert!(command(&mut sim, 0, "AT+PWR=-8").ends_with("\r\nOK\r\n"));
+    assert!(command(&mut sim, 0, "AT+RATE=2000").ends_with("\r\nOK\r\n"));
+    assert!(command(&mut sim, 0, "AT+BAUD=115200").ends_with("\r\nOK\r\n"));
+    for invalid in [
+        "AT+CHAN=101",
+        "AT+PWR=3",
+        "AT+RATE=500",
+        "AT+BAUD=12345",
+        "AT+CHAN=",
+        "AT+FOO?",
+        "HELLO",
+    ] {
+        assert!(
+            command(&mut sim, 0, invalid).ends_with("\r\nERROR\r\n"),
+            "{invalid}"
+        );
+    }
+    assert!(command(&mut sim, 0, "AT+CHAN?").contains("+CHAN: 42\r\n"));
+    assert!(sim.stored_settings(0).is_none());
+
+    assert!(command(&mut sim, 0, "AT&W").ends_with("\r\nOK\r\n"));
+    let settings = sim.stored_settings(0).unwrap();
+    assert_eq!(settings.radio.channel, 42);
+    assert_eq!(settings.radio.tx_power, -8);
+    assert_eq!(settings.uart.baud_rate, 115200);
+
+    assert_eq!(command(&mut sim, 0, "ATO"), "ATO\r\r\nOK\r\n");
+    sim.serial(0).set_baud_rate(115200);
+    // The simulated radio ignores the channel, so the link works again once the peer is at the
+    // same data rate
+    enter_command_mode(&mut sim, 1);
+    assert!(command(&mut sim, 1, "AT+RATE=2000").ends_with("\r\nOK\r\n"));
+    assert_eq!(command(&mut sim, 1, "ATO"), "ATO\r\r\nOK\r\n");
+    transfer(&mut sim, 0, 1, &payload(200, 2000));
+    transfer(&mut sim, 1, 0, &payload(201, 2000));
+}
+
+#[test]
+fn stats_are_reported() {
+    let mut sim = Simulator::new(210, ChannelConfig::ideal(), Config::default());
+    enter_command_mode(&mut sim, 1);
+    assert!(command(&mut sim, 1, "AT+STATS?").contains(
+        "\r\n+STATS: uart_dropped=0,radio_dropped=0,auth_failures=0,replays=0,uart_overruns=0,\
+         uart_parity_errors=0,uart_framing_errors=0,uart_breaks=0,peer_line_errors=0,\
+         link_drops=0\r\n\r\nOK\r\n"
+    ));
+}
+
+#[test]
+fn link_state_is_reported() {
+    let mut sim = Simulator::new(215, ChannelConfig::ideal(), Config::default());
+    enter_command_mode(&mut sim, 0);
+    assert!(command(&mut sim, 0, "AT+LINK?").contains("\r\n+LINK: UP\r\n\r\nOK\r\n"));
+
+    // The peer stops answering
+    sim.stall(1, 2 * SECOND);
+    sim.run_for(3 * SECOND / 2);
+    assert!(command(&mut sim, 0, "AT+LINK?").contains("\r\n+LINK: DOWN\r\n\r\nOK\r\n"));
+    sim.run_for(SECOND);
+    assert!(command(&mut sim, 0, "AT+LINK?").contains("\r\n+LINK: UP\r\n\r\nOK\r\n"));
+    assert!(command(&mut sim, 0, "AT+STATS?").contains(",link_drops=1\r\n"));
+}
+
+#[test]
+fn escape_sequence_without_guard_time_is_data() {
+    let mut sim = Simulator::new(220, ChannelConfig::ideal(), Config::default());
+    sim.run_for(GUARD);
+
+    // Not followed by silence
+    transfer(&mut sim, 0, 1, b"+++abc");
+    // Not preceded by silence
+    transfer(&mut sim, 0, 1, b"abc+++");
+    sim.run_for(GUARD);
+    // Too few
+    transfer(&mut sim, 0, 1, b"++");
+    sim.run_for(GUARD);
+    // Too many
+    transfer(&mut sim, 0, 1, b"++++");
+    sim.run_for(GUARD);
+    assert_eq!(read(&mut sim, 0), "");
+}
+
+#[test]
+fn data_from_peer_waits_for_data_mode() {
+    let mut sim = Simulator::new(230, ChannelConfig::lossy(), Config::default());
+    enter_command_mode(&mut sim, 0);
+
+    let data = payload(230, 1000);
+    sim.serial(1).write(&data);
+    sim.run_for(2 * SECOND);
+    assert_eq!(read(&mut sim, 0), "");
+
+    // The first bytes after ATO are data again
+    sim.serial(0).write(b"ATO\rxyz");
+    let mut received = Vec::new();
+    let done = sim.run_until(5 * SECOND, |sim| {
+        received.extend(sim.serial(0).read());
+        received.len() >= 10 + data.len()
+    });
+    assert!(done);
+    assert!(received[..10] == *b"ATO\r\r\nOK\r\n");
+    assert!(received[10..] == data);
+    assert!(sim.serial(1).read() == b"xyz");
+}
+
+#[test]
+fn baud_rate_is_detected() {
+    for baud_rate in [1200, 9600, 115200, 1_000_000] {
+        let mut config = Config::default();
+        config.settings.uart.baud_rate = AUTOBAUD;
+        let mut sim =
+            Simulator::with_configs(240, ChannelConfig::ideal(), &[config, Config::default()]);
+        sim.serial(0).set_baud_rate(baud_rate);
+
+        // Used for detecting the baud rate and not passed on
+        sim.serial(0).write(b"AT");
+        sim.run_for(SECOND / 10);
+        transfer(&mut sim, 0, 1, &payload(240, 200));
+        transfer(&mut sim, 1, 0, &payload(241, 200));
+    }
+}
+
+#[test]
+fn detected_baud_rate_is_reported() {
+    let mut config = Config::default();
+    config.settings.uart.baud_rate = AUTOBAUD;
+    let mut sim = Simulator::new(250, ChannelConfig::ideal(), config);
+    sim.serial(0).set_baud_rate(57600);
+    sim.serial(0).write(b"AT");
+    sim.run_for(SECOND / 10);
+
+    enter_command_mode(&mut sim, 0);
+    assert!(command(&mut sim, 0, "AT+BAUD?").contains("+BAUD: AUTO\r\n"));
+}
+
+#[test]
+fn parity_and_port_are_changed() {
+    let mut sim = Simulator::new(260, ChannelConfig::ideal(), Config::default());
+    enter_command_mode(&mut sim, 0);
+
+    assert!(command(&mut sim, 0, "AT+PARITY?").contains("+PARITY: NONE\r\n"));
+    assert!(command(&mut sim, 0, "AT+PARITY=EVEN").ends_with("\r\nOK\r\n"));
+    assert!(command(&mut sim, 0, "AT+PARITY=ODD").ends_with("\r\nERROR\r\n"));
+    assert!(command(&mut sim, 0, "AT+PORT?").contains("+PORT: EDGE\r\n"));
+    assert!(command(&mut sim, 0, "AT+PORT=USB").ends_with("\r\nOK\r\n"));
+    assert!(command(&mut sim, 0, "AT+PORT?").contains("+PORT: USB\r\n"));
+    assert!(command(&mut sim, 0, "AT&W").ends_with("\r\nOK\r\n"));
+    let settings = sim.stored_settings(0).unwrap();
+    assert!(settings.uart.parity == Parity::Even);
+    assert_eq!((settings.uart.tx_pin, settings.uart.rx_pin), (24, 25));
+
+    assert_eq!(command(&mut sim, 0, "ATO"), "ATO\r\r\nOK\r\n");
+    sim.serial(0).set_parity(Parity::Even);
+    transfer(&mut sim, 0, 1, &payload(260, 2000));
+    transfer(&mut sim, 1, 0, &payload(261, 2000));
+    assert_eq!(sim.stats(0).uart_parity_errors, 0);
+
+    // A host without parity
+    sim.serial(0).set_parity(Parity::None);
+    sim.serial(0).write(&payload(262, 100));
+    sim.run_for(SECOND / 10);
+    assert!(sim.stats(0).uart_parity_errors > 0);
+}
+
+#[test]
+fn link_quality_is_reported() {
+    let mut sim = Simulator::new(220, ChannelConfig::ideal(), Config::default());
+    transfer(&mut sim, 0, 1, &payload(220, 5000));
+    enter_command_mode(&mut sim, 0);
+    let response = command(&mut sim, 0, "AT+QUAL?");
+    assert!(response.contains("\r\n+QUAL: rssi=-50,average_rssi=-50,received="));
+    assert!(response.contains(",crc_errors=0,"));
+    assert!(response.ends_with(",give_ups=0\r\n\r\nOK\r\n"));
+}
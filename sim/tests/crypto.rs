@@ -0,0 +1,61 @@
+use ccm::aead::{AeadInPlace, KeyInit};
+use ccm::consts::{U13, U4};
+use ccm::Ccm;
+use radiolink::crypto;
+use radiolink_sim::rng::Rng;
+use radiolink_sim::SimAes as Aes;
+
+type Reference = Ccm<aes::Aes128, U4, U13>;
+
+fn random_bytes<const N: usize>(rng: &mut Rng) -> [u8; N] {
+    let mut bytes = [0; N];
+    for byte in bytes.iter_mut() {
+        *byte = rng.next_u64() as u8;
+    }
+    bytes
+}
+
+#[test]
+fn ccm_matches_reference_implementation() {
+    let mut rng = Rng::new(1);
+    for len in 0..100 {
+        let key: [u8; 16] = random_bytes(&mut rng);
+        let nonce: [u8; 13] = random_bytes(&mut rng);
+        let plaintext: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+
+        let mut expected = plaintext.clone();
+        let tag = Reference::new(&key.into())
+            .encrypt_in_place_detached(&nonce.into(), &[], &mut expected)
+            .unwrap();
+
+        let mut data = plaintext.clone();
+        let mic = crypto::seal(&mut Aes, &key, &nonce, &mut data);
+        assert_eq!(data, expected, "ciphertext, len {len}");
+        assert_eq!(mic[..], tag[..], "mic, len {len}");
+
+        assert!(crypto::open(&mut Aes, &key, &nonce, &mut data, &mic));
+        assert_eq!(data, plaintext);
+    }
+}
+
+#[test]
+fn ccm_rejects_tampering() {
+    let mut rng = Rng::new(2);
+    let key: [u8; 16] = random_bytes(&mut rng);
+    let nonce: [u8; 13] = random_bytes(&mut rng);
+    let plaintext: Vec<u8> = (0..40).map(|_| rng.next_u64() as u8).collect();
+
+    let mut sealed = plaintext.clone();
+    let mic = crypto::seal(&mut Aes, &key, &nonce, &mut sealed);
+
+    for bit in 0..sealed.len() * 8 {
+        let mut data = sealed.clone();
+        data[bit / 8] ^= 1 << (bit % 8);
+        assert!(!crypto::open(&mut Aes, &key, &nonce, &mut data, &mic));
+    }
+
+    let mut other_nonce = nonce;
+    other_nonce[0] ^= 1;
+    let mut data = sealed.clone();
+    assert!(!crypto::open(&mut Aes, &key, &other_nonce, &mut data, &mic));
+}
@@ -0,0 +1,925 @@
+use radiolink::hal::DataRate;
+use radiolink::node::Config;
+use radiolink::packet::VERSION;
+use radiolink::queue::Overflow;
+use radiolink::radio::{LinkState, RetransmitConfig, DEFAULT_ADDRESS};
+use radiolink::storage::Settings;
+use radiolink::uart::FlowControl;
+use radiolink_sim::rng::Rng;
+use radiolink_sim::{ChannelConfig, Recording, Simulator};
+
+/// One second in microseconds
+const SECOND: u64 = 1_000_000;
+
+/// Random data, with all byte values
+fn payload(seed: u64, len: usize) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    (0..len).map(|_| rng.next_u64() as u8).collect()
+}
+
+/// Random data without XON/XOFF, like pppd sends with `asyncmap a0000`
+fn xonxoff_payload(seed: u64, len: usize) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let byte = rng.next_u64() as u8;
+        if byte != 0x11 && byte != 0x13 {
+            data.push(byte);
+        }
+    }
+    data
+}
+
+/// For hosts that send faster than the radio link carries the data
+fn rts_cts_config() -> Config {
+    Config {
+        flow_control: FlowControl::RtsCts,
+        ..Config::default()
+    }
+}
+
+fn xonxoff_config() -> Config {
+    Config {
+        flow_control: FlowControl::XonXoff,
+        ..Config::default()
+    }
+}
+
+/// Send `a_to_b` from node 0 and `b_to_a` from node 1 at the same time, and check that both
+/// arrive intact
+fn transfer(sim: &mut Simulator, a_to_b: &[u8], b_to_a: &[u8], timeout: u64) {
+    sim.serial(0).write(a_to_b);
+    sim.serial(1).write(b_to_a);
+
+    let mut received = [Vec::new(), Vec::new()];
+    let done = sim.run_until(timeout, |sim| {
+        received[1].extend(sim.serial(1).read());
+        received[0].extend(sim.serial(0).read());
+        received[1].len() >= a_to_b.len() && received[0].len() >= b_to_a.len()
+    });
+
+    assert!(
+        done,
+        "transfer timed out: received {}/{} and {}/{} bytes, {:?}",
+        received[1].len(),
+        a_to_b.len(),
+        received[0].len(),
+        b_to_a.len(),
+        sim.channel_stats()
+    );
+    assert!(received[1] == a_to_b, "data from node 0 to node 1 differs");
+    assert!(received[0] == b_to_a, "data from node 1 to node 0 differs");
+    assert_eq!(sim.serial(0).overruns, 0);
+    assert_eq!(sim.serial(1).overruns, 0);
+}
+
+#[test]
+fn one_way_over_ideal_channel() {
+    let mut sim = Simulator::new(1, ChannelConfig::ideal(), Config::default());
+    transfer(&mut sim, &payload(1, 4096), &[], 5 * SECOND);
+}
+
+#[test]
+fn both_ways_over_ideal_channel() {
+    let mut sim = Simulator::new(2, ChannelConfig::ideal(), Config::default());
+    transfer(&mut sim, &payload(2, 4096), &payload(3, 4096), 5 * SECOND);
+}
+
+#[test]
+fn both_ways_over_lossy_channel() {
+    for seed in 0..5 {
+        let mut sim = Simulator::new(seed, ChannelConfig::lossy(), Config::default());
+        transfer(
+            &mut sim,
+            &payload(seed, 4096),
+            &payload(seed + 100, 4096),
+            10 * SECOND,
+        );
+        let stats = sim.channel_stats();
+        assert!(stats.lost > 0 && stats.corrupted > 0 && stats.duplicated > 0);
+    }
+}
+
+#[test]
+fn transfer_across_rtc_wraparound() {
+    // The simulated RTC counters wrap around 3 s after boot, in the middle of the transfer
+    let mut sim = Simulator::new(5, ChannelConfig::lossy(), rts_cts_config());
+    sim.run_for(2 * SECOND + SECOND / 2);
+    transfer(&mut sim, &payload(5, 4096), &payload(6, 4096), 10 * SECOND);
+}
+
+#[test]
+fn stop_and_wait_over_lossy_channel() {
+    let config = Config {
+        window_size: 1,
+        ..Config::default()
+    };
+    let mut sim = Simulator::new(10, ChannelConfig::lossy(), config);
+    transfer(&mut sim, &payload(10, 2048), &[], 10 * SECOND);
+}
+
+#[test]
+fn custom_retransmit_config_over_lossy_channel() {
+    // Slow and without jitter, with a lower retry limit
+    let config = Config {
+        retransmit: RetransmitConfig {
+            initial_timeout: 100,
+            min_timeout: 50,
+            max_timeout: 400,
+            max_jitter: 0,
+            max_tx_count: 8,
+        },
+        ..Config::default()
+    };
+    let mut sim = Simulator::new(15, ChannelConfig::lossy(), config);
+    transfer(
+        &mut sim,
+        &payload(15, 2048),
+        &payload(16, 2048),
+        20 * SECOND,
+    );
+}
+
+#[test]
+fn bursts_with_idle_periods() {
+    let mut sim = Simulator::new(20, ChannelConfig::lossy(), Config::default());
+    for burst in 0..5 {
+        transfer(
+            &mut sim,
+            &payload(burst, 512),
+            &payload(burst + 50, 100),
+            5 * SECOND,
+        );
+        sim.run_for(2 * SECOND);
+    }
+}
+
+#[test]
+fn all_byte_values_with_default_config() {
+    let mut sim = Simulator::new(30, ChannelConfig::lossy(), Config::default());
+    let data: Vec<u8> = (0..=255).cycle().take(4096).collect();
+    transfer(&mut sim, &data, &data, 5 * SECOND);
+}
+
+#[test]
+fn busy_main_loop_loses_nothing_at_1m_baud() {
+    let mut config = rts_cts_config();
+    config.settings.uart.baud_rate = 1_000_000;
+    let mut sim = Simulator::new(35, ChannelConfig::ideal(), config);
+    let data = payload(35, 8000);
+
+    // 2 ms is 200 bytes at 1M baud, much more than the 6-byte FIFO of the UART holds
+    sim.serial(0).write(&data);
+    let mut received = Vec::new();
+    let done = sim.run_until(5 * SECOND, |sim| {
+        if sim.now() % 10_000 < 5 {
+            sim.stall(0, 2000);
+        }
+        received.extend(sim.serial(1).read());
+        received.len() >= data.len()
+    });
+    assert!(done, "received {}/{} bytes", received.len(), data.len());
+    assert!(received == data);
+    assert_eq!(sim.serial(0).overruns, 0);
+}
+
+#[test]
+fn main_loop_sleeps_between_events() {
+    let mut sim = Simulator::new(36, ChannelConfig::ideal(), Config::default());
+    let slept = [sim.sleep_time(0), sim.sleep_time(1)];
+    sim.run_for(SECOND);
+    for (node, slept) in slept.into_iter().enumerate() {
+        let idle = sim.sleep_time(node) - slept;
+        assert!(idle > SECOND * 95 / 100, "node {node} slept {idle} µs");
+    }
+
+    // Waking up for each byte and radio event keeps up with the serial line, and most of the
+    // time is still spent sleeping
+    let start = sim.now();
+    let slept = [sim.sleep_time(0), sim.sleep_time(1)];
+    transfer(&mut sim, &payload(36, 4096), &[], 5 * SECOND);
+    let elapsed = sim.now() - start;
+    // 4096 bytes at 38400 baud take 1.07 s
+    assert!(elapsed < 1_100_000, "took {elapsed} µs");
+    for (node, slept) in slept.into_iter().enumerate() {
+        let idle = sim.sleep_time(node) - slept;
+        assert!(idle > elapsed * 9 / 10, "node {node} slept {idle} µs");
+    }
+}
+
+/// Node 0 on batteries, waking up every 100 ms, and node 1 listening all the time
+fn duty_cycled_configs() -> [Config; 2] {
+    let sleepy = Config {
+        wake_interval: Some(100),
+        ..Config::default()
+    };
+    [sleepy, Config::default()]
+}
+
+#[test]
+fn duty_cycled_node_saves_power() {
+    let mut sim = Simulator::with_configs(37, ChannelConfig::ideal(), &duty_cycled_configs());
+    sim.run_for(SECOND);
+    let on = [sim.radio_on_time(0), sim.radio_on_time(1)];
+    let clock_on = [sim.clock_on_time(0), sim.clock_on_time(1)];
+    let wakeups = [sim.timer_wakeups(0), sim.timer_wakeups(1)];
+    sim.run_for(2 * SECOND);
+    let on = [sim.radio_on_time(0) - on[0], sim.radio_on_time(1) - on[1]];
+    let clock_on = [
+        sim.clock_on_time(0) - clock_on[0],
+        sim.clock_on_time(1) - clock_on[1],
+    ];
+    let wakeups = [
+        sim.timer_wakeups(0) - wakeups[0],
+        sim.timer_wakeups(1) - wakeups[1],
+    ];
+    // A 10 ms window every 100 ms, and the ramp-ups
+    assert!(
+        on[0] < 2 * SECOND * 12 / 100,
+        "radio was on for {} µs",
+        on[0]
+    );
+    assert!(on[1] > 2 * SECOND * 99 / 100);
+    // The crystal oscillator only runs for the radio
+    assert!(
+        clock_on[0] < 2 * SECOND * 12 / 100,
+        "clock was on for {} µs",
+        clock_on[0]
+    );
+    // The CPU wakes up on the RTC every ms of a listen window, and once for the next one
+    assert!(wakeups[0] < 2 * 10 * 15, "woke up {} times", wakeups[0]);
+    assert!(wakeups[1] > 2 * 1000 * 95 / 100);
+
+    // Data for the sleeping node waits for its next wake-up
+    let start = sim.now();
+    sim.serial(1).write(b"wake up");
+    let mut received = Vec::new();
+    let done = sim.run_until(SECOND, |sim| {
+        received.extend(sim.serial(0).read());
+        received.len() >= 7
+    });
+    assert!(done && received == b"wake up");
+    assert!(sim.now() - start < 120_000, "took {} µs", sim.now() - start);
+
+    // Data from it doesn't
+    let start = sim.now();
+    sim.serial(0).write(b"hello");
+    let mut received = Vec::new();
+    let done = sim.run_until(SECOND, |sim| {
+        received.extend(sim.serial(1).read());
+        received.len() >= 5
+    });
+    assert!(done && received == b"hello");
+    assert!(sim.now() - start < 10_000, "took {} µs", sim.now() - start);
+}
+
+#[test]
+fn duty_cycled_node_over_lossy_channel() {
+    for seed in 0..3 {
+        let mut sim = Simulator::with_configs(seed, ChannelConfig::lossy(), &duty_cycled_configs());
+        transfer(
+            &mut sim,
+            &payload(seed, 2048),
+            &payload(seed + 100, 2048),
+            10 * SECOND,
+        );
+        // Bursts from the listening node, with the other one asleep in between
+        for burst in 0..3 {
+            transfer(&mut sim, &[], &payload(seed + burst, 300), 5 * SECOND);
+            sim.run_for(SECOND);
+        }
+    }
+}
+
+#[test]
+fn breaks_are_passed_on_in_order() {
+    let mut sim = Simulator::new(36, ChannelConfig::lossy(), Config::default());
+    sim.serial(0).write(b"abc");
+    sim.serial(0).send_break();
+    sim.serial(0).write(b"def");
+    sim.run_for(SECOND);
+
+    assert!(sim.serial(1).read() == b"abcdef");
+    assert_eq!(sim.serial(1).breaks(), [3]);
+    assert_eq!(sim.stats(0).uart_breaks, 1);
+}
+
+#[test]
+fn line_errors_are_counted_and_forwarded() {
+    for forward_errors in [false, true] {
+        let config = Config {
+            forward_errors,
+            ..Config::default()
+        };
+        let mut sim = Simulator::new(37, ChannelConfig::ideal(), config);
+
+        // Bytes at the wrong baud rate have no stop bit where the node expects one
+        sim.serial(0).set_baud_rate(9600);
+        sim.serial(0).write(b"xyz");
+        sim.run_for(SECOND / 10);
+        sim.serial(0).set_baud_rate(38400);
+        assert_eq!(sim.serial(1).read().len(), 3);
+        assert_eq!(sim.stats(0).uart_framing_errors, 3);
+        let peer_line_errors = sim.stats(1).peer_line_errors;
+        assert_eq!(peer_line_errors > 0, forward_errors);
+
+        let data = payload(37, 1000);
+        transfer(&mut sim, &data, &data, SECOND);
+        assert_eq!(sim.stats(1).peer_line_errors, peer_line_errors);
+    }
+}
+
+#[test]
+fn host_xoff_pauses_remote_host() {
+    let mut sim = Simulator::new(40, ChannelConfig::ideal(), xonxoff_config());
+    let data = xonxoff_payload(40, 6000);
+
+    // Node 1 stops writing to its host, so its queue fills up and node 0 tells its host to stop
+    sim.serial(1).write(&[0x13]);
+    sim.serial(0).write(&data);
+    sim.run_for(SECOND);
+    assert!(sim.serial(1).read().is_empty());
+    assert!(sim.serial(0).pending() > 0);
+
+    sim.serial(1).write(&[0x11]);
+    sim.run_for(3 * SECOND);
+    assert!(sim.serial(1).read() == data);
+}
+
+#[test]
+fn rts_cts_over_lossy_channel() {
+    let mut sim = Simulator::new(50, ChannelConfig::lossy(), rts_cts_config());
+    let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+    transfer(&mut sim, &data, &data, 5 * SECOND);
+}
+
+#[test]
+fn host_cts_pauses_remote_host() {
+    let mut sim = Simulator::new(60, ChannelConfig::ideal(), rts_cts_config());
+    let data: Vec<u8> = (0..6000).map(|i| i as u8).collect();
+
+    // Node 1 stops writing to its host, so its queue fills up and node 0 deasserts RTS
+    sim.serial(1).set_cts(false);
+    sim.serial(0).write(&data);
+    sim.run_for(SECOND);
+    assert!(sim.serial(1).read().is_empty());
+    assert!(sim.serial(0).pending() > 0);
+
+    sim.serial(1).set_cts(true);
+    sim.run_for(3 * SECOND);
+    assert!(sim.serial(1).read() == data);
+}
+
+#[test]
+fn host_xoff_over_lossy_channel() {
+    for seed in 70..73 {
+        let mut sim = Simulator::new(seed, ChannelConfig::lossy(), xonxoff_config());
+        let data = xonxoff_payload(seed, 6000);
+
+        // Pause for long enough that the sender runs out of credit and has to probe
+        sim.serial(1).write(&[0x13]);
+        sim.serial(0).write(&data);
+        sim.run_for(SECOND);
+        sim.serial(1).write(&[0x11]);
+
+        let mut received = Vec::new();
+        let done = sim.run_until(10 * SECOND, |sim| {
+            received.extend(sim.serial(1).read());
+            received.len() >= data.len()
+        });
+        assert!(done, "seed {seed}: received {} bytes", received.len());
+        assert!(received == data, "seed {seed}");
+        assert_eq!(sim.serial(0).overruns, 0);
+    }
+}
+
+#[test]
+fn host_ignoring_xoff_loses_data_but_link_survives() {
+    for (name, overflow) in [
+        ("drop newest", Overflow::DropNewest),
+        ("drop oldest", Overflow::DropOldest),
+        ("block", Overflow::Block),
+    ] {
+        let config = Config {
+            overflow,
+            ..xonxoff_config()
+        };
+        let mut sim = Simulator::new(80, ChannelConfig::lossy(), config);
+        let data = xonxoff_payload(80, 8000);
+
+        // Node 1's host pauses node 1, so node 0 runs out of credit, and node 0's host keeps
+        // sending anyway
+        sim.serial(1).write(&[0x13]);
+        sim.serial(0).ignore_flow_control();
+        sim.serial(0).write(&data);
+        sim.run_for(3 * SECOND);
+        sim.serial(1).write(&[0x11]);
+        sim.run_for(3 * SECOND);
+
+        let received = sim.serial(1).read();
+        let dropped = sim.stats(0).uart_dropped as usize;
+        let overruns = sim.serial(0).overruns;
+        assert!(dropped + overruns > 0, "{name}");
+        assert_eq!(received.len() + dropped + overruns, data.len(), "{name}");
+        assert_eq!(sim.stats(1).radio_dropped, 0);
+
+        // The link still works afterwards
+        sim.serial(0).overruns = 0;
+        let more = xonxoff_payload(81, 1000);
+        transfer(&mut sim, &more, &more, 2 * SECOND);
+    }
+}
+
+const KEY: [u8; 16] = *b"0123456789abcdef";
+
+#[test]
+fn encrypted_link_over_lossy_channel() {
+    let config = Config {
+        key: Some(KEY),
+        ..Config::default()
+    };
+    let mut sim = Simulator::new(90, ChannelConfig::lossy(), config);
+    let data = payload(90, 4096);
+    transfer(&mut sim, &data, &data, 5 * SECOND);
+    assert_eq!(sim.stats(0).auth_failures, 0);
+    assert_eq!(sim.stats(1).auth_failures, 0);
+}
+
+#[test]
+fn mismatched_keys_deliver_nothing() {
+    let config = Config {
+        key: Some(KEY),
+        ..Config::default()
+    };
+    let other = Config {
+        key: Some(*b"fedcba9876543210"),
+        ..Config::default()
+    };
+    let mut sim = Simulator::with_configs(91, ChannelConfig::ideal(), &[config, other]);
+    sim.serial(0).write(&payload(91, 100));
+    sim.run_for(SECOND);
+    assert!(sim.serial(1).read().is_empty());
+    assert!(sim.stats(1).auth_failures > 0);
+}
+
+#[test]
+fn injected_frames_are_rejected() {
+    let config = Config {
+        key: Some(KEY),
+        ..Config::default()
+    };
+    let mut sim = Simulator::new(92, ChannelConfig::ideal(), config);
+    let mut rng = Rng::new(92);
+
+    // Plaintext data packets, and garbage of every length
+    for id in 0..8 {
+        sim.inject(
+            DEFAULT_ADDRESS,
+            &[8, b'D', id, b'e', b'v', b'i', b'l', b'!'],
+        );
+        sim.run_for(1000);
+    }
+    for len in 0..100 {
+        let mut frame: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+        if let Some(first) = frame.first_mut() {
+            *first = len as u8;
+        }
+        sim.inject(DEFAULT_ADDRESS, &frame);
+        sim.run_for(1000);
+    }
+    assert!(sim.serial(0).read().is_empty());
+    assert!(sim.serial(1).read().is_empty());
+    assert!(sim.stats(0).auth_failures > 0);
+    assert!(sim.stats(1).auth_failures > 0);
+
+    let data = payload(92, 1000);
+    transfer(&mut sim, &data, &data, 2 * SECOND);
+}
+
+fn encrypted_config() -> Config {
+    Config {
+        key: Some(KEY),
+        ..Config::default()
+    }
+}
+
+/// Put recorded frames on air again, like an attacker would. Nothing reaches the hosts, the link
+/// stays up, and both ends count replays.
+fn replay(sim: &mut Simulator, recordings: &[Recording]) {
+    let replays = [sim.stats(0).replays, sim.stats(1).replays];
+    let link_drops = [sim.stats(0).link_drops, sim.stats(1).link_drops];
+    for recording in recordings {
+        sim.inject(recording.address, &recording.bytes);
+        // Not so often that the link is jammed
+        sim.run_for(5000);
+    }
+    assert!(sim.serial(0).read().is_empty());
+    assert!(sim.serial(1).read().is_empty());
+    for node in 0..2 {
+        assert!(sim.stats(node).replays > replays[node]);
+        assert_eq!(sim.stats(node).link_drops, link_drops[node]);
+        assert!(sim.link_state(node) == LinkState::Up);
+    }
+}
+
+/// Reboot a node, which starts a new crypto session, and wait for the link to come up again
+fn reboot_and_reconnect(sim: &mut Simulator, node: usize) {
+    sim.reboot(node);
+    sim.run_for(SECOND / 10);
+    let up = sim.run_until(2 * SECOND, |sim| {
+        sim.link_state(0) == LinkState::Up && sim.link_state(1) == LinkState::Up
+    });
+    assert!(up);
+}
+
+#[test]
+fn recorded_frames_are_rejected_after_a_reboot() {
+    let mut sim = Simulator::new(94, ChannelConfig::ideal(), encrypted_config());
+    sim.start_recording();
+    transfer(&mut sim, &payload(94, 512), &payload(95, 512), 2 * SECOND);
+    let recordings = sim.stop_recording();
+
+    reboot_and_reconnect(&mut sim, 1);
+    replay(&mut sim, &recordings);
+    transfer(&mut sim, &payload(96, 1000), &payload(97, 1000), 2 * SECOND);
+}
+
+#[test]
+fn recorded_frames_from_two_sessions_are_rejected() {
+    let mut sim = Simulator::new(98, ChannelConfig::ideal(), encrypted_config());
+    sim.start_recording();
+    transfer(&mut sim, &payload(98, 512), &payload(99, 512), 2 * SECOND);
+    let first = sim.stop_recording();
+    reboot_and_reconnect(&mut sim, 1);
+    sim.start_recording();
+    transfer(&mut sim, &payload(100, 512), &payload(101, 512), 2 * SECOND);
+    let second = sim.stop_recording();
+
+    // Switching between the sessions doesn't make the frames of either new again
+    let interleaved: Vec<Recording> = first
+        .chunks(4)
+        .zip(second.chunks(4))
+        .flat_map(|(first, second)| first.iter().chain(second).cloned())
+        .collect();
+    replay(&mut sim, &interleaved);
+    transfer(
+        &mut sim,
+        &payload(102, 1000),
+        &payload(103, 1000),
+        2 * SECOND,
+    );
+}
+
+#[test]
+fn packets_from_other_sessions_are_rejected() {
+    let mut sim = Simulator::new(93, ChannelConfig::ideal(), Config::default());
+    sim.run_for(SECOND / 10);
+    assert!(sim.link_state(0) == LinkState::Up && sim.link_state(1) == LinkState::Up);
+
+    // Data packets with the ids the link is about to use, as if left over from an earlier session
+    let mut rng = Rng::new(93);
+    for id in 0..8u16 {
+        let mut frame = vec![21, b'D', VERSION, 0];
+        frame.extend((rng.next_u64() as u32).to_le_bytes());
+        frame.extend([0; 6]);
+        frame.extend(id.to_le_bytes());
+        frame.extend(b"evil!");
+        sim.inject(DEFAULT_ADDRESS, &frame);
+        sim.run_for(1000);
+    }
+    assert!(sim.serial(0).read().is_empty());
+    assert!(sim.serial(1).read().is_empty());
+    assert!(sim.link_state(0) == LinkState::Up && sim.link_state(1) == LinkState::Up);
+
+    let data = payload(93, 1000);
+    transfer(&mut sim, &data, &data, 2 * SECOND);
+}
+
+/// Like `transfer`, between any two nodes and in one direction
+fn send(sim: &mut Simulator, from: usize, to: usize, data: &[u8], timeout: u64) {
+    sim.serial(from).write(data);
+    let mut received = Vec::new();
+    let done = sim.run_until(timeout, |sim| {
+        received.extend(sim.serial(to).read());
+        received.len() >= data.len()
+    });
+    assert!(done, "received {}/{} bytes", received.len(), data.len());
+    assert!(
+        received == data,
+        "data from node {from} to node {to} differs"
+    );
+}
+
+/// Average size of the frames on air while node 0 sends `data` to node 1
+fn average_frame_size(sim: &mut Simulator, data: &[u8]) -> usize {
+    sim.run_for(SECOND);
+    let before = sim.channel_stats();
+    send(sim, 0, 1, data, 10 * SECOND);
+    let after = sim.channel_stats();
+    (after.transmitted_bytes - before.transmitted_bytes) / (after.transmitted - before.transmitted)
+}
+
+#[test]
+fn frame_size_adapts_to_loss() {
+    // A host faster than the radio, so that there's always enough data for big frames
+    let mut config = Config::default();
+    config.settings.uart.baud_rate = 1_000_000;
+    config.flow_control = FlowControl::RtsCts;
+    let data = payload(140, 20_000);
+
+    let mut sim = Simulator::new(150, ChannelConfig::ideal(), config);
+    let clean = average_frame_size(&mut sim, &data);
+    let channel = ChannelConfig {
+        loss: 0.3,
+        ..ChannelConfig::lossy()
+    };
+    let mut sim = Simulator::new(150, channel, config);
+    let lossy = average_frame_size(&mut sim, &data);
+
+    // Acks and hellos are small, so the average is well below the largest data frames
+    assert!(clean > 64, "{clean} bytes per frame");
+    assert!(lossy * 4 < clean * 3, "{lossy} vs {clean} bytes per frame");
+}
+
+/// Nodes that start each session at 250 kbit and step the data rate up and down, with hosts
+/// faster than the radio
+fn adaptive_config() -> Config {
+    let mut config = Config {
+        adaptive_rate: true,
+        flow_control: FlowControl::RtsCts,
+        ..Config::default()
+    };
+    config.settings.radio.data_rate = DataRate::Kbit250;
+    config.settings.uart.baud_rate = 1_000_000;
+    config
+}
+
+#[test]
+fn data_rate_steps_up_on_a_strong_signal() {
+    let mut sim = Simulator::new(160, ChannelConfig::ideal(), adaptive_config());
+    transfer(
+        &mut sim,
+        &payload(160, 20_000),
+        &payload(161, 2000),
+        10 * SECOND,
+    );
+    assert!(sim.data_rate(0) == DataRate::Mbit2 && sim.data_rate(1) == DataRate::Mbit2);
+    assert_eq!(sim.stats(0).link_drops, 0);
+}
+
+#[test]
+fn data_rate_steps_down_as_the_signal_weakens() {
+    let mut sim = Simulator::new(165, ChannelConfig::ideal(), adaptive_config());
+    transfer(&mut sim, &payload(165, 20_000), &[], 10 * SECOND);
+    assert!(sim.data_rate(0) == DataRate::Mbit2);
+
+    // Lossy at 2 Mbit, but fine at 1 Mbit
+    sim.set_channel(ChannelConfig {
+        rssi: -82,
+        ..ChannelConfig::ideal()
+    });
+    transfer(&mut sim, &payload(166, 20_000), &[], 10 * SECOND);
+    assert!(sim.data_rate(0) == DataRate::Mbit1 && sim.data_rate(1) == DataRate::Mbit1);
+    assert_eq!(sim.stats(0).link_drops, 0);
+}
+
+#[test]
+fn data_rate_falls_back_when_the_signal_drops() {
+    let mut sim = Simulator::new(170, ChannelConfig::ideal(), adaptive_config());
+    transfer(&mut sim, &payload(170, 20_000), &[], 10 * SECOND);
+    assert!(sim.data_rate(0) == DataRate::Mbit2);
+
+    // Nothing gets through at 2 Mbit, so there's no asking the peer
+    sim.set_channel(ChannelConfig {
+        rssi: -86,
+        ..ChannelConfig::ideal()
+    });
+    transfer(&mut sim, &payload(171, 5000), &[], 10 * SECOND);
+    assert!(sim.data_rate(0) == DataRate::Kbit250 && sim.data_rate(1) == DataRate::Kbit250);
+    assert_eq!(sim.stats(0).link_drops, 0);
+}
+
+#[test]
+fn fixed_rate_peer_keeps_the_rate() {
+    let fixed = Config {
+        adaptive_rate: false,
+        ..adaptive_config()
+    };
+    let mut sim = Simulator::with_configs(175, ChannelConfig::ideal(), &[adaptive_config(), fixed]);
+    transfer(
+        &mut sim,
+        &payload(175, 20_000),
+        &payload(176, 2000),
+        10 * SECOND,
+    );
+    assert!(sim.data_rate(0) == DataRate::Kbit250 && sim.data_rate(1) == DataRate::Kbit250);
+}
+
+#[test]
+fn link_quality_counts_errors_and_retransmits() {
+    let mut sim = Simulator::new(180, ChannelConfig::lossy(), Config::default());
+    transfer(
+        &mut sim,
+        &payload(180, 4096),
+        &payload(181, 4096),
+        10 * SECOND,
+    );
+    let channel = sim.channel_stats();
+    let quality = [sim.stats(0).quality, sim.stats(1).quality];
+    for quality in &quality {
+        assert!(quality.rssi == Some(-70) && quality.average_rssi() == Some(-70));
+        assert!(quality.received > 0 && quality.crc_errors > 0);
+        assert!(quality.sent > 0 && quality.retransmits > 0);
+        assert_eq!(quality.give_ups, 0);
+    }
+    // Only frames hit on the channel fail the CRC
+    let crc_errors = quality[0].crc_errors + quality[1].crc_errors;
+    assert!(crc_errors as usize <= channel.corrupted + channel.collided);
+}
+
+#[test]
+fn packets_given_up_on_are_counted() {
+    let config = Config {
+        retransmit: RetransmitConfig {
+            max_tx_count: 3,
+            ..RetransmitConfig::default()
+        },
+        ..Config::default()
+    };
+    let mut sim = Simulator::new(185, ChannelConfig::ideal(), config);
+    sim.run_for(SECOND / 10);
+
+    // Node 1 stops answering, and node 0 gives up on its packets well before the link times out
+    sim.serial(0).write(&payload(185, 1024));
+    sim.stall(1, 3 * SECOND);
+    sim.run_for(SECOND / 2);
+    assert_eq!(sim.stats(0).quality.give_ups, 1);
+    assert_eq!(sim.stats(0).link_drops, 1);
+}
+
+#[test]
+fn two_pairs_share_a_channel() {
+    let pair = |link_id| Config {
+        settings: Settings {
+            link_id: Some(link_id),
+            ..Settings::default()
+        },
+        ..Config::default()
+    };
+    let configs = [
+        pair(0x1234_5678),
+        pair(0x1234_5678),
+        pair(0x8765_4321),
+        pair(0x8765_4321),
+    ];
+    let mut sim = Simulator::with_configs(100, ChannelConfig::ideal(), &configs);
+
+    // Both links busy in both directions, starting at the same time. The retransmit jitter keeps
+    // the links from colliding in lock-step.
+    let data = [
+        payload(100, 2000),
+        payload(101, 2000),
+        payload(102, 2000),
+        payload(103, 2000),
+    ];
+    for (node, data) in data.iter().enumerate() {
+        sim.serial(node).write(data);
+    }
+    let mut received = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+    let done = sim.run_until(5 * SECOND, |sim| {
+        for (node, received) in received.iter_mut().enumerate() {
+            received.extend(sim.serial(node).read());
+        }
+        received.iter().all(|received| received.len() >= 2000)
+    });
+    assert!(done, "{:?}", sim.channel_stats());
+    // Each node receives from its peer only
+    assert!(received[0] == data[1]);
+    assert!(received[1] == data[0]);
+    assert!(received[2] == data[3]);
+    assert!(received[3] == data[2]);
+}
+
+#[test]
+fn link_goes_down_while_peer_is_silent() {
+    let mut sim = Simulator::new(120, ChannelConfig::lossy(), Config::default());
+    sim.run_for(SECOND / 10);
+    assert!(sim.link_state(0) == LinkState::Up && sim.link_state(1) == LinkState::Up);
+    assert!(sim.serial(0).dcd() && sim.serial(1).dcd());
+
+    // Node 1 stops answering in the middle of a transfer. The packets in flight are lost, and the
+    // host of node 0 is told.
+    sim.serial(0).write(&payload(120, 4096));
+    sim.run_for(SECOND / 10);
+    sim.stall(1, 3 * SECOND);
+    sim.run_for(2 * SECOND);
+    assert!(sim.link_state(0) != LinkState::Up);
+    assert!(!sim.serial(0).dcd());
+    assert_eq!(sim.stats(0).link_drops, 1);
+
+    // Both ends start over when node 1 is back
+    sim.run_for(2 * SECOND);
+    assert!(sim.link_state(0) == LinkState::Up && sim.link_state(1) == LinkState::Up);
+    assert!(sim.serial(0).dcd() && sim.serial(1).dcd());
+    sim.serial(1).read();
+    transfer(
+        &mut sim,
+        &payload(121, 2048),
+        &payload(122, 2048),
+        5 * SECOND,
+    );
+}
+
+#[test]
+fn peer_reboot_restarts_the_link() {
+    let mut sim = Simulator::new(130, ChannelConfig::lossy(), Config::default());
+    transfer(
+        &mut sim,
+        &payload(130, 1000),
+        &payload(131, 1000),
+        5 * SECOND,
+    );
+
+    // Packet ids start over on both ends
+    sim.reboot(1);
+    sim.run_for(SECOND / 2);
+    assert!(sim.link_state(0) == LinkState::Up && sim.link_state(1) == LinkState::Up);
+    assert_eq!(sim.stats(0).link_drops, 1);
+    transfer(
+        &mut sim,
+        &payload(132, 2048),
+        &payload(133, 2048),
+        5 * SECOND,
+    );
+}
+
+#[test]
+fn link_stays_up_while_idle() {
+    let mut sim = Simulator::new(140, ChannelConfig::lossy(), Config::default());
+    sim.run_for(10 * SECOND);
+    assert!(sim.link_state(0) == LinkState::Up && sim.link_state(1) == LinkState::Up);
+    assert_eq!(sim.stats(0).link_drops, 0);
+    assert_eq!(sim.stats(1).link_drops, 0);
+    // Keepalives are all that's sent
+    assert!(sim.channel_stats().transmitted < 200);
+}
+
+#[test]
+fn pairing_agrees_on_a_link_id() {
+    // Node 2 is an unpaired bystander
+    let mut sim = Simulator::with_configs(110, ChannelConfig::lossy(), &[Config::default(); 3]);
+    sim.pair(0);
+    sim.run_for(SECOND / 10);
+    sim.pair(1);
+    let done = sim.run_until(5 * SECOND, |sim| {
+        sim.stored_link_id(0).is_some() && sim.stored_link_id(1).is_some()
+    });
+    assert!(done);
+    assert_eq!(sim.stored_link_id(0), sim.stored_link_id(1));
+    assert_eq!(sim.stored_link_id(2), None);
+
+    // The pair only hears each other now
+    sim.run_for(SECOND);
+    send(&mut sim, 0, 1, &payload(110, 1000), 2 * SECOND);
+    sim.serial(2).write(&payload(111, 100));
+    sim.run_for(SECOND);
+    assert!(sim.serial(0).read().is_empty());
+    assert!(sim.serial(1).read().is_empty());
+}
+
+#[test]
+fn paired_link_ids_are_usable_radio_addresses() {
+    for seed in 130..150 {
+        let mut sim = Simulator::new(seed, ChannelConfig::ideal(), Config::default());
+        sim.pair(0);
+        sim.run_for(SECOND / 10);
+        sim.pair(1);
+        let done = sim.run_until(5 * SECOND, |sim| {
+            sim.stored_link_id(0).is_some() && sim.stored_link_id(1).is_some()
+        });
+        assert!(done, "seed {seed}");
+        assert_eq!(sim.stored_link_id(0), sim.stored_link_id(1), "seed {seed}");
+
+        let link_id = sim.stored_link_id(0).unwrap();
+        let bits = format!("{link_id:032b}");
+        assert!(
+            !bits.contains("0000000") && !bits.contains("1111111"),
+            "{link_id:x}"
+        );
+        for byte in [link_id as u8, (link_id >> 24) as u8] {
+            assert!(byte != 0x55 && byte != 0xaa, "{link_id:x}");
+        }
+        assert_ne!(link_id, DEFAULT_ADDRESS);
+    }
+}
+
+#[test]
+fn pairing_alone_times_out() {
+    let mut sim = Simulator::new(120, ChannelConfig::ideal(), Config::default());
+    sim.pair(0);
+    sim.run_for(35 * SECOND);
+    assert_eq!(sim.stored_link_id(0), None);
+
+    // Back on the default address
+    send(&mut sim, 0, 1, &payload(120, 1000), 2 * SECOND);
+}
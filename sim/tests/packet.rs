@@ -0,0 +1,230 @@
+use radiolink::hal::DataRate;
+use radiolink::packet::{
+    Ack, Error, Hello, Offer, Packet, PacketData, RateChange, Wake, FLAG_EXTENSIONS, MAX_DATA_SIZE,
+    MAX_PLAINTEXT_SIZE, MIN_VERSION, VERSION,
+};
+use radiolink_sim::rng::Rng;
+
+fn random_packet(rng: &mut Rng) -> Packet {
+    let ack = Ack {
+        next: rng.next_u64() as u16,
+        sack: rng.next_u64() as u16,
+        limit: rng.next_u64() as u16,
+    };
+    match rng.range(0, 5) {
+        0 => Packet::Ack(rng.next_u64() as u32, ack),
+        1 => {
+            let len = rng.range(0, MAX_DATA_SIZE as u64) as usize;
+            let data: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+            let flags = rng.range(0, 3) as u8;
+            let packet_data = PacketData::new(rng.next_u64() as u16, flags, &data);
+            Packet::Data(rng.next_u64() as u32, ack, packet_data)
+        }
+        2 => Packet::Pair(Offer {
+            id: rng.next_u64() as u32,
+            heard: rng.next_u64() as u32,
+        }),
+        3 => Packet::Wake(Wake {
+            window: rng.next_u64() as u8,
+            interval: rng.next_u64() as u16,
+        }),
+        4 => Packet::Hello(Hello {
+            session: rng.next_u64() as u32,
+            heard: rng.next_u64() as u32,
+            up: rng.chance(0.5),
+            version: rng.next_u64() as u8,
+        }),
+        _ => {
+            let rate =
+                [DataRate::Kbit250, DataRate::Mbit1, DataRate::Mbit2][rng.range(0, 2) as usize];
+            let reply = rng.chance(0.5);
+            Packet::Rate(rng.next_u64() as u32, RateChange { rate, reply })
+        }
+    }
+}
+
+#[test]
+fn packets_survive_a_round_trip() {
+    let mut rng = Rng::new(1);
+    for _ in 0..10_000 {
+        let packet = random_packet(&mut rng);
+        // Bytes after the packet don't matter
+        let mut buffer = [0; MAX_PLAINTEXT_SIZE];
+        for byte in buffer.iter_mut() {
+            *byte = rng.next_u64() as u8;
+        }
+        packet.write(VERSION, &mut buffer);
+        assert_eq!(buffer[0] as usize, packet.size());
+        assert!(Packet::read(&buffer) == Ok(packet));
+        assert!(Packet::read(&buffer[..packet.size()]) == Ok(packet));
+    }
+}
+
+#[test]
+fn data_is_kept_exactly() {
+    for len in 0..=MAX_DATA_SIZE {
+        let data: Vec<u8> = (0..len as u8).collect();
+        let ack = Ack {
+            next: 1,
+            sack: 2,
+            limit: 3,
+        };
+        let packet = Packet::Data(7, ack, PacketData::new(9, 1, &data));
+        let mut buffer = [0; MAX_PLAINTEXT_SIZE];
+        packet.write(VERSION, &mut buffer);
+        match Packet::read(&buffer) {
+            Ok(Packet::Data(7, read_ack, packet_data)) => {
+                assert!(read_ack == ack);
+                assert_eq!(packet_data.id, 9);
+                assert_eq!(packet_data.flags, 1);
+                assert_eq!(packet_data.data(), &data[..]);
+            }
+            _ => panic!("data packet of {len} bytes not read back"),
+        }
+    }
+}
+
+#[test]
+fn malformed_frames_are_errors() {
+    assert!(Packet::read(&[]) == Err(Error::TooShort));
+    assert!(Packet::read(&[0, b'A', 1, 0]) == Err(Error::TooShort));
+    assert!(Packet::read(&[3, b'A', 1]) == Err(Error::TooShort));
+    assert!(Packet::read(&[5, b'X', 1, 0, 0]) == Err(Error::UnknownType(b'X')));
+    assert!(Packet::read(&[5, b'A', 1, 0, 0]) == Err(Error::BadLength(b'A')));
+    assert!(Packet::read(&[4, b'D', 1, 0]) == Err(Error::BadLength(b'D')));
+    assert!(Packet::read(&[4, b'H', 1, 0]) == Err(Error::BadLength(b'H')));
+    assert!(Packet::read(&[12, b'P', 1, 0, 0]) == Err(Error::Truncated));
+
+    // One byte of data too many
+    let mut frame = [0; MAX_PLAINTEXT_SIZE + 1];
+    frame[..3].copy_from_slice(&[MAX_PLAINTEXT_SIZE as u8 + 1, b'D', VERSION]);
+    assert!(Packet::read(&frame) == Err(Error::BadLength(b'D')));
+}
+
+#[test]
+fn corrupt_frames_never_panic() {
+    let mut rng = Rng::new(2);
+    for len in 0..300 {
+        let frame: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+        let _ = Packet::read(&frame);
+    }
+
+    // Valid packets with every length byte, and cut short anywhere
+    for _ in 0..1000 {
+        let packet = random_packet(&mut rng);
+        let mut buffer = [0; MAX_PLAINTEXT_SIZE];
+        packet.write(VERSION, &mut buffer);
+        for cut in 0..=packet.size() {
+            let _ = Packet::read(&buffer[..cut]);
+        }
+        for len in 0..=u8::MAX {
+            buffer[0] = len;
+            let read = Packet::read(&buffer);
+            assert!(
+                read.is_err()
+                    || len as usize == packet.size()
+                    || matches!(packet, Packet::Data(..))
+            );
+        }
+    }
+}
+
+/// An offer with the given version and flags, and the bytes after the header
+fn offer_frame(version: u8, flags: u8, rest: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0, b'P', version, flags];
+    frame.extend(rest);
+    frame[0] = frame.len() as u8;
+    frame
+}
+
+const OFFER: [u8; 8] = [1, 0, 0, 0, 2, 0, 0, 0];
+
+#[test]
+fn unknown_versions_are_rejected() {
+    assert!(Packet::read(&offer_frame(0, 0, &OFFER)) == Err(Error::UnsupportedVersion(0)));
+    assert!(
+        Packet::read(&offer_frame(VERSION + 1, 0, &OFFER))
+            == Err(Error::UnsupportedVersion(VERSION + 1))
+    );
+}
+
+#[test]
+fn setup_packets_are_written_as_the_lowest_version() {
+    let mut rng = Rng::new(3);
+    for _ in 0..1000 {
+        let packet = random_packet(&mut rng);
+        let mut buffer = [0; MAX_PLAINTEXT_SIZE];
+        packet.write(0xff, &mut buffer);
+        match packet {
+            Packet::Ack(..) | Packet::Data(..) | Packet::Rate(..) => {
+                assert_eq!(buffer[2], 0xff)
+            }
+            _ => assert!(Packet::read(&buffer) == Ok(packet)),
+        }
+    }
+}
+
+#[test]
+fn unknown_flags_and_extensions_are_ignored() {
+    let offer = Packet::Pair(Offer { id: 1, heard: 2 });
+    assert!(Packet::read(&offer_frame(VERSION, 0x7c, &OFFER)) == Ok(offer));
+
+    let mut rest = vec![6, 0xe0, 2, 0xaa, 0xbb, 0xe1, 0];
+    rest.extend(OFFER);
+    assert!(Packet::read(&offer_frame(VERSION, FLAG_EXTENSIONS, &rest)) == Ok(offer));
+
+    // The flags of data packets are only the known ones
+    let ack = Ack {
+        next: 0,
+        sack: 0,
+        limit: 0,
+    };
+    let packet = Packet::Data(5, ack, PacketData::new(1, 0x01, b"data"));
+    let mut buffer = [0; MAX_PLAINTEXT_SIZE];
+    packet.write(VERSION, &mut buffer);
+    buffer[3] |= 0x70;
+    assert!(Packet::read(&buffer) == Ok(packet));
+}
+
+#[test]
+fn malformed_extensions_are_errors() {
+    // No room for the extensions length, an extension longer than the extensions, and a tag
+    // without a length
+    assert!(Packet::read(&offer_frame(VERSION, FLAG_EXTENSIONS, &[])) == Err(Error::BadExtensions));
+    for extensions in [&[3, 0xe0, 2, 0xaa][..], &[1, 0xe0]] {
+        let mut rest = extensions.to_vec();
+        rest.extend(OFFER);
+        assert!(
+            Packet::read(&offer_frame(VERSION, FLAG_EXTENSIONS, &rest))
+                == Err(Error::BadExtensions)
+        );
+    }
+
+    // Extensions longer than the packet
+    let rest = [200, 0xe0, 0];
+    assert!(
+        Packet::read(&offer_frame(VERSION, FLAG_EXTENSIONS, &rest)) == Err(Error::BadExtensions)
+    );
+}
+
+#[test]
+fn malformed_rate_changes_are_errors() {
+    let change = Packet::Rate(
+        5,
+        RateChange {
+            rate: DataRate::Mbit2,
+            reply: false,
+        },
+    );
+    let mut buffer = [0; MAX_PLAINTEXT_SIZE];
+    change.write(VERSION, &mut buffer);
+    assert!(Packet::read(&buffer) == Ok(change));
+
+    // Unknown to the first version of the format
+    change.write(MIN_VERSION, &mut buffer);
+    assert!(Packet::read(&buffer) == Err(Error::UnknownType(b'R')));
+
+    change.write(VERSION, &mut buffer);
+    buffer[8] = 3;
+    assert!(Packet::read(&buffer) == Err(Error::UnknownRate(3)));
+}
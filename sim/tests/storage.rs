@@ -0,0 +1,138 @@
+use radiolink::hal::{DataRate, FlashHal, Parity, RadioSettings, UartSettings};
+use radiolink::storage::{Settings, Storage};
+use radiolink_sim::SimFlash;
+
+fn settings() -> Settings {
+    Settings {
+        radio: RadioSettings {
+            channel: 42,
+            tx_power: -8,
+            data_rate: DataRate::Mbit2,
+        },
+        uart: UartSettings {
+            baud_rate: 115200,
+            parity: Parity::Even,
+            tx_pin: 24,
+            rx_pin: 25,
+            rts_pin: 1,
+            cts_pin: 18,
+        },
+        link_id: Some(0x1234_5678),
+    }
+}
+
+#[test]
+fn settings_survive_a_reset() {
+    let mut flash = SimFlash::new();
+    assert!(Storage::new(&mut flash).load().is_none());
+
+    Storage::new(&mut flash).save(&settings());
+    assert!(Storage::new(&mut flash).load() == Some(settings()));
+
+    let unpaired = Settings {
+        link_id: None,
+        ..settings()
+    };
+    Storage::new(&mut flash).save(&unpaired);
+    assert!(Storage::new(&mut flash).load() == Some(unpaired));
+}
+
+#[test]
+fn corrupt_settings_are_ignored() {
+    // Flipping a bit anywhere in the record invalidates it
+    for offset in (0..28).step_by(4) {
+        for bit in 0..32 {
+            let mut flash = SimFlash::new();
+            Storage::new(&mut flash).save(&settings());
+            let mut word = [0; 4];
+            flash.read(offset, &mut word);
+            let word = u32::from_le_bytes(word) ^ (1 << bit);
+            // Programming can only clear bits, so rewrite the whole page
+            let mut page = [0; 28];
+            flash.read(0, &mut page);
+            page[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+            flash.erase();
+            for (i, chunk) in page.chunks(4).enumerate() {
+                flash.write_word(i * 4, u32::from_le_bytes(chunk.try_into().unwrap()));
+            }
+            assert!(
+                Storage::new(&mut flash).load().is_none(),
+                "bit {bit} at offset {offset}"
+            );
+        }
+    }
+}
+
+#[test]
+fn settings_of_version_1_are_loaded() {
+    // Saved by the first version, without parity
+    let record = [
+        0x4643_4c52,
+        0x6dd1_1001,
+        0x0102_f82a,
+        0x0001_c200,
+        0x1201_1918,
+        0x1234_5678,
+    ];
+    let mut flash = SimFlash::new();
+    for (i, word) in record.into_iter().enumerate() {
+        flash.write_word(i * 4, word);
+    }
+    let expected = Settings {
+        uart: UartSettings {
+            parity: Parity::None,
+            ..settings().uart
+        },
+        ..settings()
+    };
+    assert!(Storage::new(&mut flash).load() == Some(expected));
+}
+
+#[test]
+fn interrupted_save_is_ignored() {
+    // The magic is written last, so a save that stops before it leaves no valid record
+    let mut flash = SimFlash::new();
+    Storage::new(&mut flash).save(&settings());
+    flash.write_word(0, 0);
+    assert!(Storage::new(&mut flash).load().is_none());
+}
+
+#[test]
+fn settings_out_of_range_are_ignored() {
+    let invalid = [
+        Settings {
+            radio: RadioSettings {
+                channel: 101,
+                ..settings().radio
+            },
+            ..settings()
+        },
+        Settings {
+            radio: RadioSettings {
+                tx_power: 3,
+                ..settings().radio
+            },
+            ..settings()
+        },
+        Settings {
+            uart: UartSettings {
+                baud_rate: 12345,
+                ..settings().uart
+            },
+            ..settings()
+        },
+        Settings {
+            uart: UartSettings {
+                tx_pin: 32,
+                ..settings().uart
+            },
+            ..settings()
+        },
+    ];
+    for settings in invalid {
+        assert!(!settings.is_valid());
+        let mut flash = SimFlash::new();
+        Storage::new(&mut flash).save(&settings);
+        assert!(Storage::new(&mut flash).load().is_none());
+    }
+}
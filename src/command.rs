@@ -0,0 +1,502 @@
+//! Hayes-style command mode on the serial port.
+//!
+//! The host enters command mode by sending `+++` with a second of silence before and after it,
+//! like with a modem. In command mode, the host can query and change the settings with commands
+//! like `AT+CHAN=42`, store them in flash with `AT&W`, and return to data mode with `ATO`, which
+//! also applies the changed settings. Data from the peer is held back while in command mode.
+//!
+//! | Command          | Meaning                                               |
+//! |------------------|-------------------------------------------------------|
+//! | `AT`             | Does nothing                                          |
+//! | `ATO`            | Apply the settings and return to data mode            |
+//! | `AT&W`           | Store the settings in flash                           |
+//! | `AT+CHAN=<n>`    | Radio channel, 0-100                                  |
+//! | `AT+PWR=<dBm>`   | TX power, one of -30, -20, -16, -12, -8, -4, 0, 4     |
+//! | `AT+RATE=<kbit>` | Radio data rate, 250, 1000 or 2000                    |
+//! | `AT+BAUD=<rate>` | UART baud rate, or `AUTO` to detect it                |
+//! | `AT+PARITY=<p>`  | UART parity, `NONE` or `EVEN`                         |
+//! | `AT+PORT=<port>` | UART pins, `USB` or `EDGE` connector                  |
+//! | `AT+STATS?`      | Counters of the link                                  |
+//! | `AT+QUAL?`       | Signal strength and packet counters of the radio link |
+//! | `AT+LINK?`       | Radio link state, `UP`, `CONNECTING` or `DOWN`        |
+//!
+//! Settings are queried with `?` instead of `=<value>`, e.g. `AT+CHAN?`.
+
+use defmt::debug;
+
+use crate::hal::{
+    DataRate, Parity, AUTOBAUD, BAUD_RATES, EDGE_PINS, MAX_CHANNEL, TX_POWERS, USB_PINS,
+};
+use crate::node::Stats;
+use crate::queue::{Overflow, Queue};
+use crate::radio::LinkState;
+use crate::rtc::Deadline;
+use crate::storage::Settings;
+
+/// Silence required before and after `+++`
+const GUARD_TIME: u64 = 1000;
+
+const ESCAPE: u8 = b'+';
+const MAX_LINE_SIZE: usize = 40;
+
+/// Size of the queue for responses to the host
+pub const OUTPUT_SIZE: usize = 512;
+
+/// Asks the node to do something on behalf of the host
+pub enum Request {
+    /// Store the settings in flash
+    Save,
+    /// Apply the settings and return to data mode once everything has been sent to the host
+    DataMode,
+}
+
+#[derive(PartialEq, Eq)]
+enum State {
+    Data,
+    Command,
+    /// `ATO` received, waiting for the response to be sent
+    Leaving,
+}
+
+pub struct Command {
+    state: State,
+    /// When the last byte was received from the host in data mode
+    last_rx: u64,
+    /// Number of escape characters held back, which may be the start of `+++`
+    escapes: u8,
+    line: heapless::Vec<u8, MAX_LINE_SIZE>,
+    /// The line didn't fit in the buffer
+    line_overflow: bool,
+    output: Queue<OUTPUT_SIZE>,
+}
+
+impl Command {
+    pub fn new() -> Self {
+        Self {
+            state: State::Data,
+            last_rx: 0,
+            escapes: 0,
+            line: heapless::Vec::new(),
+            line_overflow: false,
+            output: Queue::new(Overflow::DropNewest),
+        }
+    }
+
+    /// In command mode, the UART sends responses from [`Command::output`] instead of data from
+    /// the peer
+    pub fn is_active(&self) -> bool {
+        self.state != State::Data
+    }
+
+    /// Responses to the host
+    pub fn output(&mut self) -> &mut Queue<OUTPUT_SIZE> {
+        &mut self.output
+    }
+
+    /// Responses not yet taken from [`Command::output`]
+    pub fn pending_output(&self) -> &Queue<OUTPUT_SIZE> {
+        &self.output
+    }
+
+    /// Return to data mode after [`Request::DataMode`]
+    pub fn data_mode(&mut self, now: u64) {
+        debug!("command - data mode");
+        self.state = State::Data;
+        self.last_rx = now;
+    }
+
+    /// When the escape characters held back are either the escape sequence or data, after the
+    /// guard time without another byte
+    pub fn next_timeout(&self) -> Option<Deadline> {
+        (self.state == State::Data && self.escapes > 0)
+            .then(|| Deadline::after(self.last_rx, GUARD_TIME as u32))
+    }
+
+    /// Handle the bytes from the host. In data mode, they are passed on to `tx_queue` except
+    /// for the escape sequence.
+    pub fn tick<const N: usize>(
+        &mut self,
+        now: u64,
+        host_rx: &mut Queue<N>,
+        tx_queue: &mut Queue,
+        settings: &mut Settings,
+        stats: &Stats,
+        link: LinkState,
+    ) -> Option<Request> {
+        match self.state {
+            State::Data => {
+                while tx_queue.accepts(1) {
+                    if let Some(byte) = host_rx.dequeue() {
+                        self.data_byte(now, byte, tx_queue);
+                    } else if host_rx.take_break() {
+                        self.last_rx = now;
+                        self.release_escapes(tx_queue);
+                        tx_queue.enqueue_break();
+                    } else {
+                        break;
+                    }
+                }
+                if host_rx.take_line_error() {
+                    tx_queue.set_line_error();
+                }
+                if now - self.last_rx >= GUARD_TIME && self.escapes > 0 {
+                    if self.escapes == 3 {
+                        debug!("command - command mode");
+                        self.escapes = 0;
+                        self.state = State::Command;
+                        self.line.clear();
+                        self.line_overflow = false;
+                        self.respond(b"OK");
+                    } else {
+                        // Too slow to be an escape sequence
+                        self.release_escapes(tx_queue);
+                    }
+                }
+                None
+            }
+            State::Command => {
+                let mut request = None;
+                // Only data is passed on
+                host_rx.take_break();
+                host_rx.take_line_error();
+                while let Some(byte) = host_rx.dequeue() {
+                    request = request.or(self.command_byte(byte, settings, stats, link));
+                    if self.state != State::Command {
+                        // The rest is data
+                        break;
+                    }
+                }
+                request
+            }
+            State::Leaving if self.output.is_empty() => Some(Request::DataMode),
+            State::Leaving => None,
+        }
+    }
+
+    fn data_byte(&mut self, now: u64, byte: u8, tx_queue: &mut Queue) {
+        let silent = now - self.last_rx >= GUARD_TIME;
+        self.last_rx = now;
+        if byte == ESCAPE && self.escapes < 3 && (self.escapes > 0 || silent) {
+            self.escapes += 1;
+        } else {
+            self.release_escapes(tx_queue);
+            tx_queue.enqueue(byte);
+        }
+    }
+
+    /// The escape characters held back turned out to be data
+    fn release_escapes(&mut self, tx_queue: &mut Queue) {
+        for _ in 0..self.escapes {
+            tx_queue.enqueue(ESCAPE);
+        }
+        self.escapes = 0;
+    }
+
+    fn command_byte(
+        &mut self,
+        byte: u8,
+        settings: &mut Settings,
+        stats: &Stats,
+        link: LinkState,
+    ) -> Option<Request> {
+        match byte {
+            b'\r' => {
+                self.write(b"\r");
+                let request = self.execute(settings, stats, link);
+                self.line.clear();
+                self.line_overflow = false;
+                request
+            }
+            b'\n' => None,
+            // Backspace or delete
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() {
+                    self.write(b"\x08 \x08");
+                }
+                None
+            }
+            _ => {
+                if self.line.push(byte).is_err() {
+                    self.line_overflow = true;
+                }
+                self.write(&[byte]);
+                None
+            }
+        }
+    }
+
+    fn execute(
+        &mut self,
+        settings: &mut Settings,
+        stats: &Stats,
+        link: LinkState,
+    ) -> Option<Request> {
+        if self.line.is_empty() {
+            return None;
+        }
+        let mut line = [0; MAX_LINE_SIZE];
+        let line = &mut line[..self.line.len()];
+        line.copy_from_slice(&self.line);
+        line.make_ascii_uppercase();
+        debug!("command - {=[u8]:a}", line);
+
+        let command = match line.strip_prefix(b"AT") {
+            Some(command) if !self.line_overflow => command,
+            _ => {
+                self.respond(b"ERROR");
+                return None;
+            }
+        };
+        let (name, argument) = split(command);
+
+        let mut request = None;
+        let ok = match (name, argument) {
+            (b"", Argument::None) => true,
+            (b"O", Argument::None) => {
+                self.state = State::Leaving;
+                true
+            }
+            (b"&W", Argument::None) => {
+                request = Some(Request::Save);
+                true
+            }
+            (b"+CHAN", Argument::Query) => self.report(b"+CHAN", settings.radio.channel as i32),
+            (b"+CHAN", Argument::Set(channel)) if (0..=MAX_CHANNEL as i32).contains(&channel) => {
+                settings.radio.channel = channel as u8;
+                true
+            }
+            (b"+PWR", Argument::Query) => self.report(b"+PWR", settings.radio.tx_power as i32),
+            (b"+PWR", Argument::Set(power)) if TX_POWERS.iter().any(|&p| p as i32 == power) => {
+                settings.radio.tx_power = power as i8;
+                true
+            }
+            (b"+RATE", Argument::Query) => {
+                let rate = match settings.radio.data_rate {
+                    DataRate::Kbit250 => 250,
+                    DataRate::Mbit1 => 1000,
+                    DataRate::Mbit2 => 2000,
+                };
+                self.report(b"+RATE", rate)
+            }
+            (b"+RATE", Argument::Set(rate)) => {
+                let data_rate = match rate {
+                    250 => Some(DataRate::Kbit250),
+                    1000 => Some(DataRate::Mbit1),
+                    2000 => Some(DataRate::Mbit2),
+                    _ => None,
+                };
+                if let Some(data_rate) = data_rate {
+                    settings.radio.data_rate = data_rate;
+                }
+                data_rate.is_some()
+            }
+            (b"+BAUD", Argument::Query) if settings.uart.baud_rate == AUTOBAUD => {
+                self.write(b"\r\n+BAUD: AUTO\r\n");
+                true
+            }
+            (b"+BAUD", Argument::Query) => self.report(b"+BAUD", settings.uart.baud_rate as i32),
+            (b"+BAUD", Argument::Word(b"AUTO")) => {
+                settings.uart.baud_rate = AUTOBAUD;
+                true
+            }
+            (b"+BAUD", Argument::Set(baud_rate))
+                if BAUD_RATES.iter().any(|&b| b as i32 == baud_rate) =>
+            {
+                settings.uart.baud_rate = baud_rate as u32;
+                true
+            }
+            (b"+PARITY", Argument::Query) => {
+                self.write(match settings.uart.parity {
+                    Parity::None => b"\r\n+PARITY: NONE\r\n",
+                    Parity::Even => b"\r\n+PARITY: EVEN\r\n",
+                });
+                true
+            }
+            (b"+PARITY", Argument::Word(b"NONE")) => {
+                settings.uart.parity = Parity::None;
+                true
+            }
+            (b"+PARITY", Argument::Word(b"EVEN")) => {
+                settings.uart.parity = Parity::Even;
+                true
+            }
+            (b"+PORT", Argument::Query) => {
+                match (settings.uart.tx_pin, settings.uart.rx_pin) {
+                    USB_PINS => self.write(b"\r\n+PORT: USB\r\n"),
+                    EDGE_PINS => self.write(b"\r\n+PORT: EDGE\r\n"),
+                    // Other pins set in the defaults
+                    (tx_pin, rx_pin) => {
+                        self.write(b"\r\n+PORT: ");
+                        self.write_number(tx_pin as i64);
+                        self.write(b",");
+                        self.write_number(rx_pin as i64);
+                        self.write(b"\r\n");
+                    }
+                }
+                true
+            }
+            (b"+PORT", Argument::Word(b"USB")) => {
+                (settings.uart.tx_pin, settings.uart.rx_pin) = USB_PINS;
+                true
+            }
+            (b"+PORT", Argument::Word(b"EDGE")) => {
+                (settings.uart.tx_pin, settings.uart.rx_pin) = EDGE_PINS;
+                true
+            }
+            (b"+STATS", Argument::Query) => {
+                let counters: [(&[u8], u32); 10] = [
+                    (b"uart_dropped", stats.uart_dropped),
+                    (b"radio_dropped", stats.radio_dropped),
+                    (b"auth_failures", stats.auth_failures),
+                    (b"replays", stats.replays),
+                    (b"uart_overruns", stats.uart_overruns),
+                    (b"uart_parity_errors", stats.uart_parity_errors),
+                    (b"uart_framing_errors", stats.uart_framing_errors),
+                    (b"uart_breaks", stats.uart_breaks),
+                    (b"peer_line_errors", stats.peer_line_errors),
+                    (b"link_drops", stats.link_drops),
+                ];
+                self.report_counters(
+                    b"+STATS",
+                    &counters.map(|(name, value)| (name, Some(value as i64))),
+                )
+            }
+            (b"+QUAL", Argument::Query) => {
+                let quality = &stats.quality;
+                // The signal strength is left out until a packet has been received
+                let counters: [(&[u8], Option<i64>); 7] = [
+                    (b"rssi", quality.rssi.map(i64::from)),
+                    (b"average_rssi", quality.average_rssi().map(i64::from)),
+                    (b"received", Some(quality.received as i64)),
+                    (b"crc_errors", Some(quality.crc_errors as i64)),
+                    (b"sent", Some(quality.sent as i64)),
+                    (b"retransmits", Some(quality.retransmits as i64)),
+                    (b"give_ups", Some(quality.give_ups as i64)),
+                ];
+                self.report_counters(b"+QUAL", &counters)
+            }
+            (b"+LINK", Argument::Query) => {
+                self.write(match link {
+                    LinkState::Up => b"\r\n+LINK: UP\r\n",
+                    LinkState::Connecting => b"\r\n+LINK: CONNECTING\r\n",
+                    LinkState::Down => b"\r\n+LINK: DOWN\r\n",
+                });
+                true
+            }
+            _ => false,
+        };
+        if ok {
+            self.respond(b"OK");
+            request
+        } else {
+            self.respond(b"ERROR");
+            None
+        }
+    }
+
+    /// Write the value of a setting, e.g. `+CHAN: 7`
+    fn report(&mut self, name: &[u8], value: i32) -> bool {
+        self.write(b"\r\n");
+        self.write(name);
+        self.write(b": ");
+        self.write_number(value as i64);
+        self.write(b"\r\n");
+        true
+    }
+
+    /// Write named values, e.g. `+STATS: replays=0,link_drops=1`. Values that are `None` are left
+    /// out.
+    fn report_counters(&mut self, name: &[u8], counters: &[(&[u8], Option<i64>)]) -> bool {
+        self.write(b"\r\n");
+        self.write(name);
+        self.write(b": ");
+        let mut first = true;
+        for &(name, value) in counters {
+            if let Some(value) = value {
+                if !first {
+                    self.write(b",");
+                }
+                first = false;
+                self.write(name);
+                self.write(b"=");
+                self.write_number(value);
+            }
+        }
+        self.write(b"\r\n");
+        true
+    }
+
+    /// Write a result code, e.g. `OK`
+    fn respond(&mut self, result: &[u8]) {
+        self.write(b"\r\n");
+        self.write(result);
+        self.write(b"\r\n");
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.output.enqueue(byte);
+        }
+    }
+
+    fn write_number(&mut self, value: i64) {
+        if value < 0 {
+            self.write(b"-");
+        }
+        let mut digits = [0; 20];
+        let mut len = 0;
+        let mut value = value.unsigned_abs();
+        loop {
+            digits[len] = b'0' + (value % 10) as u8;
+            len += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        digits[..len].reverse();
+        self.write(&digits[..len]);
+    }
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+enum Argument<'a> {
+    None,
+    /// `?`
+    Query,
+    /// `=<value>`
+    Set(i32),
+    /// `=<word>`, e.g. `=AUTO`
+    Word(&'a [u8]),
+    Invalid,
+}
+
+/// Split a command into the name and the argument
+fn split(command: &[u8]) -> (&[u8], Argument<'_>) {
+    match command.iter().position(|&c| c == b'=' || c == b'?') {
+        None => (command, Argument::None),
+        Some(i) if command[i..] == *b"?" => (&command[..i], Argument::Query),
+        Some(i)
+            if command[i] == b'='
+                && i + 1 < command.len()
+                && command[i + 1..].iter().all(u8::is_ascii_alphabetic) =>
+        {
+            (&command[..i], Argument::Word(&command[i + 1..]))
+        }
+        Some(i) if command[i] == b'=' => {
+            let value = core::str::from_utf8(&command[i + 1..])
+                .ok()
+                .and_then(|value| value.parse().ok());
+            (
+                &command[..i],
+                value.map_or(Argument::Invalid, Argument::Set),
+            )
+        }
+        Some(i) => (&command[..i], Argument::Invalid),
+    }
+}
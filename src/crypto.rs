@@ -0,0 +1,312 @@
+//! Authenticated encryption of radio packets with AES-CCM.
+//!
+//! The nRF51 CCM peripheral only handles BLE-sized packets of up to 27 bytes, so CCM is done in
+//! software on top of a plain AES block encryption, which the nRF51 has in the ECB peripheral.
+//!
+//! An encrypted frame looks like this, with the length byte counting itself like in plaintext
+//! packets:
+//!
+//! ```text
+//! [len, session (4), counter (4), encrypted packet without its length byte.., mic (4)]
+//! ```
+//!
+//! Every node picks a random session id at boot and numbers its frames with the counter, so a
+//! nonce is never reused even though retransmits carry the same packet id with a different
+//! piggybacked ack. The nonce is the session id of the sender and the counter, which ties each
+//! frame to the end that sent it: both ends share the key, so a node rejects frames with its own
+//! session ids, which can only be its own frames sent back at it.
+//!
+//! Frames from the peer are only taken as such once the link handshake has confirmed the peer's
+//! session, see [`Security::confirm_peer`]. Until then, frames from a session that isn't the
+//! peer's are only good for setting up the link, as they may have been recorded earlier. Frames
+//! from the peer's earlier sessions are rejected as replays, and so are frames from the confirmed
+//! session that aren't newer than the last one received.
+
+use defmt::debug;
+
+use crate::hal::{AesHal, RngHal};
+
+pub const KEY_SIZE: usize = 16;
+const BLOCK_SIZE: usize = 16;
+const NONCE_SIZE: usize = 13;
+const MIC_SIZE: usize = 4;
+/// Number of earlier sessions of both ends whose frames are recognized as replays
+const RETIRED_SESSIONS: usize = 4;
+/// Size of the CCM length field. 13 byte nonce + 2 byte length field + flags fill a block.
+const LENGTH_SIZE: usize = 2;
+
+const HEADER_SIZE: usize = 9;
+
+/// Bytes added to a packet by encryption
+pub const OVERHEAD: usize = HEADER_SIZE - 1 + MIC_SIZE;
+
+/// AES-128 key shared by both ends of the link
+pub type Key = [u8; KEY_SIZE];
+
+fn ccm_block(flags: u8, nonce: &[u8; NONCE_SIZE], value: u16) -> [u8; BLOCK_SIZE] {
+    let mut block = [0; BLOCK_SIZE];
+    block[0] = flags;
+    block[1..1 + NONCE_SIZE].copy_from_slice(nonce);
+    block[1 + NONCE_SIZE..].copy_from_slice(&value.to_be_bytes());
+    block
+}
+
+/// CBC-MAC of the plaintext
+fn mac<A: AesHal>(aes: &mut A, key: &Key, nonce: &[u8; NONCE_SIZE], data: &[u8]) -> [u8; MIC_SIZE] {
+    // No associated data, 4 byte MIC
+    let flags = (((MIC_SIZE - 2) / 2) << 3 | (LENGTH_SIZE - 1)) as u8;
+    let mut x = ccm_block(flags, nonce, data.len() as u16);
+    aes.encrypt(key, &mut x);
+    for chunk in data.chunks(BLOCK_SIZE) {
+        for (x, byte) in x.iter_mut().zip(chunk) {
+            *x ^= byte;
+        }
+        aes.encrypt(key, &mut x);
+    }
+    let mut mic = [0; MIC_SIZE];
+    mic.copy_from_slice(&x[..MIC_SIZE]);
+    mic
+}
+
+/// Encrypt or decrypt the data and the MIC in counter mode
+fn ctr<A: AesHal>(
+    aes: &mut A,
+    key: &Key,
+    nonce: &[u8; NONCE_SIZE],
+    data: &mut [u8],
+    mic: &mut [u8; MIC_SIZE],
+) {
+    let flags = (LENGTH_SIZE - 1) as u8;
+    let mut s = ccm_block(flags, nonce, 0);
+    aes.encrypt(key, &mut s);
+    for (mic, s) in mic.iter_mut().zip(s) {
+        *mic ^= s;
+    }
+    for (i, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+        let mut s = ccm_block(flags, nonce, i as u16 + 1);
+        aes.encrypt(key, &mut s);
+        for (byte, s) in chunk.iter_mut().zip(s) {
+            *byte ^= s;
+        }
+    }
+}
+
+/// Encrypt `data` in place with AES-CCM and return the encrypted MIC
+pub fn seal<A: AesHal>(
+    aes: &mut A,
+    key: &Key,
+    nonce: &[u8; NONCE_SIZE],
+    data: &mut [u8],
+) -> [u8; MIC_SIZE] {
+    let mut mic = mac(aes, key, nonce, data);
+    ctr(aes, key, nonce, data, &mut mic);
+    mic
+}
+
+/// Decrypt `data` in place with AES-CCM. Returns false if the MIC doesn't match, in which case
+/// `data` must be discarded.
+pub fn open<A: AesHal>(
+    aes: &mut A,
+    key: &Key,
+    nonce: &[u8; NONCE_SIZE],
+    data: &mut [u8],
+    mic: &[u8; MIC_SIZE],
+) -> bool {
+    let mut received = *mic;
+    ctr(aes, key, nonce, data, &mut received);
+    let expected = mac(aes, key, nonce, data);
+    // Compare in constant time
+    received
+        .iter()
+        .zip(expected)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// The nonce of a frame, from the session id of the end that sent it and the frame counter
+fn nonce(sender: u32, counter: u32) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..4].copy_from_slice(&sender.to_le_bytes());
+    nonce[4..8].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Who sent an authentic frame
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Sender {
+    /// The peer, in the session confirmed by the link handshake. The frame is newer than any
+    /// received before from that session.
+    Peer,
+    /// A session the link handshake hasn't confirmed: a peer that has rebooted, or frames recorded
+    /// earlier
+    Unconfirmed,
+}
+
+/// Encryption state of one end of the link. Without a key, packets are passed through as is.
+pub struct Security<A: AesHal> {
+    aes: A,
+    key: Option<Key>,
+    /// Our session id and the counter of the next frame we send
+    session: u32,
+    counter: u32,
+    /// Session id of the peer confirmed by the link handshake, and the last frame counter
+    /// received from it
+    peer: Option<(u32, u32)>,
+    /// Session id and frame counter of the last frame opened, which the link handshake may
+    /// confirm as the peer's
+    last: Option<(u32, u32)>,
+    /// Earlier session ids of this end and of the peer, oldest first, or 0 for none. Session ids
+    /// are never 0.
+    retired: [u32; RETIRED_SESSIONS],
+    auth_failures: u32,
+    replays: u32,
+}
+
+impl<A: AesHal> Security<A> {
+    pub fn new(aes: A, key: Option<Key>) -> Self {
+        Self {
+            aes,
+            key,
+            session: 0,
+            counter: 0,
+            peer: None,
+            last: None,
+            retired: [0; RETIRED_SESSIONS],
+            auth_failures: 0,
+            replays: 0,
+        }
+    }
+
+    /// Start a new session with a random id. Must be called at boot, before sending anything.
+    pub fn start_session<G: RngHal>(&mut self, rng: &mut G) {
+        if self.session != 0 {
+            self.retire(self.session);
+        }
+        // Sharing a session id with the peer would reuse its nonces
+        let peer = self.peer.map(|(peer_session, _)| peer_session);
+        self.session = loop {
+            let session = rng.random();
+            if session != 0 && Some(session) != peer && !self.retired.contains(&session) {
+                break session;
+            }
+        };
+        self.counter = 0;
+        debug!("crypto - session {=u32:x}", self.session);
+    }
+
+    fn retire(&mut self, session: u32) {
+        self.retired.rotate_left(1);
+        self.retired[RETIRED_SESSIONS - 1] = session;
+    }
+
+    /// Packets that failed authentication
+    pub fn auth_failures(&self) -> u32 {
+        self.auth_failures
+    }
+
+    /// Authentic packets that were rejected because they had been received before
+    pub fn replays(&self) -> u32 {
+        self.replays
+    }
+
+    /// Encrypt the plaintext packet into the frame. Returns false if the frame counter has run
+    /// out, in which case a new session must be started.
+    pub fn seal(&mut self, packet: &[u8], frame: &mut [u8]) -> bool {
+        let len = packet[0] as usize;
+        let key = match self.key {
+            Some(key) => key,
+            None => {
+                frame[..len].copy_from_slice(&packet[..len]);
+                return true;
+            }
+        };
+
+        frame[0] = (len + OVERHEAD) as u8;
+        frame[1..5].copy_from_slice(&self.session.to_le_bytes());
+        frame[5..9].copy_from_slice(&self.counter.to_le_bytes());
+        let data = &mut frame[HEADER_SIZE..HEADER_SIZE + len - 1];
+        data.copy_from_slice(&packet[1..len]);
+        let mic = seal(
+            &mut self.aes,
+            &key,
+            &nonce(self.session, self.counter),
+            data,
+        );
+        frame[HEADER_SIZE + len - 1..][..MIC_SIZE].copy_from_slice(&mic);
+
+        self.counter = self.counter.wrapping_add(1);
+        self.counter != 0
+    }
+
+    /// Check and decrypt a received frame into the plaintext packet. Returns `None` if the frame
+    /// is not authentic or is a replay. Without a key, every frame is taken as the peer's.
+    pub fn open(&mut self, frame: &[u8], packet: &mut [u8]) -> Option<Sender> {
+        let frame_len = (frame[0] as usize).min(frame.len());
+        let key = match self.key {
+            Some(key) => key,
+            None => {
+                let len = frame_len.min(packet.len());
+                packet[..len].copy_from_slice(&frame[..len]);
+                return Some(Sender::Peer);
+            }
+        };
+
+        if frame_len < HEADER_SIZE + MIC_SIZE || frame_len - OVERHEAD > packet.len() {
+            self.auth_failures = self.auth_failures.wrapping_add(1);
+            return None;
+        }
+        let len = frame_len - OVERHEAD;
+        let session = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
+        let counter = u32::from_le_bytes([frame[5], frame[6], frame[7], frame[8]]);
+        let mut mic = [0; MIC_SIZE];
+        mic.copy_from_slice(&frame[HEADER_SIZE + len - 1..frame_len]);
+
+        packet[0] = len as u8;
+        let data = &mut packet[1..len];
+        data.copy_from_slice(&frame[HEADER_SIZE..HEADER_SIZE + len - 1]);
+        if !open(&mut self.aes, &key, &nonce(session, counter), data, &mic) {
+            debug!("crypto - authentication failed");
+            self.auth_failures = self.auth_failures.wrapping_add(1);
+            return None;
+        }
+
+        if session == self.session || self.retired.contains(&session) {
+            debug!("crypto - frame from an old session {=u32:x}", session);
+            self.replays = self.replays.wrapping_add(1);
+            return None;
+        }
+
+        self.last = Some((session, counter));
+        match &mut self.peer {
+            Some((peer_session, peer_counter)) if *peer_session == session => {
+                if counter <= *peer_counter {
+                    debug!("crypto - replayed frame {=u32}", counter);
+                    self.replays = self.replays.wrapping_add(1);
+                    return None;
+                }
+                *peer_counter = counter;
+                Some(Sender::Peer)
+            }
+            _ => Some(Sender::Unconfirmed),
+        }
+    }
+
+    /// The link handshake has confirmed that the last frame opened came from the peer. Its
+    /// session is the peer's from now on.
+    pub fn confirm_peer(&mut self) {
+        let (session, counter) = match self.last {
+            Some(last) => last,
+            None => return,
+        };
+        match self.peer {
+            Some((peer_session, _)) if peer_session == session => {}
+            previous => {
+                debug!("crypto - peer session {=u32:x}", session);
+                if let Some((peer_session, _)) = previous {
+                    self.retire(peer_session);
+                }
+                self.peer = Some((session, counter));
+            }
+        }
+    }
+}
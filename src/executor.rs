@@ -0,0 +1,156 @@
+//! A minimal async executor for a fixed set of tasks that never finish. A task is only polled
+//! after its waker has been called, and the CPU sleeps with WFE while no task has been woken.
+//!
+//! The task futures are moved into a static arena with [`spawn`], so that they don't take up
+//! stack, and stay pinned there forever. State shared by the tasks goes in a static [`Shared`].
+//!
+//! The wakers set a flag for their task and send an event with SEV. An interrupt that fires
+//! between polling the tasks and WFE also sets the event register, so WFE returns right away
+//! instead of missing the wake-up.
+
+use core::cell::{Cell, UnsafeCell};
+use core::convert::Infallible;
+use core::future::Future;
+use core::mem::{align_of, size_of, MaybeUninit};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use cortex_m::interrupt::Mutex;
+
+const MAX_TASKS: usize = 4;
+
+/// Bytes for the futures of all tasks. They only hold references to the shared state and what
+/// they are waiting for.
+const ARENA_SIZE: usize = 256;
+
+struct Arena {
+    memory: UnsafeCell<MaybeUninit<[u64; ARENA_SIZE / 8]>>,
+    used: Mutex<Cell<usize>>,
+}
+
+// Each part of the memory is handed out once, in a critical section
+unsafe impl Sync for Arena {}
+
+static ARENA: Arena = Arena {
+    memory: UnsafeCell::new(MaybeUninit::uninit()),
+    used: Mutex::new(Cell::new(0)),
+};
+
+/// State shared by the tasks, in a static. The tasks run one at a time, only borrow it between
+/// awaits, and the interrupt handlers never touch it, so a flag is enough to catch a borrow
+/// that overlaps another, like `RefCell` does.
+pub struct Shared<T> {
+    value: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU8,
+}
+
+const UNINIT: u8 = 0;
+const FREE: u8 = 1;
+const BORROWED: u8 = 2;
+
+// Only used from thread mode, see above
+unsafe impl<T> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    pub const fn uninit() -> Self {
+        Self {
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU8::new(UNINIT),
+        }
+    }
+
+    /// Write the value, once. `build` writes it in place, so that a value larger than the free
+    /// stack never has to be on the stack.
+    pub fn init<F: FnOnce(&mut MaybeUninit<T>) -> &mut T>(&self, build: F) {
+        assert!(
+            self.state.load(Ordering::Acquire) == UNINIT,
+            "already initialized"
+        );
+        build_in(unsafe { &mut *self.value.get() }, build);
+        self.state.store(FREE, Ordering::Release);
+    }
+
+    /// Borrow the value. Panics if it isn't initialized or already borrowed.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        assert!(
+            self.state.load(Ordering::Acquire) == FREE,
+            "not initialized or borrowed"
+        );
+        self.state.store(BORROWED, Ordering::Release);
+        let result = f(unsafe { (*self.value.get()).assume_init_mut() });
+        self.state.store(FREE, Ordering::Release);
+        result
+    }
+}
+
+/// Kept out of line, so that the storage is a `&mut` argument, which the compiler knows nothing
+/// else accesses and builds the value in directly
+#[inline(never)]
+fn build_in<T, F: FnOnce(&mut MaybeUninit<T>) -> &mut T>(storage: &mut MaybeUninit<T>, build: F) {
+    build(storage);
+}
+
+/// Has the task been woken since it was last polled? All tasks are polled once at the start.
+static WOKEN: [AtomicBool; MAX_TASKS] = [const { AtomicBool::new(true) }; MAX_TASKS];
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+/// The waker data is the index of the task in [`WOKEN`]
+unsafe fn clone(task: *const ()) -> RawWaker {
+    RawWaker::new(task, &VTABLE)
+}
+
+unsafe fn wake(task: *const ()) {
+    WOKEN[task as usize].store(true, Ordering::Release);
+    cortex_m::asm::sev();
+}
+
+unsafe fn drop(_task: *const ()) {}
+
+pub type Task = Pin<&'static mut dyn Future<Output = Infallible>>;
+
+/// Move the future of a task into the arena. Panics if it doesn't fit.
+pub fn spawn<F: Future<Output = Infallible> + 'static>(future: F) -> Task {
+    const {
+        assert!(
+            align_of::<F>() <= align_of::<u64>(),
+            "task alignment too large"
+        )
+    };
+    let future = cortex_m::interrupt::free(|cs| {
+        let used = ARENA.used.borrow(cs);
+        let start = used.get().next_multiple_of(align_of::<F>());
+        let end = start + size_of::<F>();
+        assert!(end <= ARENA_SIZE, "task arena full");
+        used.set(end);
+        unsafe {
+            let slot = (ARENA.memory.get() as *mut u8).add(start) as *mut F;
+            slot.write(future);
+            &mut *slot
+        }
+    });
+    // Never moved or freed
+    unsafe { Pin::new_unchecked(future) }
+}
+
+/// Run the tasks forever
+pub fn run<const N: usize>(mut tasks: [Task; N]) -> ! {
+    const { assert!(N <= MAX_TASKS, "too many tasks") };
+    loop {
+        for (index, task) in tasks.iter_mut().enumerate() {
+            // Cleared before polling, so that a wake-up during the poll isn't lost. The thumbv6m
+            // atomics can't swap.
+            if !WOKEN[index].load(Ordering::Acquire) {
+                continue;
+            }
+            WOKEN[index].store(false, Ordering::Release);
+            let waker = unsafe { Waker::from_raw(RawWaker::new(index as *const (), &VTABLE)) };
+            match task.as_mut().poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(never) => match never {},
+                Poll::Pending => {}
+            }
+        }
+        cortex_m::asm::wfe();
+    }
+}
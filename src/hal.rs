@@ -0,0 +1,294 @@
+//! Hardware abstraction for the peripherals used by the link.
+//!
+//! The firmware implements these traits on top of the nRF51 registers, and the simulator in `sim/`
+//! implements them on top of a virtual radio channel and serial port. The methods map closely to
+//! the nRF51 tasks and events so that the state machines look the same on both.
+
+use defmt::Format;
+
+/// Radio data rates of the nRF51 in Nordic proprietary mode, from slowest to fastest
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum DataRate {
+    Kbit250,
+    Mbit1,
+    Mbit2,
+}
+
+impl DataRate {
+    /// The next faster rate, if any
+    pub fn faster(self) -> Option<Self> {
+        match self {
+            DataRate::Kbit250 => Some(DataRate::Mbit1),
+            DataRate::Mbit1 => Some(DataRate::Mbit2),
+            DataRate::Mbit2 => None,
+        }
+    }
+
+    /// The next slower rate, if any
+    pub fn slower(self) -> Option<Self> {
+        match self {
+            DataRate::Kbit250 => None,
+            DataRate::Mbit1 => Some(DataRate::Kbit250),
+            DataRate::Mbit2 => Some(DataRate::Mbit1),
+        }
+    }
+
+    /// Typical receiver sensitivity of the nRF51 at this rate, in dBm. Weaker packets are lost.
+    pub fn sensitivity(self) -> i8 {
+        match self {
+            DataRate::Kbit250 => -96,
+            DataRate::Mbit1 => -90,
+            DataRate::Mbit2 => -85,
+        }
+    }
+}
+
+/// Transmit powers supported by the nRF51, in dBm
+pub const TX_POWERS: [i8; 8] = [-30, -20, -16, -12, -8, -4, 0, 4];
+
+/// Highest radio channel. The frequency is 2400 MHz + channel.
+pub const MAX_CHANNEL: u8 = 100;
+
+/// Size of the radio packet buffer. The length byte at the start counts the whole packet, and the
+/// radio sends at most 254 bytes after it.
+pub const MAX_PACKET_SIZE: usize = 255;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub struct RadioSettings {
+    /// 0..=[`MAX_CHANNEL`]
+    pub channel: u8,
+    /// One of [`TX_POWERS`]
+    pub tx_power: i8,
+    pub data_rate: DataRate,
+}
+
+impl Default for RadioSettings {
+    /// The micro:bit runtime defaults
+    fn default() -> Self {
+        Self {
+            channel: 7,
+            tx_power: 4,
+            data_rate: DataRate::Mbit1,
+        }
+    }
+}
+
+/// Baud rates supported by the nRF51 UART
+pub const BAUD_RATES: [u32; 18] = [
+    1200, 2400, 4800, 9600, 14400, 19200, 28800, 31250, 38400, 56000, 57600, 76800, 115200, 230400,
+    250000, 460800, 921600, 1_000_000,
+];
+
+/// Detect the baud rate from the first characters received
+pub const AUTOBAUD: u32 = 0;
+
+/// Frequency of the clock that timestamps RX line edges for autobaud
+pub const EDGE_CLOCK: u32 = 16_000_000;
+
+/// The nRF51 UART only does even parity
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Parity {
+    /// 8N1
+    None,
+    /// 8E1
+    Even,
+}
+
+/// TX and RX pins of the micro:bit's UART to the USB interface chip
+pub const USB_PINS: (u8, u8) = (24, 25);
+
+/// TX and RX pins on rings 0 and 1 of the micro:bit's edge connector
+pub const EDGE_PINS: (u8, u8) = (2, 3);
+
+/// Edge connector pin 16, which tells the host whether the radio link is up like the DCD line of a
+/// modem. Only driven with RTS/CTS flow control.
+pub const DCD_PIN: u8 = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub struct UartSettings {
+    /// One of [`BAUD_RATES`], or [`AUTOBAUD`]
+    pub baud_rate: u32,
+    pub parity: Parity,
+    /// GPIO pin numbers, e.g. [`USB_PINS`] or [`EDGE_PINS`] for TX and RX
+    pub tx_pin: u8,
+    pub rx_pin: u8,
+    pub rts_pin: u8,
+    pub cts_pin: u8,
+}
+
+impl Default for UartSettings {
+    fn default() -> Self {
+        Self {
+            baud_rate: 38400,
+            parity: Parity::None,
+            tx_pin: EDGE_PINS.0,
+            rx_pin: EDGE_PINS.1,
+            // Edge connector ring 2 and pin 8
+            rts_pin: 1,
+            cts_pin: 18,
+        }
+    }
+}
+
+pub trait RadioHal {
+    /// Configure the radio
+    fn init(&mut self);
+
+    /// Set the channel, power and data rate. The radio must be disabled.
+    fn configure(&mut self, settings: &RadioSettings);
+
+    /// The packet buffer used for both receiving and transmitting
+    fn packet(&mut self) -> &mut [u8];
+
+    /// Set the base address for both receiving and transmitting. Takes effect the next time the
+    /// receiver or transmitter is enabled.
+    fn set_address(&mut self, base: u32);
+
+    /// Enable the receiver. Receiving starts automatically when the radio is ready.
+    fn rx_enable(&mut self);
+
+    /// Enable the transmitter. Transmitting the packet buffer starts automatically when the
+    /// radio is ready.
+    fn tx_enable(&mut self);
+
+    /// Restart receiving after a packet has been received
+    fn start(&mut self);
+
+    /// Disable the receiver or transmitter
+    fn disable(&mut self);
+
+    /// Check and clear the ADDRESS event, i.e. the start of a packet
+    fn address_event(&mut self) -> bool;
+
+    /// Check and clear the END event, i.e. the end of a packet
+    fn end_event(&mut self) -> bool;
+
+    /// Check and clear the DISABLED event
+    fn disabled_event(&mut self) -> bool;
+
+    /// Stop the 16 MHz crystal oscillator, which the radio needs, to save power while the radio
+    /// is disabled for a while. The CPU and the UART keep running from the internal oscillator.
+    fn stop_clock(&mut self);
+
+    /// Start the crystal oscillator again, and wait until it is running. Needed before enabling the
+    /// radio after [`RadioHal::stop_clock`].
+    fn start_clock(&mut self);
+
+    /// Did the last received packet have a valid CRC?
+    fn crc_ok(&self) -> bool;
+
+    /// Signal strength of the last received packet in dBm, sampled after its address
+    fn rssi(&self) -> i8;
+}
+
+/// Receive errors, as reported by the ERRORSRC register of the nRF51 UART
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum UartError {
+    /// A byte arrived while the receive FIFO was full, and was lost
+    Overrun,
+    /// A byte had the wrong parity
+    Parity,
+    /// A byte had no stop bit, e.g. because of line noise or a wrong baud rate
+    Framing,
+    /// The RX line was held low for longer than a byte
+    Break,
+}
+
+/// What the UART receiver has to report
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Received {
+    Byte(u8),
+    Error(UartError),
+}
+
+pub trait UartHal {
+    /// Configure the pins and start receiving and transmitting. The RTS, CTS and [`DCD_PIN`] pins
+    /// are only configured with `rts_cts`, so that they stay free for other uses otherwise. Can be
+    /// called again to change the settings.
+    fn init(&mut self, settings: &UartSettings, rts_cts: bool);
+
+    /// Has the last written byte been sent? Stays set until the next `write`.
+    fn tx_ready(&self) -> bool;
+
+    /// Write a byte to the transmitter. Raises an interrupt when it has been sent, which wakes up
+    /// the serial task.
+    fn write(&mut self, byte: u8);
+
+    /// Read a received byte or error, if any. Errors come in order with the bytes.
+    fn read(&mut self) -> Option<Received>;
+
+    /// Hold the TX line low, i.e. send a break, or release it. Only called when the last written
+    /// byte has been sent.
+    fn set_break(&mut self, on: bool);
+
+    /// Stop receiving and timestamp the edges on the RX line instead, for detecting the baud rate.
+    /// Receiving resumes with `init`.
+    fn start_edge_capture(&mut self);
+
+    /// Time of the next edge on the RX line in ticks of [`EDGE_CLOCK`], if any. Edges that come
+    /// faster than this is called may be missed.
+    fn edge(&mut self) -> Option<u32>;
+
+    /// Drive the RTS output. `true` means the host may send.
+    fn set_rts(&mut self, ready: bool);
+
+    /// Read the CTS input. `true` means the host is ready to receive.
+    fn cts(&self) -> bool;
+
+    /// Drive the DCD output. `true` means the radio link is up.
+    fn set_dcd(&mut self, connected: bool);
+}
+
+pub trait RtcHal {
+    /// Start the counter at ~1 ms per tick
+    fn init(&mut self);
+
+    /// Check and clear the event that wakes up the timer task
+    fn wake_event(&mut self) -> bool;
+
+    /// Wake up the timer task when the counter reaches the given value, at least two ticks ahead,
+    /// with the COMPARE event. `None` wakes it up on every TICK event instead.
+    fn wake_at(&mut self, counter: Option<u32>);
+
+    /// Current counter value
+    fn counter(&self) -> u32;
+}
+
+pub trait AesHal {
+    /// Encrypt one block in place with AES-128
+    fn encrypt(&mut self, key: &[u8; 16], block: &mut [u8; 16]);
+}
+
+pub trait RngHal {
+    /// Start the random number generator
+    fn init(&mut self);
+
+    /// A random number from a hardware source, suitable for session ids and keys
+    fn random(&mut self) -> u32;
+}
+
+pub trait FlashHal {
+    /// Read from the storage page
+    fn read(&self, offset: usize, data: &mut [u8]);
+
+    /// Erase the storage page to all ones
+    fn erase(&mut self);
+
+    /// Program a word at a word-aligned offset in the storage page. Programming can only clear
+    /// bits, so the word must have been erased.
+    fn write_word(&mut self, offset: usize, word: u32);
+}
+
+impl<F: FlashHal> FlashHal for &mut F {
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        (**self).read(offset, data)
+    }
+
+    fn erase(&mut self) {
+        (**self).erase()
+    }
+
+    fn write_word(&mut self, offset: usize, word: u32) {
+        (**self).write_word(offset, word)
+    }
+}
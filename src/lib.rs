@@ -0,0 +1,20 @@
+//! Serial link over 2.4 GHz radio.
+//!
+//! The link logic is independent of the hardware, see [`hal`]. The firmware binary runs it on the
+//! nRF51, and the simulator in `sim/` runs it on the host.
+
+#![no_std]
+
+pub mod command;
+pub mod crypto;
+pub mod hal;
+mod link;
+pub mod node;
+pub mod packet;
+mod pairing;
+pub mod queue;
+pub mod radio;
+mod rate;
+pub mod rtc;
+pub mod storage;
+pub mod uart;
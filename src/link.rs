@@ -0,0 +1,223 @@
+//! Link setup and supervision.
+//!
+//! Each end picks a random session id when it starts connecting, and sends hellos with its own
+//! session id and the one it has heard from the peer, like pairing offers. The link is up once
+//! the peer echoes our session id back. Data only flows while the link is up, and packet ids
+//! start from zero on both ends with each new session.
+//!
+//! The link goes down when nothing has been heard from the peer for a while, or when a packet
+//! isn't acked after the maximum number of transmits. The end that notices starts over with a new
+//! session id, and the peer starts over too when it hears it, like it does when the other end
+//! reboots. While the link is up, an end that hasn't sent anything for a while sends a hello as a
+//! keepalive.
+//!
+//! A hello that echoes our session id was sent after we picked it, so it can't have been recorded
+//! earlier. With encryption, that confirms the sender's crypto session as the peer's, see
+//! [`crate::crypto::Security::confirm_peer`].
+//!
+//! Hellos also tell the highest version of the packet format the sender knows, and the link uses
+//! the highest version both ends know. A peer that only knows versions older than this end does
+//! is ignored, so the link never comes up with it.
+
+use defmt::{debug, Format};
+
+use crate::packet::{self, Hello};
+use crate::rtc::Deadline;
+
+/// Time between hellos while connecting, plus up to the same amount of random jitter
+const HELLO_INTERVAL: u32 = 20;
+
+/// Time between hellos while nothing is heard from the peer, plus up to [`HELLO_INTERVAL`] of
+/// random jitter
+const SEARCH_INTERVAL: u32 = 250;
+
+/// Send a hello if nothing else has been sent for this long while the link is up, plus up to
+/// [`HELLO_INTERVAL`] of random jitter. Without it, two ends that came up at the same time would
+/// keep sending their keepalives into each other.
+const KEEPALIVE_INTERVAL: u32 = 250;
+
+/// The link goes down if nothing has been heard from the peer for this long
+pub const LINK_TIMEOUT: u64 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum LinkState {
+    /// Nothing heard from the peer
+    Down,
+    /// The peer has been heard, but hasn't echoed our session id yet
+    Connecting,
+    /// Both ends know each other's session id
+    Up,
+}
+
+/// What a hello from the peer means for the link
+pub struct Handshake {
+    /// The peer has started a new session, so the packet ids start over
+    pub new_session: bool,
+    /// The hello echoes our session id, so it's from the peer in this session
+    pub confirmed: bool,
+}
+
+pub struct Link {
+    state: LinkState,
+    own: u32,
+    peer: Option<u32>,
+    /// Version of the packet format both ends know
+    version: u8,
+    /// When something was last heard from the peer
+    heard_at: u64,
+    /// When something was last sent to the peer
+    sent_at: u64,
+    next_hello: Deadline,
+    /// Time from the last packet sent to the next keepalive
+    keepalive: u64,
+    /// The peer needs a hello right away to learn about our session id, or that we know its
+    reply: bool,
+    /// Number of times the link has gone down after being up
+    drops: u32,
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Self {
+            state: LinkState::Down,
+            own: 0,
+            peer: None,
+            version: packet::VERSION,
+            heard_at: 0,
+            sent_at: 0,
+            next_hello: Deadline::after(0, 0),
+            keepalive: KEEPALIVE_INTERVAL as u64,
+            reply: false,
+            drops: 0,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// Identifies the session in data packets and acks while the link is up, so that packets from
+    /// an earlier session are never taken for ones of this session
+    pub fn session(&self) -> Option<u32> {
+        match (self.state, self.peer) {
+            (LinkState::Up, Some(peer)) => Some(self.own ^ peer),
+            _ => None,
+        }
+    }
+
+    /// Version of the packet format to send acks and data as
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn drops(&self) -> u32 {
+        self.drops
+    }
+
+    /// Start over with a new session id. The caller starts the packet ids over.
+    pub fn restart(&mut self, now: u64, random: u32) {
+        // 0 means "nothing heard" in hellos
+        self.own = random.max(1);
+        self.peer = None;
+        self.reply = false;
+        // Nodes that boot at the same time don't all say hello at once
+        self.next_hello = Deadline::after(now, random % HELLO_INTERVAL);
+        self.set_state(LinkState::Down);
+        debug!("link - new session {=u32:x}", self.own);
+    }
+
+    fn set_state(&mut self, state: LinkState) {
+        if state != self.state {
+            debug!("link - {}", state);
+            if self.state == LinkState::Up {
+                self.drops = self.drops.wrapping_add(1);
+            }
+            self.state = state;
+        }
+    }
+
+    /// Has nothing been heard from the peer for `timeout` ms? The caller restarts the link.
+    pub fn timed_out(&self, now: u64, timeout: u64) -> bool {
+        self.state != LinkState::Down && now - self.heard_at >= timeout
+    }
+
+    /// Something was received from the peer
+    pub fn heard(&mut self, now: u64) {
+        self.heard_at = now;
+    }
+
+    /// Something was sent to the peer
+    pub fn sent(&mut self, now: u64) {
+        self.sent_at = now;
+    }
+
+    pub fn hello_due(&self, now: u64) -> bool {
+        self.reply
+            || match self.state {
+                LinkState::Up => now - self.sent_at >= self.keepalive,
+                _ => self.next_hello.has_passed(now),
+            }
+    }
+
+    /// Make the next hello and schedule the one after it
+    pub fn hello(&mut self, now: u64, random: u32) -> Hello {
+        let interval = match self.state {
+            LinkState::Down => SEARCH_INTERVAL,
+            _ => HELLO_INTERVAL,
+        };
+        self.next_hello = Deadline::after(now, interval + random % HELLO_INTERVAL);
+        self.keepalive = (KEEPALIVE_INTERVAL + random % HELLO_INTERVAL) as u64;
+        self.reply = false;
+        Hello {
+            session: self.own,
+            heard: self.peer.unwrap_or(0),
+            up: self.state == LinkState::Up,
+            version: packet::VERSION,
+        }
+    }
+
+    /// Does the hello echo our session id, so it was sent after we picked it?
+    pub fn is_echo(&self, hello: &Hello) -> bool {
+        hello.heard == self.own
+    }
+
+    pub fn handle_hello(&mut self, hello: Hello) -> Handshake {
+        let ignored = Handshake {
+            new_session: false,
+            confirmed: false,
+        };
+        if hello.session == 0 || hello.session == self.own {
+            return ignored;
+        }
+        if hello.version < packet::MIN_VERSION {
+            debug!("link - peer only knows version {=u8}", hello.version);
+            return ignored;
+        }
+        let new_session = self.peer != Some(hello.session);
+        if new_session {
+            self.peer = Some(hello.session);
+            self.version = hello.version.min(packet::VERSION);
+            debug!(
+                "link - peer session {=u32:x}, version {=u8}",
+                hello.session, self.version
+            );
+            // Our session with the peer has ended too, even if the peer has already heard our
+            // session id
+            if self.state == LinkState::Up {
+                self.set_state(LinkState::Connecting);
+            }
+        }
+        let echoed = self.is_echo(&hello);
+        self.set_state(if echoed {
+            LinkState::Up
+        } else {
+            LinkState::Connecting
+        });
+        // The peer doesn't know our session id, or that we know its
+        self.reply |= !echoed || !hello.up;
+        Handshake {
+            new_session,
+            confirmed: echoed,
+        }
+    }
+}
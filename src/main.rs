@@ -1,62 +1,187 @@
 #![no_std]
 #![no_main]
 
+use core::convert::Infallible;
 use core::panic::PanicInfo;
 use core::sync::atomic::{self, Ordering};
 
 use cortex_m_rt::entry;
 use defmt::debug;
 use defmt_rtt as _; // global logger
-use microbit::pac::Peripherals;
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use microbit::pac::{Peripherals, CLOCK, GPIO};
+use radiolink::hal::USB_PINS;
+use radiolink::node::{Config, Node};
+use radiolink::queue::Overflow;
+use radiolink::radio::{RetransmitConfig, MAX_WINDOW_SIZE};
+use radiolink::storage::{Settings, Storage};
+use radiolink::uart::FlowControl;
 
-use crate::queue::Queue;
-use crate::radio::Radio;
-use crate::rtc::Rtc;
-use crate::uart::Uart;
+use crate::executor::Shared;
+use crate::nrf51::{
+    button_pressed, Nrf51Aes, Nrf51Flash, Nrf51Radio, Nrf51Rng, Nrf51Rtc, Nrf51Uart, RADIO_WAKE,
+    SERIAL_WAKE, TIMER_WAKE,
+};
 
-mod queue;
-mod radio;
-mod rtc;
-mod uart;
+mod executor;
+mod nrf51;
 
-// USB UART pins
-// const TX_PIN: u32 = 24;
-// const RX_PIN: u32 = 25;
+// Hold button A during reset on both boards to pair them
+const BUTTON_A_PIN: u32 = 17;
+// Hold button B during reset to use the USB UART, e.g. to reach a board set up for the edge
+// connector
+const BUTTON_B_PIN: u32 = 26;
 
-// Edge connector rings 0 and 1
-const TX_PIN: u32 = 2;
-const RX_PIN: u32 = 3;
+// Number of unacked radio packets in flight, up to MAX_WINDOW_SIZE
+const WINDOW_SIZE: usize = 8;
+
+const _: () = assert!(
+    WINDOW_SIZE >= 1 && WINDOW_SIZE <= MAX_WINDOW_SIZE,
+    "window_size out of range"
+);
+
+fn config() -> Config {
+    Config {
+        window_size: WINDOW_SIZE,
+        // Timeouts computed from the measured round-trip time within these bounds, e.g.
+        // RetransmitConfig { max_timeout: 200, ..Default::default() } for a slow peer
+        retransmit: RetransmitConfig::default(),
+        // Binary transparent. RtsCts needs the RTS and CTS lines wired up, and with XonXoff the
+        // host can't send 0x11 and 0x13 as data.
+        flow_control: FlowControl::None,
+        overflow: Overflow::DropNewest,
+        // Tell the peer about framing, parity and overrun errors on the serial line
+        forward_errors: false,
+        // On batteries, turn the radio off between listen windows, e.g. Some(200) ms. The peer
+        // must keep listening.
+        wake_interval: None,
+        // Switch between 250 kbit, 1 Mbit and 2 Mbit with the link quality, starting each session
+        // at the data rate in the settings. Long links come up more reliably at AT+RATE=250.
+        adaptive_rate: true,
+        // Pre-shared key for encrypting the radio link, e.g. Some(*b"0123456789abcdef")
+        key: None,
+        // Defaults for when there are no settings in flash: channel 7 at 1 Mbit, 38400 baud on
+        // EDGE_PINS, not paired. Can be changed in the AT command mode.
+        settings: Settings::default(),
+    }
+}
 
 #[entry]
 fn main() -> ! {
     let p = Peripherals::take().unwrap();
 
-    let mut rtc = Rtc::new(p.RTC0);
-    rtc.init(&p.CLOCK);
+    let storage = Storage::new(Nrf51Flash::new(p.NVMC));
+    let mut config = config();
+    if let Some(settings) = storage.load() {
+        config.settings = settings;
+    }
+    let pair = button_pressed(&p.GPIO, BUTTON_A_PIN);
+    if button_pressed(&p.GPIO, BUTTON_B_PIN) {
+        (config.settings.uart.tx_pin, config.settings.uart.rx_pin) = USB_PINS;
+    }
 
-    let mut uart = Uart::new(p.UART0);
-    uart.init(&p.GPIO, TX_PIN, RX_PIN);
+    // The peripherals shared by the drivers go in statics, so that the node can be too
+    let (clock, gpio) = (p.CLOCK, p.GPIO);
+    let clock: &'static CLOCK = cortex_m::singleton!(: CLOCK = clock).unwrap();
+    let gpio: &'static GPIO = cortex_m::singleton!(: GPIO = gpio).unwrap();
+    let radio = Nrf51Radio::new(p.RADIO, clock);
+    let uart = Nrf51Uart::new(p.UART0, gpio, p.GPIOTE, p.TIMER0, p.PPI);
+    let rtc = Nrf51Rtc::new(p.RTC0, clock);
+    let (aes, rng) = (Nrf51Aes::new(p.ECB), Nrf51Rng::new(p.RNG));
+    NODE.init(|node| node.write(Node::new(radio, uart, rtc, aes, rng, config)));
+    NODE.with(|node| {
+        node.init();
+        if pair {
+            node.pair();
+        }
+    });
 
-    let mut radio = Radio::new(p.RADIO);
-    radio.init(&p.CLOCK);
+    executor::run([
+        executor::spawn(timer_task()),
+        executor::spawn(serial_task()),
+        executor::spawn(radio_task()),
+        executor::spawn(storage_task(storage)),
+    ])
+}
+
+type Nrf51Node =
+    Node<Nrf51Radio<'static>, Nrf51Uart<'static>, Nrf51Rtc<'static>, Nrf51Aes, Nrf51Rng>;
+
+/// Most of the RAM. There is no room for it on the stack next to the tasks, or for a copy while
+/// building it.
+static NODE: Shared<Nrf51Node> = Shared::uninit();
 
-    let mut uart_to_radio = Queue::new();
-    let mut radio_to_uart = Queue::new();
+/// Settings to be stored in flash after pairing or `AT&W`
+static SAVE: Signal<CriticalSectionRawMutex, Settings> = Signal::new();
+
+/// Run the timeouts of the other tasks when the RTC wakes it up, see `Node::tick_clock`
+async fn timer_task() -> Infallible {
     loop {
-        let now = rtc.tick();
-        uart.tick(now, &mut radio_to_uart, &mut uart_to_radio);
-        radio.tick(now, &mut uart_to_radio, &mut radio_to_uart);
+        TIMER_WAKE.wait().await;
+        NODE.with(|node| node.tick_clock());
+        SERIAL_WAKE.wake();
+        RADIO_WAKE.wake();
+    }
+}
+
+/// The UART and the command mode
+async fn serial_task() -> Infallible {
+    loop {
+        let idle = NODE.with(|node| {
+            node.tick_serial();
+            if let Some(settings) = node.take_settings() {
+                SAVE.signal(settings);
+            }
+            node.serial_is_idle()
+        });
+        // The radio sends what the host wrote, and delivers packets held back by a full queue
+        // once the UART has made room
+        RADIO_WAKE.wake();
+        if idle {
+            SERIAL_WAKE.wait().await;
+        } else {
+            yield_now().await;
+        }
+    }
+}
 
-        radio_to_uart.flow_control(&mut uart_to_radio);
-        uart_to_radio.flow_control(&mut radio_to_uart);
+/// The radio link
+async fn radio_task() -> Infallible {
+    loop {
+        let idle = NODE.with(|node| {
+            node.tick_radio();
+            if let Some(settings) = node.take_settings() {
+                SAVE.signal(settings);
+            }
+            // Received data, or flow control towards the host
+            if !node.serial_is_idle() {
+                SERIAL_WAKE.wake();
+            }
+            node.radio_is_idle()
+        });
+        if idle {
+            RADIO_WAKE.wait().await;
+        } else {
+            yield_now().await;
+        }
+    }
+}
+
+/// Store the settings in flash. The CPU stops while the page is erased, for ~20 ms.
+async fn storage_task(mut storage: Storage<Nrf51Flash>) -> Infallible {
+    loop {
+        let settings = SAVE.wait().await;
+        storage.save(&settings);
     }
 }
 
 #[inline(never)]
 #[panic_handler]
 fn panic(panic_info: &PanicInfo) -> ! {
-    if let Some(s) = panic_info.payload().downcast_ref::<&str>() {
-        debug!("panic: {=str}", *s);
+    if let Some(s) = panic_info.message().as_str() {
+        debug!("panic: {=str}", s);
     } else {
         debug!("panic");
     }
@@ -0,0 +1,274 @@
+use crate::command::{Command, Request};
+use crate::crypto::Key;
+use crate::hal::{AesHal, RadioHal, RngHal, RtcHal, UartHal};
+use crate::queue::{Overflow, Queue};
+use crate::radio::{LinkQuality, LinkState, Radio, RetransmitConfig};
+use crate::rtc::{Deadline, Rtc};
+use crate::storage::Settings;
+use crate::uart::{FlowControl, Uart};
+
+/// Link settings
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// Maximum number of unacked radio packets in flight, from 1 to
+    /// [`MAX_WINDOW_SIZE`](crate::radio::MAX_WINDOW_SIZE), see [`Radio::new`]
+    pub window_size: usize,
+    /// Retransmit timeouts and retry limit
+    pub retransmit: RetransmitConfig,
+    /// Flow control towards the host
+    pub flow_control: FlowControl,
+    /// What to do when the host or the peer sends more than fits in a queue
+    pub overflow: Overflow,
+    /// Signal framing, parity and overrun errors on the serial line to the peer, which counts them
+    /// in [`Stats::peer_line_errors`]. Breaks are always passed on.
+    pub forward_errors: bool,
+    /// Turn the radio off between listen windows to save power, waking up every this many ms. Data
+    /// from the peer is delayed by up to this long. The peer must keep listening, i.e. have this
+    /// set to `None`.
+    pub wake_interval: Option<u16>,
+    /// Step the radio data rate up and down with the link quality, if the peer agrees. Each
+    /// session starts at the data rate in the settings.
+    pub adaptive_rate: bool,
+    /// Pre-shared key for encrypting and authenticating radio packets. Both ends must use the
+    /// same key, or both none.
+    pub key: Option<Key>,
+    /// Radio and UART settings and the link id from pairing, usually loaded from
+    /// [`Storage`](crate::storage::Storage). Unpaired nodes all use the same radio address.
+    pub settings: Settings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window_size: 8,
+            retransmit: RetransmitConfig::default(),
+            flow_control: FlowControl::None,
+            overflow: Overflow::DropNewest,
+            forward_errors: false,
+            wake_interval: None,
+            adaptive_rate: false,
+            key: None,
+            settings: Settings::default(),
+        }
+    }
+}
+
+/// Counters for monitoring the link
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    /// Bytes from the host dropped because the queue towards the radio was full
+    pub uart_dropped: u32,
+    /// Bytes from the peer dropped because the queue towards the UART was full
+    pub radio_dropped: u32,
+    /// Radio packets that failed authentication
+    pub auth_failures: u32,
+    /// Authentic radio packets that had already been received, e.g. replayed by an attacker
+    pub replays: u32,
+    /// Bytes from the host lost because the UART wasn't read in time
+    pub uart_overruns: u32,
+    /// Bytes from the host with a parity error
+    pub uart_parity_errors: u32,
+    /// Bytes from the host without a stop bit
+    pub uart_framing_errors: u32,
+    /// Breaks from the host
+    pub uart_breaks: u32,
+    /// Errors on the peer's serial line, if the peer has `forward_errors` set
+    pub peer_line_errors: u32,
+    /// Times the radio link went down, losing the packets in flight
+    pub link_drops: u32,
+    /// Signal strength, CRC errors and retransmits of the radio link
+    pub quality: LinkQuality,
+}
+
+const HOST_RX_SIZE: usize = 64;
+
+/// One end of the link: the peripherals and the queues between them
+pub struct Node<R: RadioHal, U: UartHal, T: RtcHal, A: AesHal, G: RngHal> {
+    rtc: Rtc<T>,
+    uart: Uart<U>,
+    radio: Radio<R, A, G>,
+    command: Command,
+    /// Bytes from the host, on their way to the radio or the command mode
+    host_rx: Queue<HOST_RX_SIZE>,
+    uart_to_radio: Queue,
+    radio_to_uart: Queue,
+    /// Settings changed by the command mode or pairing. Not applied before leaving the command
+    /// mode.
+    settings: Settings,
+    /// Should the settings be stored?
+    save: bool,
+}
+
+impl<R: RadioHal, U: UartHal, T: RtcHal, A: AesHal, G: RngHal> Node<R, U, T, A, G> {
+    /// Inlined like [`Radio::new`], so that the node is built right where the caller stores it
+    /// instead of on the stack and copied
+    #[inline(always)]
+    pub fn new(radio: R, uart: U, rtc: T, aes: A, rng: G, config: Config) -> Self {
+        let mut node = Self {
+            rtc: Rtc::new(rtc),
+            uart: Uart::new(
+                uart,
+                config.flow_control,
+                config.settings.uart,
+                config.forward_errors,
+            ),
+            radio: Radio::new(
+                radio,
+                aes,
+                rng,
+                config.key,
+                config.settings.link_id,
+                config.settings.radio,
+                config.window_size,
+            ),
+            command: Command::new(),
+            // The UART stops reading when the host sends faster than the bytes can be handled
+            host_rx: Queue::new(Overflow::Block),
+            uart_to_radio: Queue::new(config.overflow),
+            radio_to_uart: Queue::new(config.overflow),
+            settings: config.settings,
+            save: false,
+        };
+        node.radio.set_retransmit_config(config.retransmit);
+        node.radio.set_wake_interval(config.wake_interval);
+        node.radio.set_adaptive_rate(config.adaptive_rate);
+        node
+    }
+
+    pub fn init(&mut self) {
+        self.rtc.init();
+        self.uart.init();
+        let now = self.rtc.tick();
+        self.radio.init(now);
+    }
+
+    /// Start pairing with another node. The link id is stored with the settings, see
+    /// [`Node::take_settings`].
+    pub fn pair(&mut self) {
+        let now = self.rtc.tick();
+        self.radio.pair(now);
+    }
+
+    /// Settings to be stored in flash after pairing or `AT&W`, once
+    pub fn take_settings(&mut self) -> Option<Settings> {
+        if self.save {
+            self.save = false;
+            Some(self.settings)
+        } else {
+            None
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let line_stats = self.uart.line_stats();
+        Stats {
+            uart_dropped: self.uart_to_radio.dropped(),
+            radio_dropped: self.radio_to_uart.dropped(),
+            auth_failures: self.radio.auth_failures(),
+            replays: self.radio.replays(),
+            uart_overruns: line_stats.overruns,
+            uart_parity_errors: line_stats.parity_errors,
+            uart_framing_errors: line_stats.framing_errors,
+            uart_breaks: line_stats.breaks,
+            peer_line_errors: self.radio.peer_line_errors(),
+            link_drops: self.radio.link_drops(),
+            quality: self.radio.link_quality(),
+        }
+    }
+
+    /// State of the radio link, which is also signalled on [`DCD_PIN`](crate::hal::DCD_PIN)
+    pub fn link_state(&self) -> LinkState {
+        self.radio.link_state()
+    }
+
+    /// Is there nothing for [`Node::tick_serial`] to do until a UART interrupt or the next timeout?
+    /// The serial task can then sleep until one of them, or the radio task, wakes it up.
+    pub fn serial_is_idle(&self) -> bool {
+        let uart_idle = if self.command.is_active() {
+            self.uart
+                .is_idle(self.command.pending_output(), &self.host_rx)
+        } else {
+            self.uart.is_idle(&self.radio_to_uart, &self.host_rx)
+        };
+        // Bytes held back while leaving the command mode
+        let host_rx_pending =
+            !self.command.is_active() && !self.host_rx.is_empty() && self.uart_to_radio.accepts(1);
+        uart_idle && !host_rx_pending
+    }
+
+    /// Is there nothing for [`Node::tick_radio`] to do until a radio event or the next timeout?
+    /// The radio task can then sleep until one of them, or the serial task, wakes it up.
+    pub fn radio_is_idle(&self) -> bool {
+        self.radio.is_idle()
+    }
+
+    /// Clear the RTC event that woke up the timer task. The other tasks then run their timeouts.
+    pub fn tick_clock(&mut self) {
+        self.rtc.tick();
+    }
+
+    /// Wake up the timer task at the next timeout of the radio, the UART or the command mode,
+    /// instead of every ms, so that the CPU sleeps through the radio's sleep
+    fn schedule(&mut self) {
+        let now = self.rtc.now();
+        let uart = if self.command.is_active() {
+            self.uart.next_timeout(now, self.command.pending_output())
+        } else {
+            self.uart.next_timeout(now, &self.radio_to_uart)
+        };
+        let deadline = [uart, self.command.next_timeout()]
+            .into_iter()
+            .flatten()
+            .fold(self.radio.next_timeout(now), Deadline::min);
+        self.rtc.wake_at(deadline);
+    }
+
+    /// Move bytes between the host and the queues, and run the command mode. The radio may have
+    /// something to do afterwards.
+    pub fn tick_serial(&mut self) {
+        let now = self.rtc.now();
+        if self.command.is_active() {
+            self.uart
+                .tick(now, self.command.output(), &mut self.host_rx);
+        } else {
+            self.uart
+                .tick(now, &mut self.radio_to_uart, &mut self.host_rx);
+        }
+        let stats = self.stats();
+        match self.command.tick(
+            now,
+            &mut self.host_rx,
+            &mut self.uart_to_radio,
+            &mut self.settings,
+            &stats,
+            self.radio.link_state(),
+        ) {
+            Some(Request::Save) => self.save = true,
+            Some(Request::DataMode) if self.uart.flushed() => {
+                self.radio.configure(self.settings.radio);
+                self.uart.configure(self.settings.uart);
+                self.command.data_mode(now);
+            }
+            _ => {}
+        }
+        self.schedule();
+    }
+
+    /// Send and receive radio packets between the queues and the peer. The serial task has
+    /// something to do afterwards unless [`Node::serial_is_idle`].
+    pub fn tick_radio(&mut self) {
+        let now = self.rtc.now();
+        self.radio
+            .tick(now, &mut self.uart_to_radio, &mut self.radio_to_uart);
+        if let Some(link_id) = self.radio.take_paired() {
+            self.settings.link_id = Some(link_id);
+            self.save = true;
+        }
+        self.uart.set_dcd(self.radio.link_state() == LinkState::Up);
+
+        // The radio stops sending when the peer runs out of credit, so pausing the local host is
+        // all that's needed
+        self.uart_to_radio.flow_control(&mut self.radio_to_uart);
+        self.schedule();
+    }
+}
@@ -0,0 +1,705 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use heapless::spsc::{Consumer, Producer, Queue};
+use microbit::pac::uart0::baudrate::BAUDRATE_A;
+use microbit::pac::{
+    interrupt, Interrupt, CLOCK, ECB, GPIO, GPIOTE, NVIC, NVMC, PPI, RADIO, RNG, RTC0, TIMER0,
+    UART0,
+};
+use radiolink::hal::{
+    AesHal, DataRate, FlashHal, Parity, RadioHal, RadioSettings, Received, RngHal, RtcHal,
+    UartError, UartHal, UartSettings, DCD_PIN, MAX_PACKET_SIZE,
+};
+
+pub struct Nrf51Radio<'a> {
+    radio: RADIO,
+    clock: &'a CLOCK,
+    packet: [u8; MAX_PACKET_SIZE],
+}
+
+impl<'a> Nrf51Radio<'a> {
+    pub fn new(radio: RADIO, clock: &'a CLOCK) -> Self {
+        Self {
+            radio,
+            clock,
+            packet: [0; MAX_PACKET_SIZE],
+        }
+    }
+}
+
+impl RadioHal for Nrf51Radio<'_> {
+    fn init(&mut self) {
+        self.start_clock();
+
+        self.radio.prefix0.write(|w| unsafe { w.bits(0) });
+        self.radio.txaddress.write(|w| unsafe { w.bits(0) }); // Transmit on logical address 0
+        self.radio.rxaddresses.write(|w| w.addr0().enabled()); // Enable reception on logical address 0 only
+        self.radio.pcnf0.write(|w| unsafe {
+            w.lflen()
+                .bits(8) // 8-bit length field
+                .s0len()
+                .bit(false) // No S0 field
+                .s1len()
+                .bits(0) // No S1 field
+        });
+        self.radio.pcnf1.write(|w| unsafe {
+            w.maxlen()
+                .bits((MAX_PACKET_SIZE - 1) as u8) // Maximum payload
+                .statlen()
+                .bits(0)
+                .balen()
+                .bits(4) // 4-byte base address length
+                .endian()
+                .little() // Little endian payload
+                .whiteen()
+                .enabled() // Enable packet whitening
+        });
+        self.radio.crccnf.write(|w| w.len().two()); // 16-bit CRC
+        self.radio.crcinit.write(|w| unsafe { w.bits(0xFFFF) }); // CRC initial value
+        self.radio.crcpoly.write(|w| unsafe { w.bits(0x11021) }); // CRC polynomial
+        self.radio.datawhiteiv.write(|w| unsafe { w.bits(0x18) }); // Initial value for the data whitening algorithm
+
+        let packet_ptr = self.packet.as_ptr() as u32;
+        self.radio
+            .packetptr
+            .write(|w| unsafe { w.bits(packet_ptr) });
+
+        // Shortcut READY -> START, and sample the RSSI of each received packet after its address
+        self.radio.shorts.write(|w| {
+            w.ready_start()
+                .enabled()
+                .address_rssistart()
+                .enabled()
+                .disabled_rssistop()
+                .enabled()
+        });
+
+        // The events the radio state machine waits for wake up the radio task, see `RADIO`
+        self.radio
+            .intenset
+            .write(|w| w.address().set().end().set().disabled().set());
+    }
+
+    fn configure(&mut self, settings: &RadioSettings) {
+        // The register takes the power in dBm as a two's complement byte
+        self.radio
+            .txpower
+            .write(|w| unsafe { w.txpower().bits(settings.tx_power as u8) });
+        self.radio
+            .frequency
+            .write(|w| unsafe { w.frequency().bits(settings.channel) }); // 2400 MHz + channel
+        self.radio.mode.write(|w| match settings.data_rate {
+            DataRate::Kbit250 => w.mode().nrf_250kbit(),
+            DataRate::Mbit1 => w.mode().nrf_1mbit(),
+            DataRate::Mbit2 => w.mode().nrf_2mbit(),
+        });
+    }
+
+    fn packet(&mut self) -> &mut [u8] {
+        &mut self.packet
+    }
+
+    fn set_address(&mut self, base: u32) {
+        self.radio.base0.write(|w| unsafe { w.bits(base) });
+    }
+
+    fn rx_enable(&mut self) {
+        self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn tx_enable(&mut self) {
+        self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn start(&mut self) {
+        self.radio.tasks_start.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn disable(&mut self) {
+        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn address_event(&mut self) -> bool {
+        let event = self.radio.events_address.read().bits() != 0;
+        if event {
+            self.radio.events_address.write(|w| unsafe { w.bits(0) });
+        }
+        event
+    }
+
+    fn end_event(&mut self) -> bool {
+        let event = self.radio.events_end.read().bits() != 0;
+        if event {
+            self.radio.events_end.write(|w| unsafe { w.bits(0) });
+        }
+        event
+    }
+
+    fn disabled_event(&mut self) -> bool {
+        let event = self.radio.events_disabled.read().bits() != 0;
+        if event {
+            self.radio.events_disabled.write(|w| unsafe { w.bits(0) });
+        }
+        event
+    }
+
+    fn stop_clock(&mut self) {
+        self.clock.tasks_hfclkstop.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn start_clock(&mut self) {
+        self.clock
+            .events_hfclkstarted
+            .write(|w| unsafe { w.bits(0) });
+        self.clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+        // Takes up to ~1 ms
+        while self.clock.events_hfclkstarted.read().bits() == 0 {}
+    }
+
+    fn crc_ok(&self) -> bool {
+        self.radio.crcstatus.read().crcstatus().is_crcok()
+    }
+
+    fn rssi(&self) -> i8 {
+        // The register holds the magnitude, the RSSI is negative
+        -(self.radio.rssisample.read().rssisample().bits() as i8)
+    }
+}
+
+/// Bytes and errors moved out of the 6-byte RX FIFO by the UART interrupt, for when the serial
+/// task is busy. Holds one less than this, a few milliseconds at 1M baud.
+const RX_RING_SIZE: usize = 256;
+
+/// The interrupt's end of the RX ring
+static RX_PRODUCER: Mutex<RefCell<Option<Producer<'static, Received, RX_RING_SIZE>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// ERRORSRC bits
+const UART_ERRORS: [(u32, UartError); 4] = [
+    (1 << 0, UartError::Overrun),
+    (1 << 1, UartError::Parity),
+    (1 << 2, UartError::Framing),
+    (1 << 3, UartError::Break),
+];
+
+#[interrupt]
+fn UART0() {
+    // The interrupt only touches RXDRDY, ERROR, RXD, ERRORSRC and INTENCLR, which the serial task
+    // leaves to it
+    let uart0 = unsafe { &*UART0::ptr() };
+    // TXDRDY is left for the serial task, the interrupt only wakes it up once per byte
+    if uart0.events_txdrdy.read().bits() != 0 {
+        uart0.intenclr.write(|w| w.txdrdy().clear());
+    }
+    cortex_m::interrupt::free(|cs| {
+        let mut producer = RX_PRODUCER.borrow(cs).borrow_mut();
+        let producer = match producer.as_mut() {
+            Some(producer) => producer,
+            None => return,
+        };
+        loop {
+            let error = uart0.events_error.read().bits() != 0;
+            if !error && uart0.events_rxdrdy.read().bits() == 0 {
+                return;
+            }
+            if producer.len() + UART_ERRORS.len() >= producer.capacity() {
+                // Leave the bytes in the FIFO until the serial task makes room
+                uart0.intenclr.write(|w| w.rxdrdy().clear().error().clear());
+                return;
+            }
+            // Errors first, so that a break comes before the bytes received after it
+            if error {
+                uart0.events_error.write(|w| unsafe { w.bits(0) });
+                let errorsrc = uart0.errorsrc.read().bits();
+                // Cleared by writing ones
+                uart0.errorsrc.write(|w| unsafe { w.bits(errorsrc) });
+                for (bit, error) in UART_ERRORS {
+                    if errorsrc & bit != 0 {
+                        producer.enqueue(Received::Error(error)).ok();
+                    }
+                }
+            } else {
+                uart0.events_rxdrdy.write(|w| unsafe { w.bits(0) });
+                let byte = uart0.rxd.read().bits() as u8;
+                producer.enqueue(Received::Byte(byte)).ok();
+            }
+        }
+    });
+    SERIAL_WAKE.wake();
+}
+
+/// The UART, and for autobaud a GPIOTE channel that timestamps the RX line edges with TIMER0
+/// through PPI. Received bytes are read from the ring the UART interrupt fills.
+pub struct Nrf51Uart<'a> {
+    uart0: UART0,
+    rx: Consumer<'static, Received, RX_RING_SIZE>,
+    gpio: &'a GPIO,
+    gpiote: GPIOTE,
+    timer0: TIMER0,
+    ppi: PPI,
+    tx_pin: u32,
+    rx_pin: u32,
+    rts_cts_pins: Option<(u32, u32)>,
+}
+
+impl<'a> Nrf51Uart<'a> {
+    /// Can only be called once, as there is only one RX ring
+    pub fn new(uart0: UART0, gpio: &'a GPIO, gpiote: GPIOTE, timer0: TIMER0, ppi: PPI) -> Self {
+        let ring = cortex_m::singleton!(: Queue<Received, RX_RING_SIZE> = Queue::new()).unwrap();
+        let (producer, rx) = ring.split();
+        cortex_m::interrupt::free(|cs| RX_PRODUCER.borrow(cs).replace(Some(producer)));
+        Self {
+            uart0,
+            rx,
+            gpio,
+            gpiote,
+            timer0,
+            ppi,
+            tx_pin: 0,
+            rx_pin: 0,
+            rts_cts_pins: None,
+        }
+    }
+
+    fn stop_edge_capture(&mut self) {
+        self.gpiote.intenclr.write(|w| w.in0().clear());
+        self.ppi.chenclr.write(|w| w.ch0().clear());
+        self.gpiote.config[0].write(|w| w.mode().disabled());
+        self.timer0.tasks_stop.write(|w| unsafe { w.bits(1) });
+    }
+}
+
+fn baud_rate(baud_rate: u32) -> BAUDRATE_A {
+    match baud_rate {
+        1200 => BAUDRATE_A::BAUD1200,
+        2400 => BAUDRATE_A::BAUD2400,
+        4800 => BAUDRATE_A::BAUD4800,
+        9600 => BAUDRATE_A::BAUD9600,
+        14400 => BAUDRATE_A::BAUD14400,
+        19200 => BAUDRATE_A::BAUD19200,
+        28800 => BAUDRATE_A::BAUD28800,
+        31250 => BAUDRATE_A::BAUD31250,
+        56000 => BAUDRATE_A::BAUD56000,
+        57600 => BAUDRATE_A::BAUD57600,
+        76800 => BAUDRATE_A::BAUD76800,
+        115200 => BAUDRATE_A::BAUD115200,
+        230400 => BAUDRATE_A::BAUD230400,
+        250000 => BAUDRATE_A::BAUD250000,
+        460800 => BAUDRATE_A::BAUD460800,
+        921600 => BAUDRATE_A::BAUD921600,
+        1_000_000 => BAUDRATE_A::BAUD1M,
+        _ => BAUDRATE_A::BAUD38400,
+    }
+}
+
+impl UartHal for Nrf51Uart<'_> {
+    fn init(&mut self, settings: &UartSettings, rts_cts: bool) {
+        // The pins and the baud rate can only be changed while the UART is disabled
+        self.uart0
+            .intenclr
+            .write(|w| w.rxdrdy().clear().error().clear().txdrdy().clear());
+        self.uart0.enable.write(|w| w.enable().disabled());
+        self.stop_edge_capture();
+
+        let tx_pin = settings.tx_pin as u32;
+        let rx_pin = settings.rx_pin as u32;
+        self.tx_pin = tx_pin;
+        self.rx_pin = rx_pin;
+        self.gpio.pin_cnf[tx_pin as usize].write(|w| w.pull().pullup().dir().output());
+        self.gpio.pin_cnf[rx_pin as usize].write(|w| w.pull().disabled().dir().input());
+
+        self.rts_cts_pins = if rts_cts {
+            Some((settings.rts_pin as u32, settings.cts_pin as u32))
+        } else {
+            None
+        };
+
+        // RTS and CTS are driven by software instead of the UART's own HWFC, which would only
+        // look at its 6-byte RX FIFO instead of the queue fill level
+        if let Some((rts_pin, cts_pin)) = self.rts_cts_pins {
+            let dcd_pin = DCD_PIN as u32;
+            self.gpio
+                .outset
+                .write(|w| unsafe { w.bits(1 << rts_pin | 1 << dcd_pin) }); // Deasserted
+            self.gpio.pin_cnf[rts_pin as usize].write(|w| w.dir().output());
+            self.gpio.pin_cnf[dcd_pin as usize].write(|w| w.dir().output());
+            // Pulled up so that an unconnected CTS means "not ready"
+            self.gpio.pin_cnf[cts_pin as usize].write(|w| w.pull().pullup().dir().input());
+        }
+
+        self.uart0.pseltxd.write(|w| unsafe { w.bits(tx_pin) });
+        self.uart0.pselrxd.write(|w| unsafe { w.bits(rx_pin) });
+        self.uart0
+            .baudrate
+            .write(|w| w.baudrate().variant(baud_rate(settings.baud_rate)));
+        self.uart0.config.write(|w| match settings.parity {
+            Parity::None => w.parity().excluded(),
+            Parity::Even => w.parity().included(),
+        });
+        self.uart0.enable.write(|w| w.enable().enabled());
+
+        self.uart0.tasks_startrx.write(|w| unsafe { w.bits(1) });
+        self.uart0.tasks_starttx.write(|w| unsafe { w.bits(1) });
+
+        self.uart0
+            .intenset
+            .write(|w| w.rxdrdy().set().error().set());
+        unsafe { NVIC::unmask(Interrupt::UART0) };
+    }
+
+    fn tx_ready(&self) -> bool {
+        self.uart0.events_txdrdy.read().bits() != 0
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.uart0.events_txdrdy.write(|w| unsafe { w.bits(0) });
+        self.uart0.txd.write(|w| unsafe { w.txd().bits(byte) });
+        // Wakes up the serial task when the byte has been sent, see `UART0`
+        self.uart0.intenset.write(|w| w.txdrdy().set());
+    }
+
+    fn read(&mut self) -> Option<Received> {
+        let received = self.rx.dequeue();
+        if received.is_some() && self.uart0.enable.read().enable().is_enabled() {
+            // There's room again if the interrupt stopped on a full ring
+            self.uart0
+                .intenset
+                .write(|w| w.rxdrdy().set().error().set());
+        }
+        received
+    }
+
+    fn set_break(&mut self, on: bool) {
+        // The pin is driven from the GPIO while it is disconnected from the UART
+        if on {
+            self.uart0.tasks_stoptx.write(|w| unsafe { w.bits(1) });
+            self.gpio
+                .outclr
+                .write(|w| unsafe { w.bits(1 << self.tx_pin) });
+            self.uart0.pseltxd.write(|w| unsafe { w.bits(0xffff_ffff) });
+        } else {
+            self.gpio
+                .outset
+                .write(|w| unsafe { w.bits(1 << self.tx_pin) });
+            self.uart0.pseltxd.write(|w| unsafe { w.bits(self.tx_pin) });
+            self.uart0.tasks_starttx.write(|w| unsafe { w.bits(1) });
+        }
+    }
+
+    fn start_edge_capture(&mut self) {
+        self.uart0
+            .intenclr
+            .write(|w| w.rxdrdy().clear().error().clear());
+        self.uart0.enable.write(|w| w.enable().disabled());
+
+        self.timer0.mode.write(|w| w.mode().timer());
+        self.timer0.bitmode.write(|w| w.bitmode()._32bit());
+        self.timer0
+            .prescaler
+            .write(|w| unsafe { w.prescaler().bits(0) }); // 16 MHz
+        self.timer0.tasks_clear.write(|w| unsafe { w.bits(1) });
+        self.timer0.tasks_start.write(|w| unsafe { w.bits(1) });
+
+        self.gpiote.events_in[0].write(|w| unsafe { w.bits(0) });
+        self.gpiote.config[0].write(|w| unsafe {
+            w.mode()
+                .event()
+                .psel()
+                .bits(self.rx_pin as u8)
+                .polarity()
+                .toggle()
+        });
+
+        // Capture the timer in hardware, so that the timestamps don't depend on how often the
+        // serial task gets around to reading them
+        let event = &self.gpiote.events_in[0] as *const _ as u32;
+        let task = &self.timer0.tasks_capture[0] as *const _ as u32;
+        self.ppi.ch[0].eep.write(|w| unsafe { w.bits(event) });
+        self.ppi.ch[0].tep.write(|w| unsafe { w.bits(task) });
+        self.ppi.chenset.write(|w| w.ch0().set());
+        // Each edge wakes up the serial task to read the capture before the next one, see `GPIOTE`
+        self.gpiote.intenset.write(|w| w.in0().set());
+    }
+
+    fn edge(&mut self) -> Option<u32> {
+        if self.gpiote.events_in[0].read().bits() != 0 {
+            self.gpiote.events_in[0].write(|w| unsafe { w.bits(0) });
+            Some(self.timer0.cc[0].read().bits())
+        } else {
+            None
+        }
+    }
+
+    fn set_rts(&mut self, ready: bool) {
+        // Active low
+        if let Some((rts_pin, _)) = self.rts_cts_pins {
+            if ready {
+                self.gpio.outclr.write(|w| unsafe { w.bits(1 << rts_pin) });
+            } else {
+                self.gpio.outset.write(|w| unsafe { w.bits(1 << rts_pin) });
+            }
+        }
+    }
+
+    fn cts(&self) -> bool {
+        // Active low
+        match self.rts_cts_pins {
+            Some((_, cts_pin)) => self.gpio.in_.read().bits() & (1 << cts_pin) == 0,
+            None => true,
+        }
+    }
+
+    fn set_dcd(&mut self, connected: bool) {
+        // Active low, like RTS
+        if self.rts_cts_pins.is_some() {
+            if connected {
+                self.gpio.outclr.write(|w| unsafe { w.bits(1 << DCD_PIN) });
+            } else {
+                self.gpio.outset.write(|w| unsafe { w.bits(1 << DCD_PIN) });
+            }
+        }
+    }
+}
+
+pub struct Nrf51Rtc<'a> {
+    rtc0: RTC0,
+    clock: &'a CLOCK,
+}
+
+impl<'a> Nrf51Rtc<'a> {
+    pub fn new(rtc0: RTC0, clock: &'a CLOCK) -> Self {
+        Self { rtc0, clock }
+    }
+}
+
+impl RtcHal for Nrf51Rtc<'_> {
+    fn init(&mut self) {
+        self.clock.tasks_lfclkstart.write(|w| unsafe { w.bits(1) });
+
+        // ~1 ms per tick
+        self.rtc0.prescaler.write(|w| unsafe { w.bits(32) });
+        self.wake_at(None);
+        self.rtc0.tasks_start.write(|w| unsafe { w.bits(1) });
+    }
+
+    fn wake_event(&mut self) -> bool {
+        let tick = self.rtc0.events_tick.read().bits() != 0;
+        let compare = self.rtc0.events_compare[0].read().bits() != 0;
+        if tick {
+            self.rtc0.events_tick.write(|w| unsafe { w.bits(0) });
+        }
+        if compare {
+            self.rtc0.events_compare[0].write(|w| unsafe { w.bits(0) });
+        }
+        tick || compare
+    }
+
+    // Either event wakes up the timer task, see `RTC0`. The TICK event is turned off while it
+    // isn't needed, which saves power.
+    fn wake_at(&mut self, counter: Option<u32>) {
+        match counter {
+            Some(counter) => {
+                self.rtc0.cc[0].write(|w| unsafe { w.bits(counter) });
+                self.rtc0.intenclr.write(|w| w.tick().clear());
+                self.rtc0.evtenclr.write(|w| w.tick().clear());
+                self.rtc0.evtenset.write(|w| w.compare0().set());
+                self.rtc0.intenset.write(|w| w.compare0().set());
+            }
+            None => {
+                self.rtc0.intenclr.write(|w| w.compare0().clear());
+                self.rtc0.evtenclr.write(|w| w.compare0().clear());
+                self.rtc0.evtenset.write(|w| w.tick().set());
+                self.rtc0.intenset.write(|w| w.tick().set());
+            }
+        }
+    }
+
+    fn counter(&self) -> u32 {
+        self.rtc0.counter.read().bits()
+    }
+}
+
+pub struct Nrf51Aes {
+    ecb: ECB,
+    /// Key, cleartext and ciphertext, read and written by the ECB peripheral
+    data: [u8; 48],
+}
+
+impl Nrf51Aes {
+    pub fn new(ecb: ECB) -> Self {
+        Self { ecb, data: [0; 48] }
+    }
+}
+
+impl AesHal for Nrf51Aes {
+    fn encrypt(&mut self, key: &[u8; 16], block: &mut [u8; 16]) {
+        self.data[..16].copy_from_slice(key);
+        self.data[16..32].copy_from_slice(block);
+
+        // Set the pointer every time, self may have moved since the last call
+        let data_ptr = self.data.as_ptr() as u32;
+        self.ecb.ecbdataptr.write(|w| unsafe { w.bits(data_ptr) });
+        loop {
+            self.ecb.events_endecb.write(|w| unsafe { w.bits(0) });
+            self.ecb.events_errorecb.write(|w| unsafe { w.bits(0) });
+            self.ecb.tasks_startecb.write(|w| unsafe { w.bits(1) });
+            // Takes ~7 µs
+            while self.ecb.events_endecb.read().bits() == 0
+                && self.ecb.events_errorecb.read().bits() == 0
+            {}
+            // ERRORECB means that another crypto peripheral took over, try again
+            if self.ecb.events_endecb.read().bits() != 0 {
+                break;
+            }
+        }
+
+        block.copy_from_slice(&self.data[32..]);
+    }
+}
+
+pub struct Nrf51Rng {
+    rng: RNG,
+}
+
+impl Nrf51Rng {
+    pub fn new(rng: RNG) -> Self {
+        Self { rng }
+    }
+}
+
+impl RngHal for Nrf51Rng {
+    fn init(&mut self) {
+        // Bias correction, slower but uniform
+        self.rng.config.write(|w| w.dercen().enabled());
+    }
+
+    fn random(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.rng.tasks_start.write(|w| unsafe { w.bits(1) });
+        for byte in bytes.iter_mut() {
+            while self.rng.events_valrdy.read().bits() == 0 {}
+            self.rng.events_valrdy.write(|w| unsafe { w.bits(0) });
+            *byte = self.rng.value.read().bits() as u8;
+        }
+        self.rng.tasks_stop.write(|w| unsafe { w.bits(1) });
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// The last page of the 256 kB flash. The linker script only uses the first 128 kB, so this is
+/// never overwritten by the firmware.
+const STORAGE_PAGE: usize = 0x3fc00;
+const PAGE_SIZE: usize = 1024;
+
+pub struct Nrf51Flash {
+    nvmc: NVMC,
+}
+
+impl Nrf51Flash {
+    pub fn new(nvmc: NVMC) -> Self {
+        Self { nvmc }
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmc.ready.read().ready().is_busy() {}
+    }
+}
+
+impl FlashHal for Nrf51Flash {
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        assert!(offset + data.len() <= PAGE_SIZE);
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = unsafe { core::ptr::read_volatile((STORAGE_PAGE + offset + i) as *const u8) };
+        }
+    }
+
+    fn erase(&mut self) {
+        self.nvmc.config.write(|w| w.wen().een());
+        self.wait_ready();
+        self.nvmc
+            .erasepage()
+            .write(|w| unsafe { w.bits(STORAGE_PAGE as u32) });
+        self.wait_ready();
+        self.nvmc.config.write(|w| w.wen().ren());
+    }
+
+    fn write_word(&mut self, offset: usize, word: u32) {
+        assert!(offset.is_multiple_of(4) && offset + 4 <= PAGE_SIZE);
+        self.nvmc.config.write(|w| w.wen().wen());
+        self.wait_ready();
+        unsafe { core::ptr::write_volatile((STORAGE_PAGE + offset) as *mut u32, word) };
+        self.wait_ready();
+        self.nvmc.config.write(|w| w.wen().ren());
+    }
+}
+
+/// Wakes up a task when one of its interrupts fires. The peripheral events stay set until the
+/// task clears them, so the handlers mask the interrupt in the NVIC, and [`Wake::wait`] unmasks it
+/// again. An event that the task left set, or that has been set since, then fires right away.
+pub struct Wake {
+    signal: Signal<CriticalSectionRawMutex, ()>,
+    interrupts: &'static [Interrupt],
+}
+
+impl Wake {
+    const fn new(interrupts: &'static [Interrupt]) -> Self {
+        Self {
+            signal: Signal::new(),
+            interrupts,
+        }
+    }
+
+    /// Wake up the task from one of its interrupt handlers
+    fn interrupt(&self, interrupt: Interrupt) {
+        NVIC::mask(interrupt);
+        self.signal.signal(());
+    }
+
+    /// Wake up the task from another task, e.g. when there are bytes for it in a queue
+    pub fn wake(&self) {
+        self.signal.signal(());
+    }
+
+    /// Wait for an interrupt or [`Wake::wake`]. Returns right away if one has happened since the
+    /// last wait.
+    pub async fn wait(&self) {
+        for &interrupt in self.interrupts {
+            unsafe { NVIC::unmask(interrupt) };
+        }
+        self.signal.wait().await;
+    }
+}
+
+/// The radio task, on the ADDRESS, END and DISABLED events
+pub static RADIO_WAKE: Wake = Wake::new(&[Interrupt::RADIO]);
+
+/// The serial task, on a byte received or sent and on an autobaud edge
+pub static SERIAL_WAKE: Wake = Wake::new(&[Interrupt::UART0, Interrupt::GPIOTE]);
+
+/// The timer task, on the RTC tick or compare
+pub static TIMER_WAKE: Wake = Wake::new(&[Interrupt::RTC0]);
+
+#[interrupt]
+fn RADIO() {
+    RADIO_WAKE.interrupt(Interrupt::RADIO);
+}
+
+#[interrupt]
+fn GPIOTE() {
+    SERIAL_WAKE.interrupt(Interrupt::GPIOTE);
+}
+
+#[interrupt]
+fn RTC0() {
+    TIMER_WAKE.interrupt(Interrupt::RTC0);
+}
+
+/// Is the button on the given pin held down? The micro:bit buttons are active low with external
+/// pull-ups.
+pub fn button_pressed(gpio: &GPIO, pin: u32) -> bool {
+    gpio.pin_cnf[pin as usize].write(|w| w.dir().input().input().connect());
+    gpio.in_.read().bits() & (1 << pin) == 0
+}
@@ -0,0 +1,437 @@
+//! Radio packets before encryption, and their wire format.
+//!
+//! Every packet starts with a header of its length, including the length byte, a type byte, the
+//! version of the format and flags. If [`FLAG_EXTENSIONS`] is set, extensions follow the header,
+//! and then the body:
+//!
+//! ```text
+//! [len, type, version, flags, (extensions length, (tag, length, value..)..)?, body..]
+//!
+//! 'A': [session (4), next (2), sack (2), limit (2)]
+//! 'D': [session (4), next (2), sack (2), limit (2), id (2), data..]
+//! 'P': [id (4), heard (4)]
+//! 'W': [window, interval (2)]
+//! 'H': [session (4), heard (4), up, version]
+//! 'R': [session (4), rate, reply]
+//! ```
+//!
+//! Acks, data and rate changes are tagged with the session id of the link, so that packets from an
+//! earlier session are never taken for new ones. Multi-byte fields are little-endian. Data rates
+//! are 0 for 250 kbit, 1 for 1 Mbit and 2 for 2 Mbit. Rate changes are new in version 2.
+//!
+//! Newer versions of the format may add flags, extensions and fields at the end of a body, but
+//! keep the header. Unknown flags and extensions are ignored. Hellos, offers and wake-ups are
+//! always sent as [`MIN_VERSION`], so that any two versions can set up a link, and each hello
+//! tells the highest version the sender knows. Acks, data and rate changes are sent as the highest
+//! version both ends know, and packets of a version this end doesn't know are rejected.
+//!
+//! Frames come off the air, so [`Packet::read`] checks every length before touching the bytes
+//! and never panics. A CRC-valid frame can still be corrupt or forged when encryption is off.
+
+use defmt::{write, Format, Formatter};
+
+use crate::crypto;
+use crate::hal::{DataRate, MAX_PACKET_SIZE};
+
+/// Highest version of the format this firmware knows
+pub const VERSION: u8 = 2;
+/// Lowest version of the format this firmware knows
+pub const MIN_VERSION: u8 = 1;
+/// First version of the format with rate changes
+pub const RATE_CHANGE_VERSION: u8 = 2;
+
+/// Size of the largest packet, which fills the radio packet buffer when encrypted
+pub const MAX_PLAINTEXT_SIZE: usize = MAX_PACKET_SIZE - crypto::OVERHEAD;
+/// Length, type, version and flags
+const HEADER_SIZE: usize = 4;
+/// Session id and ack
+const ACK_BODY_SIZE: usize = 4 + Ack::SIZE;
+/// The ack body and packet id
+const DATA_BODY_SIZE: usize = ACK_BODY_SIZE + 2;
+/// Most data bytes in a packet
+pub const MAX_DATA_SIZE: usize = MAX_PLAINTEXT_SIZE - HEADER_SIZE - DATA_BODY_SIZE;
+
+/// The sender's host sent a break after the data
+pub const FLAG_BREAK: u8 = 0x01;
+/// Bytes were lost or corrupted on the sender's serial line before the data
+pub const FLAG_LINE_ERROR: u8 = 0x02;
+/// Extensions follow the header
+pub const FLAG_EXTENSIONS: u8 = 0x80;
+/// Flags that belong to the data of a packet
+const DATA_FLAGS: u8 = FLAG_BREAK | FLAG_LINE_ERROR;
+
+/// Why a frame isn't a valid packet
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Error {
+    /// The header is incomplete
+    TooShort,
+    /// The buffer is shorter than the length byte says
+    Truncated,
+    /// A version of the format this end doesn't know
+    UnsupportedVersion(u8),
+    /// The extensions don't fit in their length, or the packet
+    BadExtensions,
+    /// Not one of the packet types
+    UnknownType(u8),
+    /// The length doesn't fit the packet type
+    BadLength(u8),
+    /// Not one of the data rates
+    UnknownRate(u8),
+}
+
+/// Acknowledgement of received packets
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+    /// All packets before this id have been received
+    pub next: u16,
+    /// Selective acks: bit n is set if packet `next + 1 + n` has been received
+    pub sack: u16,
+    /// Credit: packets before this id fit in the receive queue even if they are full-sized
+    pub limit: u16,
+}
+
+impl Ack {
+    const SIZE: usize = 6;
+
+    fn read(source: &[u8]) -> Self {
+        Self {
+            next: u16::from_le_bytes([source[0], source[1]]),
+            sack: u16::from_le_bytes([source[2], source[3]]),
+            limit: u16::from_le_bytes([source[4], source[5]]),
+        }
+    }
+
+    fn write(&self, target: &mut [u8]) {
+        target[0..2].copy_from_slice(&self.next.to_le_bytes());
+        target[2..4].copy_from_slice(&self.sack.to_le_bytes());
+        target[4..6].copy_from_slice(&self.limit.to_le_bytes());
+    }
+}
+
+/// Data from the host, with the id it is acked by
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PacketData {
+    pub id: u16,
+    /// [`FLAG_BREAK`] and [`FLAG_LINE_ERROR`], sent in the header
+    pub flags: u8,
+    data_len: u8,
+    /// Zero after `data_len`, so that equal packets compare equal
+    data: [u8; MAX_DATA_SIZE],
+}
+
+impl PacketData {
+    /// Takes up to [`MAX_DATA_SIZE`] bytes of `data`
+    pub fn new(id: u16, flags: u8, data: &[u8]) -> Self {
+        let len = data.len().min(MAX_DATA_SIZE);
+        let mut buffer = [0; MAX_DATA_SIZE];
+        buffer[..len].copy_from_slice(&data[..len]);
+        Self {
+            id,
+            flags,
+            data_len: len as u8,
+            data: buffer,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.data_len as usize]
+    }
+}
+
+/// Sent while pairing, see [`crate::pairing`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Offer {
+    /// Random id of the sender
+    pub id: u32,
+    /// Id heard from the peer, or 0 if none yet
+    pub heard: u32,
+}
+
+impl Offer {
+    const SIZE: usize = 8;
+
+    fn read(source: &[u8]) -> Self {
+        Self {
+            id: u32::from_le_bytes([source[0], source[1], source[2], source[3]]),
+            heard: u32::from_le_bytes([source[4], source[5], source[6], source[7]]),
+        }
+    }
+
+    fn write(&self, target: &mut [u8]) {
+        target[..4].copy_from_slice(&self.id.to_le_bytes());
+        target[4..8].copy_from_slice(&self.heard.to_le_bytes());
+    }
+}
+
+/// Sent by a node with duty-cycled listening each time it wakes up
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Wake {
+    /// The node listens for this long after each packet, in ms
+    pub window: u8,
+    /// Time between wake-ups, in ms
+    pub interval: u16,
+}
+
+impl Wake {
+    const SIZE: usize = 3;
+
+    fn read(source: &[u8]) -> Self {
+        Self {
+            window: source[0],
+            interval: u16::from_le_bytes([source[1], source[2]]),
+        }
+    }
+
+    fn write(&self, target: &mut [u8]) {
+        target[0] = self.window;
+        target[1..3].copy_from_slice(&self.interval.to_le_bytes());
+    }
+}
+
+/// Sent while setting up the link and as a keepalive, see [`crate::link`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    /// Session id of the sender
+    pub session: u32,
+    /// Session id heard from the peer, or 0 if none yet
+    pub heard: u32,
+    /// The sender's link is up, so it doesn't need a hello back. Keepalives have this set.
+    pub up: bool,
+    /// Highest version of the format the sender knows
+    pub version: u8,
+}
+
+impl Hello {
+    const SIZE: usize = 10;
+
+    fn read(source: &[u8]) -> Self {
+        Self {
+            session: u32::from_le_bytes([source[0], source[1], source[2], source[3]]),
+            heard: u32::from_le_bytes([source[4], source[5], source[6], source[7]]),
+            up: source[8] != 0,
+            version: source[9],
+        }
+    }
+
+    fn write(&self, target: &mut [u8]) {
+        target[..4].copy_from_slice(&self.session.to_le_bytes());
+        target[4..8].copy_from_slice(&self.heard.to_le_bytes());
+        target[8] = self.up as u8;
+        target[9] = self.version;
+    }
+}
+
+/// Asks the peer to switch to another data rate, or answers such a request, see [`crate::rate`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RateChange {
+    pub rate: DataRate,
+    /// This is an answer: both ends use `rate` from now on
+    pub reply: bool,
+}
+
+impl RateChange {
+    const SIZE: usize = 2;
+
+    fn read(source: &[u8]) -> Result<Self, Error> {
+        let rate = match source[0] {
+            0 => DataRate::Kbit250,
+            1 => DataRate::Mbit1,
+            2 => DataRate::Mbit2,
+            rate => return Err(Error::UnknownRate(rate)),
+        };
+        Ok(Self {
+            rate,
+            reply: source[1] != 0,
+        })
+    }
+
+    fn write(&self, target: &mut [u8]) {
+        target[0] = match self.rate {
+            DataRate::Kbit250 => 0,
+            DataRate::Mbit1 => 1,
+            DataRate::Mbit2 => 2,
+        };
+        target[1] = self.reply as u8;
+    }
+}
+
+// There's no heap to box the data in, and only a packet or two exists at a time
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Packet {
+    /// Acks only, with the session id
+    Ack(u32, Ack),
+    /// Data with a piggybacked ack, with the session id
+    Data(u32, Ack, PacketData),
+    Pair(Offer),
+    Wake(Wake),
+    Hello(Hello),
+    /// A data rate change, with the session id
+    Rate(u32, RateChange),
+}
+
+impl Packet {
+    /// Parse a packet from the start of `source`. Bytes after the length are ignored.
+    pub fn read(source: &[u8]) -> Result<Self, Error> {
+        let len = *source.first().ok_or(Error::TooShort)? as usize;
+        if len < HEADER_SIZE {
+            return Err(Error::TooShort);
+        }
+        let packet = source.get(..len).ok_or(Error::Truncated)?;
+        let (kind, version, flags) = (packet[1], packet[2], packet[3]);
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let mut body = &packet[HEADER_SIZE..];
+        if flags & FLAG_EXTENSIONS != 0 {
+            body = skip_extensions(body)?;
+        }
+
+        let expected = match kind {
+            b'A' => ACK_BODY_SIZE,
+            b'D' => DATA_BODY_SIZE,
+            b'P' => Offer::SIZE,
+            b'W' => Wake::SIZE,
+            b'H' => Hello::SIZE,
+            b'R' if version >= RATE_CHANGE_VERSION => 4 + RateChange::SIZE,
+            _ => return Err(Error::UnknownType(kind)),
+        };
+        let fits = match kind {
+            b'D' => (expected..=expected + MAX_DATA_SIZE).contains(&body.len()),
+            _ => body.len() == expected,
+        };
+        if !fits {
+            return Err(Error::BadLength(kind));
+        }
+
+        let session = || u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+        Ok(match kind {
+            b'A' => Self::Ack(session(), Ack::read(&body[4..])),
+            b'D' => Self::Data(
+                session(),
+                Ack::read(&body[4..]),
+                PacketData::new(
+                    u16::from_le_bytes([body[10], body[11]]),
+                    flags & DATA_FLAGS,
+                    &body[DATA_BODY_SIZE..],
+                ),
+            ),
+            b'P' => Self::Pair(Offer::read(body)),
+            b'W' => Self::Wake(Wake::read(body)),
+            b'R' => Self::Rate(session(), RateChange::read(&body[4..])?),
+            _ => Self::Hello(Hello::read(body)),
+        })
+    }
+
+    /// Length of the packet, including the length byte
+    pub fn size(&self) -> usize {
+        HEADER_SIZE
+            + match self {
+                Packet::Ack(..) => ACK_BODY_SIZE,
+                Packet::Data(_, _, packet_data) => DATA_BODY_SIZE + packet_data.data_len as usize,
+                Packet::Pair(_) => Offer::SIZE,
+                Packet::Wake(_) => Wake::SIZE,
+                Packet::Hello(_) => Hello::SIZE,
+                Packet::Rate(..) => 4 + RateChange::SIZE,
+            }
+    }
+
+    /// Acks, data and rate changes are written as `version`, which both ends have to know, and
+    /// rate changes need [`RATE_CHANGE_VERSION`]. `target` must have room for [`Packet::size`]
+    /// bytes.
+    pub fn write(&self, version: u8, target: &mut [u8]) {
+        let (header, body) = target.split_at_mut(HEADER_SIZE);
+        header[0] = self.size() as u8;
+        header[1] = match self {
+            Packet::Ack(..) => b'A',
+            Packet::Data(..) => b'D',
+            Packet::Pair(_) => b'P',
+            Packet::Wake(_) => b'W',
+            Packet::Hello(_) => b'H',
+            Packet::Rate(..) => b'R',
+        };
+        header[2] = match self {
+            Packet::Ack(..) | Packet::Data(..) | Packet::Rate(..) => version,
+            _ => MIN_VERSION,
+        };
+        header[3] = match self {
+            Packet::Data(_, _, packet_data) => packet_data.flags & DATA_FLAGS,
+            _ => 0,
+        };
+        match self {
+            Packet::Ack(session, ack) => {
+                body[..4].copy_from_slice(&session.to_le_bytes());
+                ack.write(&mut body[4..]);
+            }
+            Packet::Data(session, ack, packet_data) => {
+                let data = packet_data.data();
+                body[..4].copy_from_slice(&session.to_le_bytes());
+                ack.write(&mut body[4..]);
+                body[10..12].copy_from_slice(&packet_data.id.to_le_bytes());
+                body[DATA_BODY_SIZE..][..data.len()].copy_from_slice(data);
+            }
+            Packet::Pair(offer) => offer.write(body),
+            Packet::Wake(wake) => wake.write(body),
+            Packet::Hello(hello) => hello.write(body),
+            Packet::Rate(session, rate_change) => {
+                body[..4].copy_from_slice(&session.to_le_bytes());
+                rate_change.write(&mut body[4..]);
+            }
+        }
+    }
+}
+
+/// Check that the extensions at the start of `source` are well-formed, and return what follows
+/// them. None are known yet.
+fn skip_extensions(source: &[u8]) -> Result<&[u8], Error> {
+    let (&len, rest) = source.split_first().ok_or(Error::BadExtensions)?;
+    let (mut extensions, body) = rest
+        .split_at_checked(len as usize)
+        .ok_or(Error::BadExtensions)?;
+    while let [_tag, len, rest @ ..] = extensions {
+        extensions = rest.get(*len as usize..).ok_or(Error::BadExtensions)?;
+    }
+    if !extensions.is_empty() {
+        // A tag without a length
+        return Err(Error::BadExtensions);
+    }
+    Ok(body)
+}
+
+impl Format for Packet {
+    fn format(&self, f: Formatter) {
+        match self {
+            Packet::Ack(session, Ack { next, sack, limit }) => write!(
+                f,
+                "A session={=u32:x} ack={=u16} sack={=u16:b} limit={=u16}",
+                session, next, sack, limit
+            ),
+            Packet::Data(session, Ack { next, sack, limit }, packet_data) => write!(
+                f,
+                "D session={=u32:x} ack={=u16} sack={=u16:b} limit={=u16} id={=u16} data_len={=u8}",
+                session, next, sack, limit, packet_data.id, packet_data.data_len
+            ),
+            Packet::Pair(Offer { id, heard }) => {
+                write!(f, "P id={=u32:x} heard={=u32:x}", id, heard)
+            }
+            Packet::Wake(Wake { window, interval }) => {
+                write!(f, "W window={=u8} interval={=u16}", window, interval)
+            }
+            Packet::Hello(Hello {
+                session,
+                heard,
+                up,
+                version,
+            }) => write!(
+                f,
+                "H session={=u32:x} heard={=u32:x} up={=bool} version={=u8}",
+                session, heard, up, version
+            ),
+            Packet::Rate(session, RateChange { rate, reply }) => write!(
+                f,
+                "R session={=u32:x} rate={} reply={=bool}",
+                session, rate, reply
+            ),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Pairing two nodes to a link of their own.
+//!
+//! Nodes in pairing mode use the default address, and send offers with a random id and the id
+//! they have heard from the peer. A node that sees its own id echoed back knows both ids, and
+//! the link id is their XOR. It keeps sending offers for a while after that, so that the peer
+//! sees its id echoed back too. A link id that makes a poor radio address is replaced by another
+//! one derived from it, the same on both ends.
+
+use defmt::{debug, Format};
+
+use crate::packet::Offer;
+use crate::rtc::Deadline;
+
+/// Base address of unpaired nodes and of pairing: "uBit", like the micro:bit runtime
+pub const DEFAULT_ADDRESS: u32 = 0x7562_6974;
+
+/// Time between offers, plus up to the same amount of random jitter
+pub const OFFER_INTERVAL: u32 = 20;
+
+/// Keep sending offers this long after the peer has heard ours
+const LINGER_TIME: u64 = 500;
+
+/// Give up if pairing hasn't finished in this time
+const TIMEOUT: u64 = 30_000;
+
+/// Longest run of equal bits in a link id. Longer runs leave the receiver too few edges to keep
+/// its bit timing, and noise matches them more easily.
+const MAX_RUN: u32 = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Status {
+    Pairing,
+    Paired(u32),
+    Failed,
+}
+
+pub struct Pairing {
+    started: u64,
+    own: u32,
+    peer: Option<u32>,
+    /// When we first saw the peer echo our id
+    confirmed: Option<u64>,
+    next_offer: Deadline,
+}
+
+impl Pairing {
+    /// Start pairing with a random id
+    pub fn new(now: u64, random: u32) -> Self {
+        debug!("pairing - started with id {=u32:x}", random);
+        Self {
+            started: now,
+            // 0 means "nothing heard" in offers
+            own: random.max(1),
+            peer: None,
+            confirmed: None,
+            next_offer: Deadline::after(now, 0),
+        }
+    }
+
+    pub fn offer_due(&self, now: u64) -> bool {
+        self.next_offer.has_passed(now)
+    }
+
+    /// Make the next offer and schedule the one after it
+    pub fn offer(&mut self, now: u64, jitter: u32) -> Offer {
+        self.next_offer = Deadline::after(now, OFFER_INTERVAL + jitter);
+        Offer {
+            id: self.own,
+            heard: self.peer.unwrap_or(0),
+        }
+    }
+
+    pub fn handle_offer(&mut self, now: u64, offer: Offer) {
+        if offer.id == self.own || offer.id == 0 {
+            return;
+        }
+        match self.peer {
+            None => {
+                debug!("pairing - heard peer {=u32:x}", offer.id);
+                self.peer = Some(offer.id);
+            }
+            // Someone else pairing at the same time. Stick with the first one.
+            Some(peer) if peer != offer.id => return,
+            Some(_) => {}
+        }
+        if offer.heard == self.own && self.confirmed.is_none() {
+            debug!("pairing - peer heard us");
+            self.confirmed = Some(now);
+        }
+    }
+
+    pub fn status(&self, now: u64) -> Status {
+        match (self.peer, self.confirmed) {
+            (Some(peer), Some(confirmed)) if now - confirmed >= LINGER_TIME => {
+                let mut link_id = self.own ^ peer;
+                while !usable(link_id) {
+                    // A full-period LCG step, so that a usable id is always reached
+                    link_id = link_id.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                }
+                Status::Paired(link_id)
+            }
+            _ if now - self.started >= TIMEOUT => Status::Failed,
+            _ => Status::Pairing,
+        }
+    }
+}
+
+/// Can the radio use `link_id` as its base address? Not the default address, no long runs of 0s
+/// or 1s, which also rules out 0 and 0xFFFFFFFF, and no 0x55 or 0xAA at either end, which the
+/// receiver can mistake for the end of the preamble.
+fn usable(link_id: u32) -> bool {
+    let long_run = |bits: u32| (1..=MAX_RUN).fold(bits, |run, _| run & run >> 1) != 0;
+    let preamble = |byte: u8| byte == 0x55 || byte == 0xAA;
+    link_id != DEFAULT_ADDRESS
+        && !long_run(link_id)
+        && !long_run(!link_id)
+        && !preamble(link_id as u8)
+        && !preamble((link_id >> 24) as u8)
+}
@@ -1,60 +1,175 @@
-const XON: u8 = 0x11;
-const XOFF: u8 = 0x13;
+use defmt::{debug, Format};
 
 const QUEUE_SIZE: usize = 2048;
 
-pub struct Queue {
-    queue: heapless::spsc::Queue<u8, QUEUE_SIZE>,
+/// Flow control signal for the host, carried out of band next to the data
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Control {
+    Xoff,
+    Xon,
+}
+
+/// What to do when data arrives to a full queue
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Overflow {
+    /// Drop the arriving byte
+    DropNewest,
+    /// Drop the oldest byte in the queue to make room
+    DropOldest,
+    /// Leave the data where it is. The UART stops reading, so the host's bytes are lost in the
+    /// UART FIFO if it keeps sending. The radio stops delivering and acking, so the peer
+    /// retransmits.
+    Block,
+}
+
+/// A byte queue holding up to `N - 1` bytes. The default size is for the queues between the
+/// UART and the radio.
+pub struct Queue<const N: usize = QUEUE_SIZE> {
+    queue: heapless::spsc::Queue<u8, N>,
+    overflow: Overflow,
+
+    /// Number of bytes dropped because the queue was full
+    dropped: u32,
+    /// Are we dropping bytes right now? Used to log once per overflow.
+    overflowing: bool,
 
     /// Flow control requested from the outside
-    control: Option<u8>,
+    control: Option<Control>,
 
     /// Have we requested XOFF?
     xoff_on: bool,
+
+    /// A break on the serial line after this many of the queued bytes
+    brk: Option<usize>,
+    /// Bytes were lost or corrupted on the serial line before the queued ones
+    line_error: bool,
 }
 
-impl Queue {
-    pub fn new() -> Self {
+impl<const N: usize> Queue<N> {
+    pub fn new(overflow: Overflow) -> Self {
         Self {
             queue: heapless::spsc::Queue::new(),
+            overflow,
+            dropped: 0,
+            overflowing: false,
             control: None,
             xoff_on: false,
+            brk: None,
+            line_error: false,
         }
     }
 
+    /// Add a byte, or drop a byte according to the overflow policy if the queue is full. With
+    /// [`Overflow::Block`], the caller should check [`Queue::accepts`] first. A byte that doesn't
+    /// fit anyway is dropped.
     pub fn enqueue(&mut self, byte: u8) {
-        self.queue.enqueue(byte).unwrap();
+        if self.overflow == Overflow::DropOldest && self.free() == 0 && self.dequeue().is_some() {
+            self.drop_byte();
+        }
+        if self.queue.enqueue(byte).is_ok() {
+            self.overflowing = false;
+        } else {
+            self.drop_byte();
+        }
+    }
+
+    fn drop_byte(&mut self) {
+        if !self.overflowing {
+            self.overflowing = true;
+            debug!("queue - full, dropping with {}", self.overflow);
+        }
+        self.dropped = self.dropped.wrapping_add(1);
     }
 
+    /// Should the producer hand over `len` more bytes now?
+    pub fn accepts(&self, len: usize) -> bool {
+        self.overflow != Overflow::Block || self.free() >= len
+    }
+
+    /// Number of bytes dropped because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Bytes after a break are held back until the break has been taken
     pub fn dequeue(&mut self) -> Option<u8> {
-        match self.control {
-            Some(c) => {
-                self.control = None;
-                Some(c)
-            }
-            None => self.queue.dequeue(),
+        if self.brk == Some(0) {
+            return None;
+        }
+        let byte = self.queue.dequeue()?;
+        if let Some(before) = &mut self.brk {
+            *before -= 1;
         }
+        Some(byte)
     }
 
+    /// Add a break after the queued bytes. Only one break is held at a time, so another one before
+    /// the first has been taken is dropped.
+    pub fn enqueue_break(&mut self) {
+        if self.brk.is_none() {
+            self.brk = Some(self.queue.len());
+        }
+    }
+
+    /// Take the break, if it is next in the queue
+    pub fn take_break(&mut self) -> bool {
+        if self.brk == Some(0) {
+            self.brk = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Signal that bytes were lost or corrupted on the serial line
+    pub fn set_line_error(&mut self) {
+        self.line_error = true;
+    }
+
+    /// Take the line error signal, if any
+    pub fn take_line_error(&mut self) -> bool {
+        core::mem::take(&mut self.line_error)
+    }
+
+    /// Number of bytes, not counting a break
     pub fn len(&self) -> usize {
-        (match self.control {
-            Some(_) => 1,
-            None => 0,
-        }) + self.queue.len()
+        self.queue.len()
     }
 
+    /// Are there no bytes and no break?
     pub fn is_empty(&self) -> bool {
-        self.len() == 0
+        self.len() == 0 && self.brk.is_none()
+    }
+
+    /// Number of bytes that can be enqueued
+    pub fn free(&self) -> usize {
+        self.queue.capacity() - self.queue.len()
+    }
+
+    /// Is a flow control signal waiting to be sent?
+    pub fn has_control(&self) -> bool {
+        self.control.is_some()
+    }
+
+    /// Take the pending flow control signal, if any
+    pub fn take_control(&mut self) -> Option<Control> {
+        self.control.take()
     }
 
     /// Request flow control by sending XON/XOFF to the target queue if needed
-    pub fn flow_control(&mut self, target: &mut Queue) {
-        if self.queue.len() > QUEUE_SIZE / 2 && !self.xoff_on {
+    pub fn flow_control<const M: usize>(&mut self, target: &mut Queue<M>) {
+        if self.queue.len() > N / 2 && !self.xoff_on {
             self.xoff_on = true;
-            target.control = Some(XOFF);
-        } else if self.xoff_on && self.queue.len() < QUEUE_SIZE / 3 {
+            target.control = Some(Control::Xoff);
+        } else if self.xoff_on && self.queue.len() < N / 3 {
             self.xoff_on = false;
-            target.control = Some(XON);
+            target.control = Some(Control::Xon);
         }
     }
 }
+
+impl<const N: usize> Default for Queue<N> {
+    fn default() -> Self {
+        Self::new(Overflow::DropNewest)
+    }
+}
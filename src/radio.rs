@@ -1,10 +1,53 @@
+use crate::crypto::{Key, Security, Sender};
+use crate::hal::{AesHal, DataRate, RadioHal, RadioSettings, RngHal};
+use crate::link::{Link, LINK_TIMEOUT};
+use crate::packet::{
+    self, Ack, Packet, PacketData, Wake, FLAG_BREAK, FLAG_LINE_ERROR, MAX_DATA_SIZE,
+    MAX_PLAINTEXT_SIZE,
+};
+use crate::pairing::{self, Pairing, Status};
 use crate::queue::Queue;
+use crate::rate::{RateControl, RssiAverage};
+use crate::rtc::Deadline;
 use defmt::{debug, Format};
-use microbit::pac::{CLOCK, RADIO};
 
-const MAX_DATA_SIZE: usize = 64;
-const MIN_PACKET_SIZE: usize = 3;
-const MAX_PACKET_SIZE: usize = MAX_DATA_SIZE + 4;
+pub use crate::link::LinkState;
+pub use crate::pairing::DEFAULT_ADDRESS;
+
+/// Upper limit for the transmit window size, and the size of the receive window. Both ends keep a
+/// full-sized packet for each slot in the transmit and in the receive window, about 500 bytes per
+/// slot with [`MAX_DATA_SIZE`] bytes of data, so the 8 slots take 4 KB of the 16 KB of RAM. The
+/// rest of the statics and the worst-case stack leave about 1.5 KB free, which isn't enough for
+/// more slots. `scripts/ram-budget.py` checks that the firmware fits.
+pub const MAX_WINDOW_SIZE: usize = 8;
+
+/// Log the link quality this often, in ms
+const QUALITY_LOG_INTERVAL: u32 = 10_000;
+
+/// Least data put in a packet while the queue has more, after packets have been lost
+const MIN_FRAME_DATA: usize = 32;
+
+/// Grow the data in a packet by this much each time a packet gets through at the first try
+const FRAME_DATA_STEP: usize = 16;
+
+/// Minimum time from the end of a data packet or rate change to the start of the next. The radio
+/// is half duplex, so this leaves the peer time to answer before our next packet.
+const DATA_INTERVAL: u64 = 2;
+
+/// Send an ack without waiting for data when the peer's limit can be raised by this many packets
+const WINDOW_UPDATE_THRESHOLD: usize = 4;
+
+/// When the peer's receive queue is full and nothing is in flight, poke the peer this often to get
+/// a fresh limit, in case the ack that raised it was lost
+const PROBE_INTERVAL: u64 = 50;
+
+/// How long a node with duty-cycled listening keeps listening after waking up, and after each
+/// packet it sends or receives, in ms
+const LISTEN_WINDOW: u8 = 10;
+
+/// Only send to a duty-cycled peer if its listen window stays open at least this long, in ms. This
+/// covers the clocks of the two ends ticking at different moments.
+const LISTEN_GUARD: u64 = 3;
 
 #[derive(Clone, Copy, PartialEq, Eq, Format)]
 enum RadioState {
@@ -14,522 +57,1128 @@ enum RadioState {
     RxDisable,
     Tx,
     TxDisable,
+    /// Turning the radio off until the next wake-up
+    SleepDisable,
+    Sleep,
 }
 
+/// Duty-cycled listening of this node
+struct PowerSave {
+    interval: u16,
+    /// Listening until then, or longer while packets are in flight
+    listen_until: Deadline,
+    /// When to wake up next
+    wake_at: Deadline,
+    /// Tell the peer that we're listening
+    announce: bool,
+}
+
+/// A peer with duty-cycled listening, which only hears us in its listen windows
 #[derive(Clone, Copy)]
-enum RxState {
-    Initial,
-    NeedsAck { id: u8 },
-    Acked { id: u8 },
+struct PeerWake {
+    wake: Wake,
+    /// When the peer last announced a wake-up
+    announced: u64,
+    /// When the peer last sent something, which keeps it listening
+    heard: u64,
+}
+
+impl PeerWake {
+    /// Is the peer listening now? A peer that hasn't announced a wake-up for a few intervals has
+    /// stopped duty-cycling.
+    fn is_listening(&self, now: u64) -> bool {
+        let interval = self.wake.interval as u64;
+        let window = self.wake.window as u64;
+        now - self.announced > 3 * interval + window || now - self.heard + LISTEN_GUARD < window
+    }
+}
+
+/// Receive window
+struct RxState {
+    /// Next in-order packet id
+    next: u16,
+    /// Packets received ahead of `next`, indexed by `id % MAX_WINDOW_SIZE`
+    buffered: [Option<PacketData>; MAX_WINDOW_SIZE],
+    /// Have we received data packets that haven't been acked yet?
+    needs_ack: bool,
+    /// The limit sent in our latest ack
+    advertised: u16,
 }
 
 impl RxState {
-    fn debug(&self) {
-        match self {
-            RxState::Initial => {
-                debug!("radio - rx_state: Initial",);
-            }
-            RxState::NeedsAck { id } => {
-                debug!("radio - rx_state: NeedsAck id={=u8}", id);
+    fn new() -> Self {
+        Self {
+            next: 0,
+            buffered: [None; MAX_WINDOW_SIZE],
+            needs_ack: false,
+            advertised: 0,
+        }
+    }
+
+    fn slot(&mut self, id: u16) -> &mut Option<PacketData> {
+        &mut self.buffered[id as usize % MAX_WINDOW_SIZE]
+    }
+
+    /// Deliver packets that are in order, as long as the queue accepts them. Returns whether
+    /// anything was delivered.
+    fn deliver(&mut self, rx_queue: &mut Queue) -> bool {
+        let mut delivered = false;
+        while let Some(buffered) = self.slot(self.next) {
+            if !rx_queue.accepts(buffered.data().len()) {
+                break;
             }
-            RxState::Acked { id } => {
-                debug!("radio - rx_state: Acked id={=u8}", id);
+            buffered.deliver(rx_queue);
+            *self.slot(self.next) = None;
+            self.next = self.next.wrapping_add(1);
+            delivered = true;
+        }
+        delivered
+    }
+
+    fn ack(&self, rx_queue: &Queue) -> Ack {
+        let next = self.next;
+        let mut sack = 0;
+        for n in 0..(MAX_WINDOW_SIZE - 1) {
+            let id = next.wrapping_add(1 + n as u16);
+            if self.buffered[id as usize % MAX_WINDOW_SIZE].is_some() {
+                sack |= 1 << n;
             }
         }
+        // Every packet from `next` up to the limit, buffered or not, must fit in the queue. This
+        // never decreases: delivering a packet takes at most MAX_DATA_SIZE bytes of space, and
+        // moves `next` forward by one.
+        let limit = next.wrapping_add((rx_queue.free() / MAX_DATA_SIZE) as u16);
+        Ack { next, sack, limit }
+    }
+
+    /// Has the queue drained enough that the peer should hear about it without waiting for data?
+    fn needs_window_update(&self, rx_queue: &Queue) -> bool {
+        self.ack(rx_queue).limit.wrapping_sub(self.advertised) as usize >= WINDOW_UPDATE_THRESHOLD
+    }
+
+    fn debug(&self) {
+        debug!(
+            "radio - rx_state: next={=u16} needs_ack={=bool} advertised={=u16}",
+            self.next, self.needs_ack, self.advertised
+        );
     }
 }
 
+/// Retransmission tuning. Times are in ms.
 #[derive(Clone, Copy)]
-enum TxState {
-    Idle,
-    Sent {
-        packet_data: PacketData,
-        tx_count: u32,
-        since: u32,
-    },
+pub struct RetransmitConfig {
+    /// Timeout until the round-trip time has been measured
+    pub initial_timeout: u32,
+    /// Lower bound of the timeout computed from the round-trip time
+    pub min_timeout: u32,
+    /// Upper bound of the timeout, also after backing off
+    pub max_timeout: u32,
+    /// Up to this much random time is added to each timeout, so that links sharing a channel
+    /// don't keep retransmitting at the same time
+    pub max_jitter: u32,
+    /// Give up on a packet after this many transmits
+    pub max_tx_count: u32,
 }
 
-impl TxState {
-    fn debug(&self) {
-        match self {
-            TxState::Idle => {
-                debug!("radio - tx_state: Idle",);
-            }
-            TxState::Sent {
-                packet_data: PacketData { id, .. },
-                tx_count,
-                since,
-            } => {
-                debug!(
-                    "radio - tx_state: Sent id={=u8} tx_count={=u32} since={=u32}",
-                    id, tx_count, since
-                );
-            }
+impl Default for RetransmitConfig {
+    fn default() -> Self {
+        Self {
+            initial_timeout: 30,
+            min_timeout: 3,
+            max_timeout: 100,
+            max_jitter: 8,
+            max_tx_count: 16,
         }
     }
 }
 
-#[derive(Clone, Copy)]
-struct PacketData {
-    id: u8,
-    data_len: u8,
-    data: [u8; MAX_DATA_SIZE],
+/// Retransmit timeouts from the measured round-trip time, like TCP (RFC 6298), doubled for each
+/// retransmit of a packet
+struct RetransmitTimer {
+    config: RetransmitConfig,
+    /// Smoothed round-trip time, in 1/8 ms, once measured
+    srtt: Option<u32>,
+    /// Mean deviation of the round-trip time, in 1/8 ms
+    rttvar: u32,
+    /// State of the xorshift generator for the jitter. Seeded from the RNG, which is too slow to
+    /// use for every packet.
+    jitter: u32,
 }
 
-impl PacketData {
-    fn from_queue(id: u8, queue: &mut Queue) -> Self {
-        let mut data = [0; MAX_DATA_SIZE];
-        let mut len = 0;
-        while len < MAX_DATA_SIZE && !queue.is_empty() {
-            data[len] = queue.dequeue().unwrap();
-            len += 1;
-        }
+impl RetransmitTimer {
+    fn new(config: RetransmitConfig) -> Self {
         Self {
-            id,
-            data_len: len as u8,
-            data,
+            config,
+            srtt: None,
+            rttvar: 0,
+            jitter: 1,
         }
     }
 
-    fn iter(&self) -> core::slice::Iter<'_, u8> {
-        self.data[..self.data_len as usize].iter()
+    fn seed(&mut self, random: u32) {
+        // Xorshift gets stuck at zero
+        self.jitter = random.max(1);
+    }
+
+    /// Measure the round-trip time of a packet acked after its first transmit. Retransmitted
+    /// packets aren't measured (Karn's algorithm), as the ack may be for any of the transmits.
+    fn sample(&mut self, rtt: u64) {
+        let rtt = rtt.min(self.config.max_timeout as u64) as u32 * 8;
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = self.rttvar - self.rttvar / 4 + srtt.abs_diff(rtt) / 4;
+                self.srtt = Some(srtt - srtt / 8 + rtt / 8);
+            }
+        }
+    }
+
+    /// Timeout for the first transmit of a packet
+    fn timeout(&self) -> u32 {
+        match self.srtt {
+            // The variance term is at least the 1 ms granularity of the clock
+            Some(srtt) => (srtt + (4 * self.rttvar).max(8)) / 8,
+            None => self.config.initial_timeout,
+        }
+        .clamp(self.config.min_timeout, self.config.max_timeout)
+    }
+
+    /// When to retransmit a packet that has just been sent for the `tx_count`th time
+    fn deadline(&mut self, now: u64, tx_count: u32) -> Deadline {
+        let backoff = 1u32 << (tx_count - 1).min(16);
+        let timeout = self
+            .timeout()
+            .saturating_mul(backoff)
+            .min(self.config.max_timeout);
+        Deadline::after(now, timeout + self.jitter())
+    }
+
+    fn jitter(&mut self) -> u32 {
+        let mut x = self.jitter;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.jitter = x;
+        x % (self.config.max_jitter + 1)
     }
 }
 
-enum Packet {
-    Ack(u8),
-    Data(PacketData),
-    Both(u8, PacketData),
+/// How much data to put in a packet. Long packets carry less overhead, but are more likely to be
+/// hit by interference and cost more to retransmit. The size is halved each time a packet has to
+/// be retransmitted, and grows back while packets get through at the first try.
+struct FrameSize(usize);
+
+impl FrameSize {
+    fn new() -> Self {
+        Self(MAX_DATA_SIZE)
+    }
+
+    fn get(&self) -> usize {
+        self.0
+    }
+
+    fn delivered(&mut self) {
+        self.0 = (self.0 + FRAME_DATA_STEP).min(MAX_DATA_SIZE);
+    }
+
+    fn lost(&mut self) {
+        self.0 = (self.0 / 2).max(MIN_FRAME_DATA);
+    }
 }
 
-impl Packet {
-    fn read(source: &[u8]) -> Option<Self> {
-        let len = source[0];
-        if (len as usize) < MIN_PACKET_SIZE || (len as usize) > MAX_PACKET_SIZE {
-            None
+/// Signal strength and packet counters of the radio link, as seen by this end. The counters cover
+/// all sessions.
+#[derive(Clone, Copy, Default, Format)]
+pub struct LinkQuality {
+    /// Signal strength of the last packet received, in dBm, once one has been received
+    pub rssi: Option<i8>,
+    /// See [`LinkQuality::average_rssi`]. The rate control decides on it too.
+    average: RssiAverage,
+    /// Packets received with a valid CRC, from the peer or anyone else using the same address
+    pub received: u32,
+    /// Packets received with a CRC error, because of interference or a weak signal
+    pub crc_errors: u32,
+    /// Data packets sent for the first time
+    pub sent: u32,
+    /// Data packets sent again because they weren't acked in time
+    pub retransmits: u32,
+    /// Data packets given up on after the retry limit, each of which restarted the link
+    pub give_ups: u32,
+}
+
+impl LinkQuality {
+    /// Moving average of the signal strength of the packets received, in dBm
+    pub fn average_rssi(&self) -> Option<i8> {
+        self.average.dbm()
+    }
+
+    fn received(&mut self, rssi: i8, crc_ok: bool) {
+        if crc_ok {
+            self.received = self.received.wrapping_add(1);
         } else {
-            match source[1] {
-                b'A' => Some(Self::Ack(source[2])),
-                b'D' => {
-                    let mut data = [0; MAX_DATA_SIZE];
-                    data[..(len as usize - 3)].copy_from_slice(&source[3..(len as usize)]);
-                    Some(Self::Data(PacketData {
-                        id: source[2],
-                        data_len: len - 3,
-                        data,
-                    }))
-                }
-                b'X' => {
-                    let mut data = [0; MAX_DATA_SIZE];
-                    data[..(len as usize - 4)].copy_from_slice(&source[4..(len as usize)]);
-                    Some(Self::Both(
-                        source[2],
-                        PacketData {
-                            id: source[3],
-                            data_len: len - 4,
-                            data,
-                        },
-                    ))
-                }
-                _ => None,
-            }
+            self.crc_errors = self.crc_errors.wrapping_add(1);
         }
+        self.rssi = Some(rssi);
+        self.average.add(rssi);
     }
+}
 
-    fn write(&self, target: &mut [u8]) {
-        match self {
-            Packet::Ack(ack) => {
-                target[0] = 3;
-                target[1] = b'A';
-                target[2] = *ack;
-            }
-            Packet::Data(PacketData { id, data_len, data }) => {
-                target[0] = data_len + 3;
-                target[1] = b'D';
-                target[2] = *id;
-                target[3..(*data_len as usize + 3)].copy_from_slice(&data[0..*data_len as usize]);
-            }
-            Packet::Both(ack_id, PacketData { id, data_len, data }) => {
-                target[0] = data_len + 4;
-                target[1] = b'X';
-                target[2] = *ack_id;
-                target[3] = *id;
-                target[4..(*data_len as usize + 4)].copy_from_slice(&data[0..*data_len as usize]);
-            }
+#[derive(Clone, Copy)]
+struct TxSlot {
+    packet_data: PacketData,
+    tx_count: u32,
+    /// When the packet was last sent
+    sent_at: u64,
+    /// When to retransmit if there's no ack by then
+    retransmit_at: Deadline,
+}
+
+/// Transmit window
+struct TxState {
+    /// Oldest packet id that has not been acked
+    base: u16,
+    /// Id of the next new packet
+    next: u16,
+    /// Packets waiting for an ack, indexed by `id % MAX_WINDOW_SIZE`
+    slots: [Option<TxSlot>; MAX_WINDOW_SIZE],
+    /// Packets before this id fit in the peer's receive queue
+    limit: u16,
+    frame_size: FrameSize,
+}
+
+impl TxState {
+    fn new() -> Self {
+        Self {
+            base: 0,
+            next: 0,
+            slots: [None; MAX_WINDOW_SIZE],
+            limit: 1,
+            frame_size: FrameSize::new(),
         }
     }
 
-    fn debug_assembled(&self) {
-        match self {
-            Packet::Ack(ack) => {
-                debug!("radio - assembled packet: A ack={=u8}", ack);
+    fn in_flight(&self) -> usize {
+        self.next.wrapping_sub(self.base) as usize
+    }
+
+    /// Number of new packets the peer can accept
+    fn credit(&self) -> usize {
+        self.limit.wrapping_sub(self.next) as usize
+    }
+
+    fn slot(&mut self, id: u16) -> &mut Option<TxSlot> {
+        &mut self.slots[id as usize % MAX_WINDOW_SIZE]
+    }
+
+    fn is_in_flight(&self, id: u16) -> bool {
+        (id.wrapping_sub(self.base) as usize) < self.in_flight()
+    }
+
+    /// Add a new packet to the window
+    fn push(&mut self, now: u64, tx_queue: &mut Queue, timer: &mut RetransmitTimer) -> PacketData {
+        let packet_data = PacketData::from_queue(self.next, tx_queue, self.frame_size.get());
+        *self.slot(self.next) = Some(TxSlot {
+            packet_data,
+            tx_count: 1,
+            sent_at: now,
+            retransmit_at: timer.deadline(now, 1),
+        });
+        self.next = self.next.wrapping_add(1);
+        packet_data
+    }
+
+    /// Remove an acked packet from the window, measuring the round-trip time
+    fn acked(&mut self, id: u16, now: u64, timer: &mut RetransmitTimer) {
+        if let Some(slot) = self.slot(id).take() {
+            if slot.tx_count == 1 {
+                timer.sample(now - slot.sent_at);
+                self.frame_size.delivered();
             }
-            Packet::Data(PacketData { id, data_len, .. }) => {
-                debug!(
-                    "radio - assembled packet: D id={=u8} data_len={=u8}",
-                    id, data_len
-                );
+        }
+    }
+
+    fn handle_ack(&mut self, now: u64, ack: Ack, timer: &mut RetransmitTimer) {
+        // A duplicated or delayed ack may carry an old limit. Never move it backwards.
+        if (ack.limit.wrapping_sub(self.limit) as usize) < 0x8000 {
+            self.limit = ack.limit;
+        }
+
+        // Cumulative ack
+        if ack.next.wrapping_sub(self.base) as usize <= self.in_flight() {
+            while self.base != ack.next {
+                self.acked(self.base, now, timer);
+                self.base = self.base.wrapping_add(1);
             }
-            Packet::Both(ack, PacketData { id, data_len, .. }) => {
-                debug!(
-                    "radio - assembled packet: X ack={=u8} id={=u8} data_len={=u8}",
-                    ack, id, data_len
-                );
+        }
+
+        // Selective acks
+        for n in 0..(MAX_WINDOW_SIZE - 1) {
+            let id = ack.next.wrapping_add(1 + n as u16);
+            if ack.sack & (1 << n) != 0 && self.is_in_flight(id) {
+                self.acked(id, now, timer);
             }
         }
+
+        self.advance();
     }
 
-    fn debug_received(&self) {
-        match self {
-            Packet::Ack(ack) => {
-                debug!("radio - received packet: A ack={=u8}", ack);
-            }
-            Packet::Data(PacketData { id, data_len, .. }) => {
-                debug!(
-                    "radio - received packet: D id={=u8} data_len={=u8}",
-                    id, data_len
-                );
+    /// Move the window past packets that have been acked
+    fn advance(&mut self) {
+        while self.base != self.next && self.slot(self.base).is_none() {
+            self.base = self.base.wrapping_add(1);
+        }
+    }
+
+    /// Has a packet gone unacked after the maximum number of transmits?
+    fn exhausted(&self, now: u64, max_tx_count: u32) -> bool {
+        self.slots
+            .iter()
+            .flatten()
+            .any(|slot| slot.tx_count >= max_tx_count && slot.retransmit_at.has_passed(now))
+    }
+
+    /// Find the oldest packet that should be retransmitted
+    fn retransmit(&mut self, now: u64, timer: &mut RetransmitTimer) -> Option<PacketData> {
+        for offset in 0..self.in_flight() {
+            let id = self.base.wrapping_add(offset as u16);
+            if let Some(slot) = self.slot(id) {
+                if slot.retransmit_at.has_passed(now) {
+                    slot.tx_count += 1;
+                    slot.sent_at = now;
+                    slot.retransmit_at = timer.deadline(now, slot.tx_count);
+                    let packet_data = slot.packet_data;
+                    self.frame_size.lost();
+                    return Some(packet_data);
+                }
             }
-            Packet::Both(ack, PacketData { id, data_len, .. }) => {
-                debug!(
-                    "radio - received packet: X ack={=u8} id={=u8} data_len={=u8}",
-                    ack, id, data_len
-                );
+        }
+        None
+    }
+
+    fn debug(&self) {
+        debug!(
+            "radio - tx_state: base={=u16} next={=u16} in_flight={=usize} limit={=u16} frame_size={=usize}",
+            self.base,
+            self.next,
+            self.in_flight(),
+            self.limit,
+            self.frame_size.get()
+        );
+    }
+}
+
+impl PacketData {
+    /// Take up to `max_len` bytes from the queue, and the break after them, if any
+    fn from_queue(id: u16, queue: &mut Queue, max_len: usize) -> Self {
+        let mut data = [0; MAX_DATA_SIZE];
+        let mut len = 0;
+        while len < max_len {
+            match queue.dequeue() {
+                Some(byte) => data[len] = byte,
+                None => break,
             }
+            len += 1;
+        }
+        let mut flags = 0;
+        if queue.take_break() {
+            flags |= FLAG_BREAK;
+        }
+        if queue.take_line_error() {
+            flags |= FLAG_LINE_ERROR;
+        }
+        Self::new(id, flags, &data[..len])
+    }
+
+    /// An empty packet with an id the peer has already seen. The peer acks it but delivers
+    /// nothing.
+    fn probe(id: u16) -> Self {
+        Self::new(id, 0, &[])
+    }
+
+    /// Write the data and the break after it to the rx queue
+    fn deliver(&self, queue: &mut Queue) {
+        for &byte in self.data() {
+            queue.enqueue(byte);
+        }
+        if self.flags & FLAG_BREAK != 0 {
+            queue.enqueue_break();
         }
     }
 }
 
-pub struct Radio {
-    radio: RADIO,
-    packet: [u8; MAX_PACKET_SIZE],
-    next_packet_id: u8,
+pub struct Radio<R: RadioHal, A: AesHal, G: RngHal> {
+    radio: R,
+    rng: G,
+    security: Security<A>,
+    window_size: usize,
+    /// When the last data packet or rate change was sent, or started to be sent
+    last_data_tx: u64,
+    /// The packet being sent is a data packet or rate change, which the peer answers
+    sending_data: bool,
     radio_state: RadioState,
     rx_state: RxState,
     tx_state: TxState,
+    timer: RetransmitTimer,
+    link: Link,
+    rate: RateControl,
+    /// The configured settings. The data rate in use may differ, see [`Radio::set_adaptive_rate`].
+    settings: RadioSettings,
+    /// Link id agreed by pairing, which determines the radio address
+    link_id: Option<u32>,
+    pairing: Option<Pairing>,
+    /// Link id from a finished pairing, not yet taken for storing
+    paired: Option<u32>,
+    /// Apply new settings or a new address the next time the radio is disabled
+    reconfigure: bool,
+    /// Data packets from the peer that signalled a line error
+    peer_line_errors: u32,
+    quality: LinkQuality,
+    next_quality_log: Deadline,
+    /// Did the last tick leave the radio waiting for an event or the clock?
+    idle: bool,
+    /// Duty-cycled listening, see [`Radio::set_wake_interval`]
+    power_save: Option<PowerSave>,
+    /// Set if the peer uses duty-cycled listening
+    peer_wake: Option<PeerWake>,
 }
 
-impl Radio {
-    pub fn new(radio: RADIO) -> Self {
+impl<R: RadioHal, A: AesHal, G: RngHal> Radio<R, A, G> {
+    /// Create a new radio. `window_size` is the maximum number of unacked packets in flight,
+    /// from 1 to [`MAX_WINDOW_SIZE`]. Packets are encrypted if `key` is given. Nodes with a
+    /// `link_id` only hear nodes with the same link id.
+    ///
+    /// Always inlined, as the buffers take several KiB that must not be built on the stack
+    #[inline(always)]
+    pub fn new(
+        radio: R,
+        aes: A,
+        rng: G,
+        key: Option<Key>,
+        link_id: Option<u32>,
+        settings: RadioSettings,
+        window_size: usize,
+    ) -> Self {
+        assert!(
+            (1..=MAX_WINDOW_SIZE).contains(&window_size),
+            "window size out of range"
+        );
         Self {
             radio,
-            packet: [0; MAX_PACKET_SIZE],
-            next_packet_id: 0,
+            rng,
+            security: Security::new(aes, key),
+            window_size,
+            last_data_tx: 0,
+            sending_data: false,
             radio_state: RadioState::Uninitialized,
-            rx_state: RxState::Initial,
-            tx_state: TxState::Idle,
-        }
-    }
-
-    pub fn init(&mut self, clock: &CLOCK) {
-        clock.events_hfclkstarted.write(|w| unsafe { w.bits(0) });
-        clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
-        while clock.events_hfclkstarted.read().bits() == 0 {}
-
-        // Configure radio to match microbit defaults
-        self.radio.txpower.write(|w| w.txpower().pos4d_bm()); // +4 dBm
-        self.radio.frequency.write(|w| unsafe { w.bits(7) }); // Default channel: 7
-        self.radio.mode.write(|w| w.mode().nrf_1mbit()); // Default data rate: 1 Mbps
-        self.radio.base0.write(|w| unsafe { w.bits(0x75626974) }); // "uBit"
-        self.radio.prefix0.write(|w| unsafe { w.bits(0) });
-        self.radio.txaddress.write(|w| unsafe { w.bits(0) }); // Transmit on logical address 0
-        self.radio.rxaddresses.write(|w| w.addr0().enabled()); // Enable reception on logical address 0 only
-        self.radio.pcnf0.write(|w| unsafe {
-            w.lflen()
-                .bits(8) // 8-bit length field
-                .s0len()
-                .bit(false) // No S0 field
-                .s1len()
-                .bits(0) // No S1 field
-        });
-        self.radio.pcnf1.write(|w| unsafe {
-            w.maxlen()
-                .bits((MAX_PACKET_SIZE - 1) as u8) // Maximum payload
-                .statlen()
-                .bits(0)
-                .balen()
-                .bits(4) // 4-byte base address length
-                .endian()
-                .little() // Little endian payload
-                .whiteen()
-                .enabled() // Enable packet whitening
+            rx_state: RxState::new(),
+            tx_state: TxState::new(),
+            timer: RetransmitTimer::new(RetransmitConfig::default()),
+            link: Link::new(),
+            rate: RateControl::new(settings.data_rate),
+            settings,
+            link_id,
+            pairing: None,
+            paired: None,
+            reconfigure: false,
+            peer_line_errors: 0,
+            quality: LinkQuality::default(),
+            next_quality_log: Deadline::after(0, QUALITY_LOG_INTERVAL),
+            idle: false,
+            power_save: None,
+            peer_wake: None,
+        }
+    }
+
+    pub fn init(&mut self, now: u64) {
+        self.rng.init();
+        self.security.start_session(&mut self.rng);
+        self.timer.seed(self.rng.random());
+        self.link.restart(now, self.rng.random());
+        self.radio.init();
+        self.radio.configure(&self.settings);
+        self.radio.set_address(self.address());
+        self.radio.rx_enable();
+        self.radio_state = RadioState::RxIdle;
+
+        debug!("Radio initialized: {}", self.settings);
+    }
+
+    fn address(&self) -> u32 {
+        match (&self.pairing, self.link_id) {
+            (None, Some(link_id)) => link_id,
+            _ => pairing::DEFAULT_ADDRESS,
+        }
+    }
+
+    /// Change the channel, power or data rate. Takes effect after the packet being sent or
+    /// received, if any.
+    pub fn configure(&mut self, settings: RadioSettings) {
+        if settings != self.settings {
+            self.settings = settings;
+            self.rate.configure(settings.data_rate);
+            self.reconfigure = true;
+        }
+    }
+
+    /// Step the data rate up and down with the link quality, starting from the configured one
+    /// with each new session. The peer has to agree to each change, see [`crate::rate`].
+    pub fn set_adaptive_rate(&mut self, adaptive: bool) {
+        self.rate.set_adaptive(adaptive);
+    }
+
+    /// The settings the radio runs with, at the data rate in use
+    fn radio_settings(&self) -> RadioSettings {
+        RadioSettings {
+            data_rate: self.rate.current(),
+            ..self.settings
+        }
+    }
+
+    /// The data rate in use
+    pub fn data_rate(&self) -> DataRate {
+        self.rate.current()
+    }
+
+    /// Change the retransmit timeouts and the retry limit
+    pub fn set_retransmit_config(&mut self, config: RetransmitConfig) {
+        self.timer.config = config;
+    }
+
+    /// Turn the radio off between listen windows to save power, waking up every `interval` ms.
+    /// Data from the peer waits for the next wake-up, data to the peer wakes the radio right away.
+    /// The peer holds its packets back while the radio is off, so it must keep listening itself.
+    pub fn set_wake_interval(&mut self, interval: Option<u16>) {
+        self.power_save = interval.map(|interval| PowerSave {
+            interval,
+            listen_until: Deadline::after(0, 0),
+            wake_at: Deadline::after(0, 0),
+            announce: true,
         });
-        self.radio.crccnf.write(|w| w.len().two()); // 16-bit CRC
-        self.radio.crcinit.write(|w| unsafe { w.bits(0xFFFF) }); // CRC initial value
-        self.radio.crcpoly.write(|w| unsafe { w.bits(0x11021) }); // CRC polynomial
-        self.radio.datawhiteiv.write(|w| unsafe { w.bits(0x18) }); // Initial value for the data whitening algorithm
+    }
 
-        let packet_ptr = self.packet.as_ptr() as u32;
-        self.radio
-            .packetptr
-            .write(|w| unsafe { w.bits(packet_ptr) });
+    /// Start pairing with another node that is in pairing mode. Data isn't sent or received
+    /// until pairing has finished or failed.
+    pub fn pair(&mut self, now: u64) {
+        self.pairing = Some(Pairing::new(now, self.rng.random()));
+        // Switch to the new address
+        self.reconfigure = true;
+    }
 
-        // Shortcut READY -> START
-        self.radio.shorts.write(|w| w.ready_start().enabled());
+    /// The link id agreed by a finished pairing, to be stored
+    pub fn take_paired(&mut self) -> Option<u32> {
+        self.paired.take()
+    }
 
-        self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
-        self.radio_state = RadioState::RxIdle;
+    fn check_pairing(&mut self, now: u64) {
+        let status = match &self.pairing {
+            Some(pairing) => pairing.status(now),
+            None => return,
+        };
+        match status {
+            Status::Pairing => return,
+            Status::Paired(link_id) => {
+                debug!("radio - paired, link id {=u32:x}", link_id);
+                self.link_id = Some(link_id);
+                self.paired = Some(link_id);
+                // A new peer, so start over
+                self.restart_link(now);
+            }
+            Status::Failed => {
+                debug!("radio - pairing failed");
+            }
+        }
+        self.pairing = None;
+        // Switch to the new address
+        self.reconfigure = true;
+    }
+
+    /// Start a new session, with new packet ids. Packets in flight are lost.
+    fn restart_link(&mut self, now: u64) {
+        self.link.restart(now, self.rng.random());
+        self.rx_state = RxState::new();
+        self.tx_state = TxState::new();
+        self.rate.reset(now, false);
+    }
+
+    /// An authentic packet was received from the peer
+    fn heard(&mut self, now: u64) {
+        self.link.heard(now);
+        self.rate.heard(now);
+    }
+
+    /// The link goes down if the peer isn't heard for a while. A duty-cycled end may not be heard
+    /// for a few wake intervals.
+    fn check_link(&mut self, now: u64) {
+        let own_interval = self
+            .power_save
+            .as_ref()
+            .map_or(0, |power_save| power_save.interval);
+        let peer_interval = self
+            .peer_wake
+            .map_or(0, |peer_wake| peer_wake.wake.interval);
+        let timeout = LINK_TIMEOUT.max(4 * own_interval.max(peer_interval) as u64);
+        if self.pairing.is_none() && self.link.timed_out(now, timeout) {
+            debug!("radio - nothing heard from the peer, restarting the link");
+            self.restart_link(now);
+        }
+    }
+
+    pub fn link_state(&self) -> LinkState {
+        self.link.state()
+    }
+
+    /// Number of times the link has gone down after being up
+    pub fn link_drops(&self) -> u32 {
+        self.link.drops()
+    }
+
+    /// Received packets that failed authentication
+    pub fn auth_failures(&self) -> u32 {
+        self.security.auth_failures()
+    }
+
+    /// Received authentic packets that were rejected as replays
+    pub fn replays(&self) -> u32 {
+        self.security.replays()
+    }
+
+    /// Errors on the peer's serial line that the peer has signalled
+    pub fn peer_line_errors(&self) -> u32 {
+        self.peer_line_errors
+    }
+
+    /// Signal strength and packet counters of the link
+    pub fn link_quality(&self) -> LinkQuality {
+        self.quality
+    }
 
-        debug!("Radio initialized");
+    /// Is there nothing to do until a radio event or [`Radio::next_timeout`]? A tick that changes
+    /// the state leaves the next one something to do, e.g. sending an ack after receiving.
+    pub fn is_idle(&self) -> bool {
+        self.idle
     }
 
-    pub fn tick(&mut self, now: u32, tx_queue: &mut Queue, rx_queue: &mut Queue) {
+    /// When to tick next without a radio event or new data to send. While the radio is on, that's
+    /// the next ms, for the many timeouts of sending packets. While it sleeps, it's the next
+    /// wake-up, and the link timeout is checked then, at most one wake interval late.
+    pub fn next_timeout(&self, now: u64) -> Deadline {
+        match &self.power_save {
+            Some(power_save) if self.radio_state == RadioState::Sleep => power_save.wake_at,
+            _ => Deadline::after(now, 1),
+        }
+    }
+
+    pub fn tick(&mut self, now: u64, tx_queue: &mut Queue, rx_queue: &mut Queue) {
+        let state = self.radio_state;
+        // Packets held back by a full queue with Overflow::Block, now acked
+        if self.rx_state.deliver(rx_queue) {
+            self.rx_state.needs_ack = true;
+        }
+        self.check_pairing(now);
+        self.check_link(now);
+        self.rate.check(now);
+        if self.next_quality_log.has_passed(now) {
+            self.next_quality_log = Deadline::after(now, QUALITY_LOG_INTERVAL);
+            debug!("radio - link quality: {}", self.quality);
+        }
+
         self.radio_state = match self.radio_state {
             RadioState::Uninitialized => RadioState::Uninitialized,
             RadioState::RxIdle => {
-                if self.radio.events_address.read().bits() != 0 {
-                    debug!("radio - receiving at {=u32}", now);
-                    self.radio.events_address.write(|w| unsafe { w.bits(0) });
+                if self.reconfigure {
+                    self.radio.disable();
+                    RadioState::TxDisable
+                } else if self.radio.address_event() {
+                    debug!("radio - receiving at {=u64}", now);
                     RadioState::Rx
                 } else {
-                    let (tx_packet, rx_state, tx_state) = self.assemble_packet(now, tx_queue);
-                    self.rx_state = rx_state;
-                    self.tx_state = tx_state;
-
-                    if let Some(packet) = tx_packet {
-                        packet.debug_assembled();
-                        rx_state.debug();
-                        tx_state.debug();
-                        packet.write(&mut self.packet);
-
-                        debug!("radio - disable rx at {=u32}", now);
-                        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+                    if let Some(packet) = self.assemble_packet(now, tx_queue, rx_queue) {
+                        self.link.sent(now);
+                        self.sending_data = matches!(packet, Packet::Data(..) | Packet::Rate(..));
+                        debug!("radio - assembled packet: {}", packet);
+                        self.rx_state.debug();
+                        self.tx_state.debug();
+                        let mut plaintext = [0; MAX_PLAINTEXT_SIZE];
+                        packet.write(self.link.version(), &mut plaintext);
+                        if !self.security.seal(&plaintext, self.radio.packet()) {
+                            self.security.start_session(&mut self.rng);
+                        }
+
+                        debug!("radio - disable rx at {=u64}", now);
+                        self.radio.disable();
                         RadioState::RxDisable
+                    } else if self.should_sleep(now, tx_queue) {
+                        debug!("radio - sleeping at {=u64}", now);
+                        self.radio.disable();
+                        RadioState::SleepDisable
                     } else {
                         RadioState::RxIdle
                     }
                 }
             }
             RadioState::Rx => {
-                if self.radio.events_end.read().bits() != 0 {
-                    self.radio.events_end.write(|w| unsafe { w.bits(0) });
-                    if self.radio.crcstatus.read().crcstatus().is_crcok() {
+                if self.radio.end_event() {
+                    let crc_ok = self.radio.crc_ok();
+                    self.quality.received(self.radio.rssi(), crc_ok);
+                    if crc_ok {
                         // CRC ok
-                        debug!("radio - crc ok at {=u32}", now);
-                        if let Some(packet) = Packet::read(&self.packet) {
-                            match packet {
-                                Packet::Ack(ack) => {
-                                    // debug!("radio - received ack: {=u8}", ack);
-                                    self.handle_rx_ack(ack);
+                        debug!("radio - crc ok at {=u64}", now);
+                        let mut plaintext = [0; MAX_PLAINTEXT_SIZE];
+                        match self.security.open(self.radio.packet(), &mut plaintext) {
+                            None => debug!("radio - rejected packet"),
+                            Some(sender) => match Packet::read(&plaintext) {
+                                Ok(packet) => self.handle_packet(now, packet, sender, rx_queue),
+                                Err(error) => {
+                                    debug!("radio - received malformed packet: {}", error)
                                 }
-                                Packet::Data(packet_data) => {
-                                    // debug!("radio - received data: {=u8}", packet_data.id);
-                                    self.handle_rx_data(packet_data, rx_queue);
-                                }
-                                Packet::Both(ack, packet_data) => {
-                                    // debug!("radio - received ack and data: {=u8}", packet_data.id);
-                                    self.handle_rx_ack(ack);
-                                    self.handle_rx_data(packet_data, rx_queue);
-                                }
-                            }
-                            packet.debug_received();
-                            self.rx_state.debug();
-                            self.tx_state.debug();
-                        } else {
-                            debug!(
-                                "radio - received malformed packet {=u8} {=u8} {=u8} {=u8}",
-                                self.packet[0], self.packet[1], self.packet[2], self.packet[3]
-                            );
+                            },
                         }
                     } else {
                         // CRC error
                         debug!("radio - crc error");
                     }
-                    self.radio.tasks_start.write(|w| unsafe { w.bits(1) });
-                    debug!("radio - receive done - restarted rx at {=u32}", now);
+                    self.keep_listening(now);
+                    self.radio.start();
+                    debug!("radio - receive done - restarted rx at {=u64}", now);
                     RadioState::RxIdle
                 } else {
                     RadioState::Rx
                 }
             }
             RadioState::RxDisable => {
-                if self.radio.events_disabled.read().bits() != 0 {
-                    debug!("radio - rx disabled at {=u32}", now);
-                    self.radio.events_disabled.write(|w| unsafe { w.bits(0) });
-                    self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+                if self.radio.disabled_event() {
+                    debug!("radio - rx disabled at {=u64}", now);
+                    self.radio.tx_enable();
                     RadioState::Tx
                 } else {
                     RadioState::RxDisable
                 }
             }
             RadioState::Tx => {
-                if self.radio.events_end.read().bits() != 0 {
-                    debug!("radio - tx done at {=u32}", now);
-                    self.radio.events_address.write(|w| unsafe { w.bits(0) });
-                    self.radio.events_end.write(|w| unsafe { w.bits(0) });
-                    self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+                if self.radio.end_event() {
+                    debug!("radio - tx done at {=u64}", now);
+                    if self.sending_data {
+                        self.last_data_tx = now;
+                    }
+                    self.keep_listening(now);
+                    // Clear the ADDRESS event generated by our own transmission
+                    self.radio.address_event();
+                    self.radio.disable();
                     RadioState::TxDisable
                 } else {
                     RadioState::Tx
                 }
             }
             RadioState::TxDisable => {
-                if self.radio.events_disabled.read().bits() != 0 {
-                    debug!("radio - tx disabled at {=u32}", now);
-                    self.radio.events_disabled.write(|w| unsafe { w.bits(0) });
-                    self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+                if self.radio.disabled_event() {
+                    debug!("radio - tx disabled at {=u64}", now);
+                    // A frame that was arriving when the radio was disabled from RxIdle, e.g. to
+                    // reconfigure it, was cut off and never ends
+                    self.radio.address_event();
+                    if self.reconfigure {
+                        self.reconfigure = false;
+                        let settings = self.radio_settings();
+                        self.radio.configure(&settings);
+                        self.radio.set_address(self.address());
+                        debug!("radio - reconfigured: {}", settings);
+                    }
+                    self.radio.rx_enable();
                     RadioState::RxIdle
                 } else {
                     RadioState::TxDisable
                 }
             }
-        };
-    }
-
-    fn get_packet_id(&mut self) -> u8 {
-        let id = self.next_packet_id;
-        self.next_packet_id = self.next_packet_id.wrapping_add(1);
-        id
-    }
-
-    fn handle_rx_ack(&mut self, ack: u8) {
-        if let TxState::Sent {
-            packet_data: PacketData { id, .. },
-            ..
-        } = self.tx_state
-        {
-            if id == ack {
-                self.tx_state = TxState::Idle;
+            RadioState::SleepDisable => {
+                if self.radio.disabled_event() {
+                    // As in TxDisable
+                    self.radio.address_event();
+                    self.radio.stop_clock();
+                    RadioState::Sleep
+                } else {
+                    RadioState::SleepDisable
+                }
+            }
+            RadioState::Sleep => {
+                if self.wake_up(now, tx_queue) {
+                    debug!("radio - woke up at {=u64}", now);
+                    self.radio.start_clock();
+                    self.radio.rx_enable();
+                    RadioState::RxIdle
+                } else {
+                    RadioState::Sleep
+                }
             }
+        };
+        // A new data rate is applied after the packet being sent, which may be the answer that
+        // agrees to it
+        if self.rate.take_changed() {
+            self.reconfigure = true;
         }
+        // New settings are applied from RxIdle right away
+        self.idle = self.radio_state == state
+            && !(self.radio_state == RadioState::RxIdle && self.reconfigure);
     }
 
-    fn handle_rx_data(&mut self, packet_data: PacketData, rx_queue: &mut Queue) {
-        match self.rx_state {
-            RxState::Initial => {
-                // This is the first received data packet
-                for &byte in packet_data.iter() {
-                    rx_queue.enqueue(byte);
+    fn handle_packet(&mut self, now: u64, packet: Packet, sender: Sender, rx_queue: &mut Queue) {
+        // Only the handshake can tell a rebooted peer from a recording, so nothing else is taken
+        // from an unconfirmed session
+        if sender == Sender::Unconfirmed && !matches!(packet, Packet::Pair(_) | Packet::Hello(_)) {
+            debug!("radio - packet from an unconfirmed session");
+            return;
+        }
+        if let Some(peer_wake) = &mut self.peer_wake {
+            peer_wake.heard = now;
+        }
+        match packet {
+            Packet::Pair(offer) => {
+                if let Some(pairing) = &mut self.pairing {
+                    pairing.handle_offer(now, offer);
                 }
-                self.rx_state = RxState::NeedsAck { id: packet_data.id };
             }
-            RxState::Acked { id: last_acked_id } => {
-                if packet_data.id != last_acked_id {
-                    // Write data to rx queue only if it's a new packet
-                    for &byte in packet_data.iter() {
-                        rx_queue.enqueue(byte);
-                    }
-                } else {
-                    debug!(
-                        "radio - received an already acked packet {=u8}",
-                        packet_data.id
-                    );
+            // Unpaired nodes use the pairing address too
+            _ if self.pairing.is_some() => {}
+            // May be recorded from an earlier session of either end, which mustn't end the link.
+            // A peer that has rebooted is heard again once the link has timed out.
+            Packet::Hello(hello)
+                if sender == Sender::Unconfirmed
+                    && self.link.state() == LinkState::Up
+                    && !self.link.is_echo(&hello) =>
+            {
+                debug!("radio - hello from an unconfirmed session");
+            }
+            Packet::Hello(hello) => {
+                let handshake = self.link.handle_hello(hello);
+                if handshake.confirmed {
+                    self.security.confirm_peer();
+                }
+                if handshake.new_session {
+                    // The peer has started over
+                    self.rx_state = RxState::new();
+                    self.tx_state = TxState::new();
+                    let peer_adaptive = self.link.version() >= packet::RATE_CHANGE_VERSION;
+                    self.rate.reset(now, peer_adaptive);
+                }
+                // A recorded hello doesn't keep the link up
+                if sender == Sender::Peer || handshake.confirmed {
+                    self.heard(now);
                 }
-                self.rx_state = RxState::NeedsAck { id: packet_data.id };
             }
-            RxState::NeedsAck { .. } => {
-                // Got a new packet before sending an ack => must be a retransmit
+            Packet::Wake(wake) => {
+                self.heard(now);
+                self.peer_wake = Some(PeerWake {
+                    wake,
+                    announced: now,
+                    heard: now,
+                });
+            }
+            // From a session that has ended, or the peer hasn't heard our hello yet
+            Packet::Ack(session, _) | Packet::Data(session, _, _) | Packet::Rate(session, _)
+                if self.link.session() != Some(session) =>
+            {
+                debug!("radio - packet from another session");
+            }
+            Packet::Ack(_, ack) => {
+                self.heard(now);
+                self.tx_state.handle_ack(now, ack, &mut self.timer);
+            }
+            Packet::Rate(_, rate_change) => {
+                self.heard(now);
+                if self.rate.handle(now, rate_change) {
+                    self.rx_state.needs_ack = true;
+                }
+            }
+            Packet::Data(_, ack, packet_data) => {
+                self.heard(now);
+                self.tx_state.handle_ack(now, ack, &mut self.timer);
+                self.handle_rx_data(packet_data, rx_queue);
+            }
+        }
+        debug!("radio - received packet: {}", packet);
+        self.rx_state.debug();
+        self.tx_state.debug();
+    }
+
+    fn handle_rx_data(&mut self, packet_data: PacketData, rx_queue: &mut Queue) {
+        let ahead = packet_data.id.wrapping_sub(self.rx_state.next) as usize;
+        let behind = self.rx_state.next.wrapping_sub(packet_data.id) as usize;
+
+        if (1..=MAX_WINDOW_SIZE).contains(&behind) {
+            // Must be a retransmit of a packet whose ack was lost, or a probe. The peer's window
+            // never starts more than a window before ours.
+            debug!(
+                "radio - received an already acked packet {=u16}",
+                packet_data.id
+            );
+        } else if ahead >= MAX_WINDOW_SIZE {
+            // The peer never sends outside of the window, since it restarts the link rather than
+            // give up on a packet. Must be stale or forged, so not acked.
+            debug!(
+                "radio - received packet {=u16} outside of the window",
+                packet_data.id
+            );
+            return;
+        } else {
+            let slot = self.rx_state.slot(packet_data.id);
+            if slot.is_some() {
+                // Keep the original, this may be a probe reusing the id
                 debug!(
-                    "radio - received a packet before sending ack {=u8}",
+                    "radio - received an already buffered packet {=u16}",
                     packet_data.id
                 );
+            } else {
+                *slot = Some(packet_data);
+                if packet_data.flags & FLAG_LINE_ERROR != 0 {
+                    self.peer_line_errors = self.peer_line_errors.wrapping_add(1);
+                }
+            }
+        }
+
+        self.rx_state.deliver(rx_queue);
+        self.rx_state.needs_ack = true;
+    }
+
+    /// Extend the listen window after a packet, which may be followed by more
+    fn keep_listening(&mut self, now: u64) {
+        if let Some(power_save) = &mut self.power_save {
+            power_save.listen_until = Deadline::after(now, LISTEN_WINDOW as u32);
+        }
+    }
+
+    /// Should the radio be turned off until the next wake-up? Not before the peer has acked
+    /// everything we sent.
+    fn should_sleep(&self, now: u64, tx_queue: &Queue) -> bool {
+        match &self.power_save {
+            Some(power_save) => {
+                self.pairing.is_none()
+                    && power_save.listen_until.has_passed(now)
+                    && !power_save.announce
+                    && self.tx_state.in_flight() == 0
+                    && !self.rx_state.needs_ack
+                    && (tx_queue.is_empty() || self.tx_state.credit() == 0)
             }
+            None => false,
         }
     }
 
+    /// Is it time to turn the radio on again? Data to send doesn't wait for the wake-up.
+    fn wake_up(&mut self, now: u64, tx_queue: &Queue) -> bool {
+        let power_save = match &mut self.power_save {
+            Some(power_save) => power_save,
+            None => return true,
+        };
+        let wake_up = power_save.wake_at.has_passed(now)
+            || self.pairing.is_some()
+            || self.reconfigure
+            || (!tx_queue.is_empty() && self.tx_state.credit() > 0);
+        if wake_up {
+            power_save.wake_at = Deadline::after(now, power_save.interval as u32);
+            power_save.listen_until = Deadline::after(now, LISTEN_WINDOW as u32);
+            power_save.announce = true;
+        }
+        wake_up
+    }
+
     fn assemble_packet(
         &mut self,
-        now: u32,
+        now: u64,
         tx_queue: &mut Queue,
-    ) -> (Option<Packet>, RxState, TxState) {
-        match (self.rx_state, self.tx_state) {
-            (
-                RxState::NeedsAck { id: ack_id },
-                TxState::Sent {
-                    packet_data,
-                    tx_count,
-                    ..
-                },
-            ) => {
-                // Should ack the last rx packet, and still waiting for tx ack =>
-                // send ack and retransmit last data
-                (
-                    Some(Packet::Both(ack_id, packet_data)),
-                    RxState::Acked { id: ack_id },
-                    TxState::Sent {
-                        tx_count: tx_count + 1,
-                        since: now,
-                        packet_data,
-                    },
-                )
+        rx_queue: &Queue,
+    ) -> Option<Packet> {
+        if let Some(pairing) = &mut self.pairing {
+            return if pairing.offer_due(now) {
+                let jitter = self.rng.random() % pairing::OFFER_INTERVAL;
+                Some(Packet::Pair(pairing.offer(now, jitter)))
+            } else {
+                None
+            };
+        }
+
+        if let Some(power_save) = &mut self.power_save {
+            if power_save.announce {
+                power_save.announce = false;
+                return Some(Packet::Wake(Wake {
+                    window: LISTEN_WINDOW,
+                    interval: power_save.interval,
+                }));
             }
-            (
-                rx_state,
-                tx_state @ TxState::Sent {
-                    packet_data,
-                    tx_count,
-                    since,
-                },
-            ) => {
-                // Waiting for ack to the last tx packet => retransmit if enough time has passed (with exponential backoff).
-                // The (now % 3) term adds some randomness to the retransmit interval.
-                // if now - since > 2 + 2u32.pow(tx_count) + (now % 3) {
-                if now - since > 2 + ((now * 7) % 89) {
-                    if tx_count <= 16 {
-                        (
-                            // Re-send ack too, because they might be waiting for it
-                            if let RxState::Acked { id: ack_id } = rx_state {
-                                Some(Packet::Both(ack_id, packet_data))
-                            } else {
-                                Some(Packet::Data(packet_data))
-                            },
-                            rx_state,
-                            TxState::Sent {
-                                tx_count: tx_count + 1,
-                                since: now,
-                                packet_data,
-                            },
-                        )
-                    } else {
-                        debug!(
-                            "radio - no ack received for {=u8} after {=u32} transmits, giving up",
-                            packet_data.id, tx_count
-                        );
-                        (None, rx_state, TxState::Idle)
-                    }
-                } else {
-                    (None, rx_state, tx_state)
-                }
+        }
+        // A duty-cycled peer only hears us while it listens. Everything waits until then, so
+        // retransmits aren't used up while it sleeps.
+        if self
+            .peer_wake
+            .is_some_and(|peer_wake| !peer_wake.is_listening(now))
+        {
+            return None;
+        }
+
+        // Dropping the packet would leave a hole in the data, so start over and let the host
+        // know that the link went down
+        if self.tx_state.exhausted(now, self.timer.config.max_tx_count) {
+            debug!("radio - packet not acked, restarting the link");
+            self.quality.give_ups = self.quality.give_ups.wrapping_add(1);
+            self.restart_link(now);
+        }
+        if self.link.hello_due(now) {
+            let random = self.rng.random();
+            return Some(Packet::Hello(self.link.hello(now, random)));
+        }
+        let session = self.link.session()?;
+        if now - self.last_data_tx >= DATA_INTERVAL {
+            if let Some(rate_change) = self.rate.poll(now) {
+                return Some(Packet::Rate(session, rate_change));
             }
-            (RxState::NeedsAck { id: ack_id }, TxState::Idle) => {
-                if !tx_queue.is_empty() {
-                    // Should ack the last rx packet and has data to transmit => send ack and data
-                    let packet_data = PacketData::from_queue(self.get_packet_id(), tx_queue);
-                    (
-                        Some(Packet::Both(ack_id, packet_data)),
-                        RxState::Acked { id: ack_id },
-                        TxState::Sent {
-                            tx_count: 1,
-                            since: now,
-                            packet_data,
-                        },
-                    )
-                } else {
-                    // Should ack the last rx packet and no data to transmit => send ack
-                    (
-                        Some(Packet::Ack(ack_id)),
-                        RxState::Acked { id: ack_id },
-                        TxState::Idle,
-                    )
-                }
+        }
+
+        // Retransmits take precedence over new data
+        let packet_data = if now - self.last_data_tx >= DATA_INTERVAL {
+            let retransmit = self.tx_state.retransmit(now, &mut self.timer);
+            if retransmit.is_some() {
+                self.rate.sent(now, true, self.quality.average);
+                self.quality.retransmits = self.quality.retransmits.wrapping_add(1);
             }
-            (rx_state, TxState::Idle) => {
-                if !tx_queue.is_empty() {
-                    // Data to transmit => send data
-                    let packet_data = PacketData::from_queue(self.get_packet_id(), tx_queue);
-                    (
-                        Some(Packet::Data(packet_data)),
-                        rx_state,
-                        TxState::Sent {
-                            tx_count: 1,
-                            since: now,
-                            packet_data,
-                        },
-                    )
+            retransmit.or_else(|| {
+                let in_flight = self.tx_state.in_flight();
+                if tx_queue.is_empty() {
+                    None
+                } else if in_flight < self.window_size && self.tx_state.credit() > 0 {
+                    self.rate.sent(now, false, self.quality.average);
+                    self.quality.sent = self.quality.sent.wrapping_add(1);
+                    Some(self.tx_state.push(now, tx_queue, &mut self.timer))
+                } else if in_flight == 0 && now - self.last_data_tx >= PROBE_INTERVAL {
+                    debug!("radio - out of credit, probing");
+                    Some(PacketData::probe(self.tx_state.next.wrapping_sub(1)))
                 } else {
-                    (None, rx_state, TxState::Idle)
+                    None
                 }
-            }
+            })
+        } else {
+            None
+        };
+        if packet_data.is_some() {
+            self.last_data_tx = now;
+        }
+
+        // Piggyback an ack on every data packet, and send a bare ack if needed
+        let ack = if packet_data.is_some()
+            || self.rx_state.needs_ack
+            || self.rx_state.needs_window_update(rx_queue)
+        {
+            self.rx_state.needs_ack = false;
+            Some(self.rx_state.ack(rx_queue))
+        } else {
+            None
+        };
+        if let Some(ack) = ack {
+            self.rx_state.advertised = ack.limit;
+        }
+
+        match (ack, packet_data) {
+            (Some(ack), Some(packet_data)) => Some(Packet::Data(session, ack, packet_data)),
+            (Some(ack), None) => Some(Packet::Ack(session, ack)),
+            _ => None,
         }
     }
 }
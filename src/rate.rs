@@ -0,0 +1,318 @@
+//! Adaptive data rate.
+//!
+//! Each session starts at the configured data rate. While the link is up, an end with adaptive
+//! rates enabled watches the data packets it sends and the signal strength of the packets it
+//! receives. When packets keep getting through and the signal is strong enough for the next faster
+//! rate, it asks the peer to switch to it. When packets get lost and the signal is weak, or the
+//! signal gets too weak for the current rate, it asks for the next slower one. The peer answers
+//! with the rate both ends use from then on: the one asked for, or the current one if the peer
+//! keeps a fixed rate. If both ends ask at the same time, the slower rate wins.
+//!
+//! The peer switches right after sending its answer, and the asking end right after receiving it,
+//! so the two ends change the radio mode at almost the same time. After switching, each end keeps
+//! sending its answer again until it hears the peer at the new rate. If the peer isn't heard within
+//! [`SWITCH_TIMEOUT`], e.g. because the answer was lost, the end goes back to the previous rate.
+//!
+//! An end that doesn't hear the peer for [`FALLBACK_TIMEOUT`] at a rate other than the configured
+//! one goes back to the configured rate, where the peer ends up too. That happens well before the
+//! link times out, so a link whose signal suddenly drops keeps its session.
+
+use defmt::{debug, Format};
+
+use crate::hal::DataRate;
+use crate::packet::RateChange;
+use crate::rtc::Deadline;
+
+/// Decide whether to change the rate after this many data packets sent, counting retransmits
+const SAMPLE_SIZE: u32 = 32;
+
+/// Packets are being lost if at least one in this many sent is a retransmit
+const SLOWER_LOSS: u32 = 4;
+
+/// A faster rate is only tried if less than one in this many packets sent is a retransmit
+const FASTER_LOSS: u32 = 8;
+
+/// A signal this close to the sensitivity of the current rate is too weak for it, in dB
+const MIN_MARGIN: i32 = 3;
+
+/// With a signal this far above the sensitivity of a rate, lost packets are blamed on interference,
+/// which a slower rate doesn't help against. A faster rate is only tried with this much margin.
+const GOOD_MARGIN: i32 = 10;
+
+/// Time between requests until the peer answers
+const REQUEST_INTERVAL: u32 = 20;
+
+/// Give up on a request after sending it this many times
+const MAX_REQUESTS: u32 = 5;
+
+/// Time between answers sent again after switching, until the peer is heard at the new rate
+const CONFIRM_INTERVAL: u32 = 5;
+
+/// Go back to the previous rate if the peer isn't heard this long after switching
+const SWITCH_TIMEOUT: u32 = 50;
+
+/// Go back to the configured rate if the peer isn't heard for this long. Longer than the time
+/// between keepalives, and shorter than the link timeout.
+const FALLBACK_TIMEOUT: u64 = 400;
+
+/// Don't ask for a faster rate for this long after the peer refused, or a switch failed
+const HOLD_OFF: u32 = 5000;
+
+/// A switch to a new rate, until the peer is heard at it
+#[derive(Clone, Copy)]
+struct Switch {
+    previous: DataRate,
+    timeout: Deadline,
+    next_confirm: Deadline,
+}
+
+pub struct RateControl {
+    /// Ask the peer for other rates, and agree when it asks
+    adaptive: bool,
+    /// The peer knows rate changes, see [`RateControl::reset`]
+    peer_adaptive: bool,
+    configured: DataRate,
+    current: DataRate,
+    /// The radio needs to be set to `current`
+    changed: bool,
+    /// A rate asked from the peer, not answered yet
+    request: Option<DataRate>,
+    requests: u32,
+    next_request: Deadline,
+    /// The answer to the peer's request, to be sent next
+    answer: Option<DataRate>,
+    switch: Option<Switch>,
+    /// When the peer was last heard
+    heard_at: u64,
+    hold_off: Deadline,
+    /// Data packets sent since the last decision, and how many of them were retransmits
+    sent: u32,
+    retransmits: u32,
+}
+
+/// Moving average of the RSSI of received packets in 1/8 dBm, once one has been received. Each
+/// new packet counts for an eighth.
+#[derive(Clone, Copy, Default, Format)]
+pub struct RssiAverage(Option<i32>);
+
+impl RssiAverage {
+    pub fn add(&mut self, rssi: i8) {
+        let rssi = rssi as i32 * 8;
+        self.0 = Some(match self.0 {
+            Some(average) => average - average / 8 + rssi / 8,
+            None => rssi,
+        });
+    }
+
+    /// The average in dBm, once a packet has been received
+    pub fn dbm(self) -> Option<i8> {
+        self.0.map(|average| (average / 8) as i8)
+    }
+}
+
+impl RateControl {
+    pub fn new(configured: DataRate) -> Self {
+        Self {
+            adaptive: false,
+            peer_adaptive: false,
+            configured,
+            current: configured,
+            changed: false,
+            request: None,
+            requests: 0,
+            next_request: Deadline::after(0, 0),
+            answer: None,
+            switch: None,
+            heard_at: 0,
+            hold_off: Deadline::after(0, 0),
+            sent: 0,
+            retransmits: 0,
+        }
+    }
+
+    pub fn set_adaptive(&mut self, adaptive: bool) {
+        self.adaptive = adaptive;
+    }
+
+    /// Rate to set the radio to
+    pub fn current(&self) -> DataRate {
+        self.current
+    }
+
+    /// Has the rate changed since the last call? The caller reconfigures the radio, after the
+    /// packet being sent if any.
+    pub fn take_changed(&mut self) -> bool {
+        core::mem::take(&mut self.changed)
+    }
+
+    fn set_current(&mut self, rate: DataRate) {
+        if rate != self.current {
+            debug!("rate - {} -> {}", self.current, rate);
+            self.current = rate;
+            self.changed = true;
+        }
+    }
+
+    /// Use a new configured rate right away
+    pub fn configure(&mut self, configured: DataRate) {
+        self.configured = configured;
+        self.current = configured;
+        self.request = None;
+        self.answer = None;
+        self.switch = None;
+    }
+
+    /// Start over at the configured rate with a new session. `peer_adaptive` tells whether the
+    /// peer knows rate changes, which are new in version 2 of the packet format.
+    pub fn reset(&mut self, now: u64, peer_adaptive: bool) {
+        self.set_current(self.configured);
+        self.peer_adaptive = peer_adaptive;
+        self.request = None;
+        self.answer = None;
+        self.switch = None;
+        self.heard_at = now;
+        self.sent = 0;
+        self.retransmits = 0;
+    }
+
+    /// An authentic packet from the peer was received at the current rate
+    pub fn heard(&mut self, now: u64) {
+        self.heard_at = now;
+        if self.switch.take().is_some() {
+            debug!("rate - peer heard at {}", self.current);
+        }
+    }
+
+    /// A data packet was sent, for the first time or as a retransmit. `rssi` is the average
+    /// signal strength of the packets received, kept by the radio for the link quality.
+    pub fn sent(&mut self, now: u64, retransmit: bool, rssi: RssiAverage) {
+        self.sent += 1;
+        self.retransmits += retransmit as u32;
+        if self.sent >= SAMPLE_SIZE {
+            self.decide(now, rssi);
+            self.sent = 0;
+            self.retransmits = 0;
+        }
+    }
+
+    fn decide(&mut self, now: u64, rssi: RssiAverage) {
+        let busy = self.request.is_some() || self.answer.is_some() || self.switch.is_some();
+        let rssi = match rssi.dbm() {
+            Some(rssi) if self.adaptive && self.peer_adaptive && !busy => rssi as i32,
+            _ => return,
+        };
+        let margin = |rate: DataRate| rssi - rate.sensitivity() as i32;
+        let lossy = self.retransmits * SLOWER_LOSS >= self.sent;
+        let clean = self.retransmits * FASTER_LOSS < self.sent;
+        let rate =
+            if margin(self.current) < MIN_MARGIN || (lossy && margin(self.current) < GOOD_MARGIN) {
+                self.current.slower()
+            } else if clean && self.hold_off.has_passed(now) {
+                self.current
+                    .faster()
+                    .filter(|&faster| margin(faster) >= GOOD_MARGIN)
+            } else {
+                None
+            };
+        if let Some(rate) = rate {
+            debug!(
+                "rate - asking for {}, rssi {=i32}, {=u32}/{=u32} retransmits",
+                rate, rssi, self.retransmits, self.sent
+            );
+            self.request = Some(rate);
+            self.requests = 0;
+            self.next_request = Deadline::after(now, 0);
+        }
+    }
+
+    /// Handle a rate change from the peer. Returns true if the peer is checking that we hear it at
+    /// the current rate, and needs something back.
+    pub fn handle(&mut self, now: u64, change: RateChange) -> bool {
+        if change.reply {
+            if change.rate != self.current {
+                // Otherwise an answer to a request that has been given up on
+                if self.request == Some(change.rate) {
+                    self.request = None;
+                    self.start_switch(now, change.rate);
+                }
+                return false;
+            }
+            if self.request.take().is_some() {
+                debug!("rate - peer stays at {}", self.current);
+                self.hold_off = Deadline::after(now, HOLD_OFF);
+                return false;
+            }
+            return true;
+        }
+
+        let rate = match self.request {
+            _ if !self.adaptive => self.current,
+            // Ours is slower, and the peer agrees to it when it arrives
+            Some(request) if request < change.rate => return false,
+            _ => change.rate,
+        };
+        self.request = None;
+        self.answer = Some(rate);
+        false
+    }
+
+    fn start_switch(&mut self, now: u64, rate: DataRate) {
+        self.switch = Some(Switch {
+            previous: self.current,
+            timeout: Deadline::after(now, SWITCH_TIMEOUT),
+            next_confirm: Deadline::after(now, 0),
+        });
+        self.set_current(rate);
+    }
+
+    /// Go back to an earlier rate if the peer isn't heard
+    pub fn check(&mut self, now: u64) {
+        if let Some(switch) = self.switch {
+            if switch.timeout.has_passed(now) {
+                debug!("rate - peer not heard at {}", self.current);
+                self.switch = None;
+                self.hold_off = Deadline::after(now, HOLD_OFF);
+                self.set_current(switch.previous);
+            }
+        }
+        if self.current != self.configured && now - self.heard_at >= FALLBACK_TIMEOUT {
+            debug!("rate - peer not heard, falling back");
+            self.request = None;
+            self.switch = None;
+            self.set_current(self.configured);
+        }
+    }
+
+    /// The rate change to send to the peer now, if any
+    pub fn poll(&mut self, now: u64) -> Option<RateChange> {
+        if let Some(rate) = self.answer.take() {
+            // Switch once the answer has been sent
+            if rate != self.current {
+                self.start_switch(now, rate);
+            }
+            return Some(RateChange { rate, reply: true });
+        }
+        if let Some(switch) = &mut self.switch {
+            if switch.next_confirm.has_passed(now) {
+                switch.next_confirm = Deadline::after(now, CONFIRM_INTERVAL);
+                return Some(RateChange {
+                    rate: self.current,
+                    reply: true,
+                });
+            }
+        }
+        let rate = self.request?;
+        if !self.next_request.has_passed(now) {
+            return None;
+        }
+        if self.requests == MAX_REQUESTS {
+            debug!("rate - no answer from the peer");
+            self.request = None;
+            self.hold_off = Deadline::after(now, HOLD_OFF);
+            return None;
+        }
+        self.requests += 1;
+        self.next_request = Deadline::after(now, REQUEST_INTERVAL);
+        Some(RateChange { rate, reply: false })
+    }
+}
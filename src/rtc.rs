@@ -1,29 +1,82 @@
-use microbit::pac::{CLOCK, RTC0};
+use defmt::Format;
 
-pub struct Rtc {
-    rtc0: RTC0,
-    now: u32,
-}
+use crate::hal::RtcHal;
 
-impl Rtc {
-    pub fn new(rtc0: RTC0) -> Self {
-        Self { rtc0, now: 0 }
-    }
+/// The RTC counter is 24 bits wide, so it wraps around after about 4.6 hours at ~1 ms per tick
+const COUNTER_MASK: u32 = 0xff_ffff;
+
+/// The nRF51 RTC doesn't raise COMPARE for a value less than two ticks ahead of the counter
+const MIN_COMPARE_TICKS: u64 = 2;
 
-    pub fn init(&self, clock: &CLOCK) {
-        clock.tasks_lfclkstart.write(|w| unsafe { w.bits(1) });
+/// Half the counter range
+const MAX_COMPARE_TICKS: u64 = 1 << 23;
 
-        // ~1 ms per tick
-        self.rtc0.prescaler.write(|w| unsafe { w.bits(32) });
-        self.rtc0.evtenset.write(|w| w.tick().set());
-        self.rtc0.tasks_start.write(|w| unsafe { w.bits(1) });
+/// A point in time on the clock of [`Rtc`], when a timeout expires
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    /// `timeout` milliseconds after `now`
+    pub fn after(now: u64, timeout: u32) -> Self {
+        Self(now + timeout as u64)
     }
 
-    pub fn tick(&mut self) -> u32 {
-        if self.rtc0.events_tick.read().bits() != 0 {
-            self.rtc0.events_tick.write(|w| unsafe { w.bits(0) });
-            self.now = self.rtc0.counter.read().bits();
+    pub fn has_passed(self, now: u64) -> bool {
+        now >= self.0
+    }
+}
+
+/// Milliseconds since [`Rtc::init`], extended from the 24-bit counter to 64 bits so that the
+/// clock never wraps around. Time differences and deadlines can be computed without wrapping
+/// arithmetic.
+pub struct Rtc<T: RtcHal> {
+    rtc: T,
+    /// Counter value at the last read
+    counter: u32,
+    now: u64,
+}
+
+impl<T: RtcHal> Rtc<T> {
+    pub fn new(rtc: T) -> Self {
+        Self {
+            rtc,
+            counter: 0,
+            now: 0,
         }
+    }
+
+    pub fn init(&mut self) {
+        self.rtc.init();
+        self.counter = self.rtc.counter();
+    }
+
+    /// The current time. The counter is read at least once before it wraps around, see
+    /// [`Rtc::wake_at`], so the difference modulo 2^24 is the time passed. The OVRFLW event isn't
+    /// needed for that.
+    pub fn now(&mut self) -> u64 {
+        let counter = self.rtc.counter();
+        self.now += (counter.wrapping_sub(self.counter) & COUNTER_MASK) as u64;
+        self.counter = counter;
         self.now
     }
+
+    /// Clear the event that woke up the timer task, and read the clock
+    pub fn tick(&mut self) -> u64 {
+        self.rtc.wake_event();
+        self.now()
+    }
+
+    /// Wake up the timer task at `deadline`, or on every tick while it is too close for the
+    /// compare register. It wakes up at least every half counter range, so that the counter is
+    /// read before it wraps around.
+    pub fn wake_at(&mut self, deadline: Deadline) {
+        let now = self.now();
+        let counter = if deadline.0 >= now + MIN_COMPARE_TICKS {
+            let ticks = (deadline.0 - now).min(MAX_COMPARE_TICKS) as u32;
+            Some(self.counter.wrapping_add(ticks) & COUNTER_MASK)
+        } else {
+            None
+        };
+        self.rtc.wake_at(counter);
+    }
 }
@@ -0,0 +1,211 @@
+//! Settings that survive a reset, kept in a page of flash.
+//!
+//! The settings are stored as one record:
+//!
+//! ```text
+//! [magic (4), version, payload length, crc (2), payload..]
+//! ```
+//!
+//! The CRC is CRC-16/CCITT over the version, the length and the payload, like the radio uses.
+//! A record that is erased, half-written, corrupt, of an unknown version or has values out of
+//! range is ignored, and the caller falls back to the defaults. Records of older versions are
+//! shorter, and the fields they lack read as zero, which means the default.
+
+use defmt::{debug, Format};
+
+use crate::hal::{
+    DataRate, FlashHal, Parity, RadioSettings, UartSettings, AUTOBAUD, BAUD_RATES, MAX_CHANNEL,
+    TX_POWERS,
+};
+
+/// "RLCF"
+const MAGIC: u32 = 0x4643_4c52;
+const VERSION: u8 = 2;
+
+const HEADER_SIZE: usize = 8;
+const PAYLOAD_SIZE: usize = 20;
+
+/// Payload size of each version, starting from 1
+const PAYLOAD_SIZES: [usize; VERSION as usize] = [16, PAYLOAD_SIZE];
+
+/// Everything that is stored in flash
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Settings {
+    pub radio: RadioSettings,
+    pub uart: UartSettings,
+    /// Link id from pairing
+    pub link_id: Option<u32>,
+}
+
+impl Settings {
+    fn write(&self, target: &mut [u8; PAYLOAD_SIZE]) {
+        target[0] = self.radio.channel;
+        target[1] = self.radio.tx_power as u8;
+        target[2] = match self.radio.data_rate {
+            DataRate::Kbit250 => 0,
+            DataRate::Mbit1 => 1,
+            DataRate::Mbit2 => 2,
+        };
+        target[3] = self.link_id.is_some() as u8;
+        target[4..8].copy_from_slice(&self.uart.baud_rate.to_le_bytes());
+        target[8] = self.uart.tx_pin;
+        target[9] = self.uart.rx_pin;
+        target[10] = self.uart.rts_pin;
+        target[11] = self.uart.cts_pin;
+        target[12..16].copy_from_slice(&self.link_id.unwrap_or(0).to_le_bytes());
+        // Since version 2
+        target[16] = match self.uart.parity {
+            Parity::None => 0,
+            Parity::Even => 1,
+        };
+    }
+
+    /// Returns `None` if any value is out of range
+    fn read(source: &[u8; PAYLOAD_SIZE]) -> Option<Self> {
+        let data_rate = match source[2] {
+            0 => DataRate::Kbit250,
+            1 => DataRate::Mbit1,
+            2 => DataRate::Mbit2,
+            _ => return None,
+        };
+        let parity = match source[16] {
+            0 => Parity::None,
+            1 => Parity::Even,
+            _ => return None,
+        };
+        let link_id = match source[3] {
+            0 => None,
+            1 => Some(u32::from_le_bytes([
+                source[12], source[13], source[14], source[15],
+            ])),
+            _ => return None,
+        };
+        let settings = Self {
+            radio: RadioSettings {
+                channel: source[0],
+                tx_power: source[1] as i8,
+                data_rate,
+            },
+            uart: UartSettings {
+                baud_rate: u32::from_le_bytes([source[4], source[5], source[6], source[7]]),
+                parity,
+                tx_pin: source[8],
+                rx_pin: source[9],
+                rts_pin: source[10],
+                cts_pin: source[11],
+            },
+            link_id,
+        };
+        if settings.is_valid() {
+            Some(settings)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let pins = [
+            self.uart.tx_pin,
+            self.uart.rx_pin,
+            self.uart.rts_pin,
+            self.uart.cts_pin,
+        ];
+        self.radio.channel <= MAX_CHANNEL
+            && TX_POWERS.contains(&self.radio.tx_power)
+            && (BAUD_RATES.contains(&self.uart.baud_rate) || self.uart.baud_rate == AUTOBAUD)
+            && pins.iter().all(|&pin| pin < 32)
+    }
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC of the version, length and payload of a record
+fn record_crc(version: u8, len: u8, payload: &[u8]) -> u16 {
+    let mut data = [0; 2 + PAYLOAD_SIZE];
+    data[0] = version;
+    data[1] = len;
+    data[2..2 + payload.len()].copy_from_slice(payload);
+    crc16(&data[..2 + payload.len()])
+}
+
+pub struct Storage<F: FlashHal> {
+    flash: F,
+}
+
+impl<F: FlashHal> Storage<F> {
+    pub fn new(flash: F) -> Self {
+        Self { flash }
+    }
+
+    fn read_word(&self, offset: usize) -> u32 {
+        let mut word = [0; 4];
+        self.flash.read(offset, &mut word);
+        u32::from_le_bytes(word)
+    }
+
+    /// The stored settings, or `None` if there is no valid record
+    pub fn load(&self) -> Option<Settings> {
+        if self.read_word(0) != MAGIC {
+            debug!("storage - no settings");
+            return None;
+        }
+        let mut header = [0; 4];
+        self.flash.read(4, &mut header);
+        let [version, len, crc_lo, crc_hi] = header;
+        let known =
+            (1..=VERSION).contains(&version) && PAYLOAD_SIZES[version as usize - 1] == len as usize;
+        if !known {
+            debug!(
+                "storage - unknown settings version {=u8}, length {=u8}",
+                version, len
+            );
+            return None;
+        }
+        let mut payload = [0; PAYLOAD_SIZE];
+        let stored = &mut payload[..len as usize];
+        self.flash.read(HEADER_SIZE, stored);
+        if record_crc(version, len, stored) != u16::from_le_bytes([crc_lo, crc_hi]) {
+            debug!("storage - settings CRC mismatch");
+            return None;
+        }
+        let settings = Settings::read(&payload);
+        if settings.is_none() {
+            debug!("storage - settings out of range");
+        }
+        settings
+    }
+
+    pub fn save(&mut self, settings: &Settings) {
+        let mut payload = [0; PAYLOAD_SIZE];
+        settings.write(&mut payload);
+        let crc = record_crc(VERSION, PAYLOAD_SIZE as u8, &payload).to_le_bytes();
+
+        self.flash.erase();
+        self.flash.write_word(
+            4,
+            u32::from_le_bytes([VERSION, PAYLOAD_SIZE as u8, crc[0], crc[1]]),
+        );
+        for (i, word) in payload.chunks(4).enumerate() {
+            self.flash.write_word(
+                HEADER_SIZE + i * 4,
+                u32::from_le_bytes([word[0], word[1], word[2], word[3]]),
+            );
+        }
+        // Written last, so that the record only becomes valid when complete
+        self.flash.write_word(0, MAGIC);
+        debug!("storage - saved {}", settings);
+    }
+}
@@ -1,82 +1,360 @@
-use defmt::debug;
-use microbit::pac::{GPIO, UART0};
+use defmt::{debug, Format};
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum TxState {
     Idle,
     Tx,
+    /// Sending a break until the deadline
+    Break(Deadline),
 }
-use crate::queue::Queue;
+use crate::hal::{Received, UartError, UartHal, UartSettings, AUTOBAUD, BAUD_RATES, EDGE_CLOCK};
+use crate::queue::{Control, Queue};
+use crate::rtc::Deadline;
 use TxState::*;
 
-pub struct Uart {
-    uart0: UART0,
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+/// Length of a break sent to the host, longer than two bytes at 1200 baud
+const BREAK_TIME: u32 = 20;
+
+/// Edges on the RX line needed for detecting the baud rate, a bit less than there are in `AT`
+const AUTOBAUD_EDGES: u8 = 12;
+
+/// How flow control is signalled to and from the host
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum FlowControl {
+    /// No flow control. All bytes are data, in both directions.
+    None,
+    /// XON/XOFF bytes in the serial stream. The host can't send 0x11 or 0x13 as data.
+    XonXoff,
+    /// RTS/CTS lines. All bytes are data, in both directions.
+    RtsCts,
+}
+
+/// Counters of receive errors and breaks from the host
+#[derive(Clone, Copy, Default)]
+pub struct LineStats {
+    pub overruns: u32,
+    pub parity_errors: u32,
+    pub framing_errors: u32,
+    pub breaks: u32,
+}
+
+/// Detects the baud rate from the shortest pulse on the RX line, which is one bit long in most
+/// characters
+struct Autobaud {
+    edges: u8,
+    last: Option<u32>,
+    shortest: u32,
+}
+
+impl Autobaud {
+    fn new() -> Self {
+        Self {
+            edges: 0,
+            last: None,
+            shortest: u32::MAX,
+        }
+    }
+
+    /// Returns the baud rate once enough edges have been seen
+    fn edge(&mut self, time: u32) -> Option<u32> {
+        if let Some(last) = self.last {
+            self.shortest = self.shortest.min(time.wrapping_sub(last));
+        }
+        self.last = Some(time);
+        self.edges += 1;
+        if self.edges < AUTOBAUD_EDGES {
+            return None;
+        }
+        BAUD_RATES
+            .iter()
+            .copied()
+            .min_by_key(|&baud_rate| (EDGE_CLOCK / baud_rate).abs_diff(self.shortest))
+    }
+}
+
+pub struct Uart<U: UartHal> {
+    uart: U,
+    flow_control: FlowControl,
+    settings: UartSettings,
+    /// Signal receive errors to the peer
+    forward_errors: bool,
+    /// Detecting the baud rate. Nothing is sent or received meanwhile.
+    autobaud: Option<Autobaud>,
     tx_state: TxState,
+    /// Has the host requested XOFF?
+    tx_paused: bool,
+    /// Did the last tick leave bytes in the UART because the RX queue was full?
+    rx_blocked: bool,
+    /// Is the radio link up?
+    dcd: bool,
+    line_stats: LineStats,
 }
 
-impl Uart {
-    pub fn new(uart0: UART0) -> Self {
+impl<U: UartHal> Uart<U> {
+    /// Breaks from the host are always passed on with the data. Other receive errors are only
+    /// signalled with `forward_errors`.
+    pub fn new(
+        uart: U,
+        flow_control: FlowControl,
+        settings: UartSettings,
+        forward_errors: bool,
+    ) -> Self {
         Self {
-            uart0,
+            uart,
+            flow_control,
+            settings,
+            forward_errors,
+            autobaud: None,
             tx_state: Idle,
+            tx_paused: false,
+            rx_blocked: false,
+            dcd: false,
+            line_stats: LineStats::default(),
         }
     }
 
-    pub fn init(&self, gpio: &GPIO, tx_pin: u32, rx_pin: u32) {
-        gpio.pin_cnf[tx_pin as usize].write(|w| w.pull().pullup().dir().output());
-        gpio.pin_cnf[rx_pin as usize].write(|w| w.pull().disabled().dir().input());
+    pub fn init(&mut self) {
+        if self.settings.baud_rate == AUTOBAUD {
+            // Only the pins matter until the baud rate is known
+            self.start(BAUD_RATES[0]);
+            self.uart.start_edge_capture();
+            self.autobaud = Some(Autobaud::new());
+            debug!("UART initialized, detecting baud rate");
+        } else {
+            self.start(self.settings.baud_rate);
+            self.autobaud = None;
+            debug!("UART initialized at {=u32} baud", self.settings.baud_rate);
+        }
+    }
+
+    fn start(&mut self, baud_rate: u32) {
+        let rts_cts = self.flow_control == FlowControl::RtsCts;
+        let settings = UartSettings {
+            baud_rate,
+            ..self.settings
+        };
+        self.uart.init(&settings, rts_cts);
+        if rts_cts {
+            self.uart.set_rts(true);
+            self.uart.set_dcd(self.dcd);
+        }
+    }
+
+    /// Feed the edges on the RX line to the baud rate detection, and start the UART once the
+    /// baud rate is known. Returns true while still detecting.
+    fn detect_baud_rate(&mut self) -> bool {
+        let autobaud = match &mut self.autobaud {
+            Some(autobaud) => autobaud,
+            None => return false,
+        };
+        while let Some(time) = self.uart.edge() {
+            if let Some(baud_rate) = autobaud.edge(time) {
+                debug!("uart - detected {=u32} baud", baud_rate);
+                self.autobaud = None;
+                self.start(baud_rate);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Change the baud rate or pins. Bytes being sent or received are lost, so wait for
+    /// [`Uart::flushed`] first.
+    pub fn configure(&mut self, settings: UartSettings) {
+        if settings != self.settings {
+            self.settings = settings;
+            self.init();
+        }
+    }
+
+    /// Has everything written to the UART been sent?
+    pub fn flushed(&self) -> bool {
+        self.tx_state == Idle || (self.tx_state == Tx && self.uart.tx_ready())
+    }
+
+    /// Is there nothing to do until an interrupt or [`Uart::next_timeout`]? Bytes left in the RX
+    /// ring don't raise the interrupt again, so they have to be read first.
+    pub fn is_idle<const N: usize, const M: usize>(
+        &self,
+        tx_queue: &Queue<N>,
+        rx_queue: &Queue<M>,
+    ) -> bool {
+        if self.autobaud.is_some() {
+            // Nothing is sent meanwhile, and each edge raises an interrupt
+            return true;
+        }
+        let rx_pending = self.rx_blocked && rx_queue.accepts(1);
+        let tx_pending = match self.tx_state {
+            // Raises an interrupt when sent
+            Tx if !self.uart.tx_ready() => false,
+            Idle | Tx => {
+                tx_queue.has_control()
+                    || (!tx_queue.is_empty() && !self.tx_paused && self.uart.cts())
+            }
+            // Ends on its timeout
+            Break(_) => false,
+        };
+        !tx_pending && !rx_pending
+    }
 
-        self.uart0.pseltxd.write(|w| unsafe { w.bits(tx_pin) });
-        self.uart0.pselrxd.write(|w| unsafe { w.bits(rx_pin) });
-        self.uart0.baudrate.write(|w| w.baudrate().baud38400());
-        self.uart0.enable.write(|w| w.enable().enabled());
+    /// When to tick next without an interrupt: at the end of a break, or every ms while CTS holds
+    /// back bytes for the host, as CTS doesn't raise an interrupt
+    pub fn next_timeout<const N: usize>(&self, now: u64, tx_queue: &Queue<N>) -> Option<Deadline> {
+        match self.tx_state {
+            Break(until) => Some(until),
+            _ if !tx_queue.is_empty() && !self.uart.cts() => Some(Deadline::after(now, 1)),
+            _ => None,
+        }
+    }
 
-        self.uart0.tasks_startrx.write(|w| unsafe { w.bits(1) });
-        self.uart0.tasks_starttx.write(|w| unsafe { w.bits(1) });
+    /// Tell the host whether the radio link is up
+    pub fn set_dcd(&mut self, connected: bool) {
+        if connected != self.dcd {
+            self.dcd = connected;
+            self.uart.set_dcd(connected);
+        }
+    }
 
-        debug!("UART initialized");
+    pub fn line_stats(&self) -> LineStats {
+        self.line_stats
     }
 
-    pub fn tick(&mut self, _now: u32, tx_queue: &mut Queue, rx_queue: &mut Queue) {
+    pub fn tick<const N: usize, const M: usize>(
+        &mut self,
+        now: u64,
+        tx_queue: &mut Queue<N>,
+        rx_queue: &mut Queue<M>,
+    ) {
+        if self.detect_baud_rate() {
+            return;
+        }
+
+        if self.flow_control == FlowControl::RtsCts {
+            if let Some(control) = tx_queue.take_control() {
+                debug!("uart - set RTS: {}", control);
+                self.uart.set_rts(control == Control::Xon);
+            }
+        }
+
         self.tx_state = match self.tx_state {
             Idle => {
-                if let Some(c) = tx_queue.dequeue() {
+                if let Some(c) = self.next_tx_byte(tx_queue) {
                     // debug!(
                     //     "uart - first write {=u8:x}, queue size {=usize}",
                     //     c,
                     //     tx_queue.len()
                     // );
-                    self.uart0.txd.write(|w| unsafe { w.txd().bits(c) });
+                    self.uart.write(c);
                     Tx
                 } else {
-                    Idle
+                    self.start_break(now, tx_queue).unwrap_or(Idle)
                 }
             }
             Tx => {
-                if self.uart0.events_txdrdy.read().bits() != 0 {
-                    if let Some(c) = tx_queue.dequeue() {
+                if self.uart.tx_ready() {
+                    if let Some(c) = self.next_tx_byte(tx_queue) {
                         // debug!(
                         //     "uart - write {=u8:x}, queue size {=usize}",
                         //     c,
                         //     tx_queue.len()
                         // );
-                        self.uart0.events_txdrdy.write(|w| unsafe { w.bits(0) });
-                        self.uart0.txd.write(|w| unsafe { w.txd().bits(c) });
+                        self.uart.write(c);
+                        Tx
+                    } else {
+                        self.start_break(now, tx_queue).unwrap_or(Tx)
                     }
+                } else {
+                    Tx
+                }
+            }
+            Break(until) => {
+                if until.has_passed(now) {
+                    self.uart.set_break(false);
+                    Idle
+                } else {
+                    Break(until)
                 }
-                Tx
             }
         };
 
-        while self.uart0.events_rxdrdy.read().bits() != 0 {
-            self.uart0.events_rxdrdy.write(|w| unsafe { w.bits(0) });
-            let byte = self.uart0.rxd.read().bits() as u8;
-            rx_queue.enqueue(byte);
-            // debug!(
-            //     "uart - read {=u8:x}, queue size {=usize}",
-            //     byte,
-            //     rx_queue.len()
-            // );
+        self.rx_blocked = false;
+        while let Some(received) = self.next_rx(rx_queue) {
+            match received {
+                Received::Byte(byte) => match (self.flow_control, byte) {
+                    (FlowControl::XonXoff, XOFF) => {
+                        debug!("uart - host requested XOFF");
+                        self.tx_paused = true;
+                    }
+                    (FlowControl::XonXoff, XON) => {
+                        debug!("uart - host requested XON");
+                        self.tx_paused = false;
+                    }
+                    _ => {
+                        rx_queue.enqueue(byte);
+                        // debug!(
+                        //     "uart - read {=u8:x}, queue size {=usize}",
+                        //     byte,
+                        //     rx_queue.len()
+                        // );
+                    }
+                },
+                Received::Error(error) => self.rx_error(error, rx_queue),
+            }
+        }
+    }
+
+    fn rx_error<const M: usize>(&mut self, error: UartError, rx_queue: &mut Queue<M>) {
+        debug!("uart - receive error: {}", error);
+        let stats = &mut self.line_stats;
+        let counter = match error {
+            UartError::Overrun => &mut stats.overruns,
+            UartError::Parity => &mut stats.parity_errors,
+            UartError::Framing => &mut stats.framing_errors,
+            UartError::Break => &mut stats.breaks,
+        };
+        *counter = counter.wrapping_add(1);
+        if error == UartError::Break {
+            rx_queue.enqueue_break();
+        } else if self.forward_errors {
+            rx_queue.set_line_error();
+        }
+    }
+
+    /// Send a break if it is next in the queue. Flow control applies like to the data.
+    fn start_break<const N: usize>(
+        &mut self,
+        now: u64,
+        tx_queue: &mut Queue<N>,
+    ) -> Option<TxState> {
+        if self.tx_paused || !self.uart.cts() || !tx_queue.take_break() {
+            return None;
+        }
+        debug!("uart - sending break");
+        self.uart.set_break(true);
+        Some(Break(Deadline::after(now, BREAK_TIME)))
+    }
+
+    /// With `Overflow::Block`, bytes are left in the UART while the queue is full
+    fn next_rx<const M: usize>(&mut self, rx_queue: &Queue<M>) -> Option<Received> {
+        if rx_queue.accepts(1) {
+            self.uart.read()
+        } else {
+            self.rx_blocked = true;
+            None
+        }
+    }
+
+    /// Flow control signals to the host take precedence over data
+    fn next_tx_byte<const N: usize>(&mut self, tx_queue: &mut Queue<N>) -> Option<u8> {
+        match (self.flow_control, tx_queue.take_control()) {
+            (FlowControl::XonXoff, Some(Control::Xoff)) => Some(XOFF),
+            (FlowControl::XonXoff, Some(Control::Xon)) => Some(XON),
+            _ if self.tx_paused => None,
+            (FlowControl::RtsCts, _) if !self.uart.cts() => None,
+            _ => tx_queue.dequeue(),
         }
     }
 }